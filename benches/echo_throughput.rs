@@ -0,0 +1,71 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use serde_json::Value;
+
+// Throughput of the echo binary, end to end over its stdio, for payloads from a kilobyte up to
+// several megabytes, the last being what the large payload handling is for.
+//
+//   cargo bench --bench echo_throughput
+//
+// Under `cargo test --benches` only one small round is run, as a smoke test.
+const ROUNDS: &[(usize, usize)] = &[(1 << 10, 10_000), (1 << 20, 200), (8 << 20, 20)];
+
+fn main() {
+    let benching = std::env::args().any(|arg| arg == "--bench");
+    let rounds = if benching { ROUNDS } else { &ROUNDS[..1] };
+    for &(payload_bytes, requests) in rounds {
+        let requests = if benching { requests } else { 10 };
+        run(payload_bytes, requests);
+    }
+}
+
+// Send `requests` echoes of `payload_bytes` each, and time until every reply has been read.
+fn run(payload_bytes: usize, requests: usize) {
+    let mut echo = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = BufWriter::new(echo.stdin.take().unwrap());
+    let mut stdout = BufReader::new(echo.stdout.take().unwrap());
+    let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n1"]}}"#;
+    writeln!(stdin, "{init}").unwrap();
+    stdin.flush().unwrap();
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+
+    let payload = "x".repeat(payload_bytes);
+    let start = Instant::now();
+    // Written from another thread, so neither side of the pipe fills up waiting on the other.
+    let writer = std::thread::spawn(move || {
+        for msg_id in 1..=requests {
+            let body = format!(r#"{{"type":"echo","msg_id":{msg_id},"echo":"{payload}"}}"#);
+            writeln!(stdin, r#"{{"src":"c1","dest":"n1","body":{body}}}"#).unwrap();
+        }
+        stdin.flush().unwrap();
+    });
+    let mut reply_bytes = 0;
+    for i in 0..requests {
+        line.clear();
+        reply_bytes += stdout.read_line(&mut line).unwrap();
+        // Parsing every reply would time the bench rather than the binary.
+        if i == 0 {
+            let reply: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(reply["body"]["echo"].as_str().map(str::len), Some(payload_bytes));
+        }
+    }
+    let took = start.elapsed();
+    writer.join().unwrap();
+    echo.wait().unwrap();
+
+    let mib = reply_bytes as f64 / (1 << 20) as f64;
+    println!(
+        "{payload_bytes:>9} byte payloads: {requests:>6} echoes in {took:>10.2?}, {:>9.0} echoes/s, \
+         {:>7.1} MiB/s",
+        requests as f64 / took.as_secs_f64(),
+        mib / took.as_secs_f64(),
+    );
+}
@@ -0,0 +1,322 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::node::Node;
+use crate::quorum::{self, Done, Quorum, Registers, Timestamp, Versioned};
+use crate::rpc::{request_header, take_field, ERROR_KEY_DOES_NOT_EXIST, ERROR_TIMEOUT};
+
+// The ABD multi-writer atomic register (Attiya, Bar-Noy and Dolev): linearizable reads and writes
+// without consensus, as long as a majority of nodes is reachable. Every operation takes two rounds,
+// each to a majority, so any two rounds share a node:
+// - A write queries for the newest timestamp, then stores its value at a newer one, ours breaking
+//   ties.
+// - A read queries for the newest value, then writes it back before answering, so no later read
+//   can return anything older, even if the write it came from never finished.
+
+// What a client request is waiting on, carried through its two rounds.
+enum Phase {
+    // The query round of a read or write, the latter with the value to store.
+    Query { request: Map<String, Value>, key: String, write: Option<Value> },
+    // The store round of a write, or a read's write-back of `versioned`.
+    Store { request: Map<String, Value>, versioned: Versioned, reply_type: &'static str },
+}
+
+#[derive(Serialize)]
+pub struct Abd {
+    registers: Registers,
+    #[serde(skip)]
+    quorum: Quorum<Phase>,
+}
+
+impl Abd {
+    // Operations which haven't finished both rounds in `timeout` are answered with a timeout.
+    pub fn new(timeout: Duration) -> Self {
+        Self { registers: Registers::new(), quorum: Quorum::new(timeout) }
+    }
+
+    // A client's `read` or `write`.
+    pub fn handle_request(
+        &mut self,
+        node: &Node,
+        mut request: Map<String, Value>,
+    ) -> Vec<Map<String, Value>> {
+        let header = request_header(&request);
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let key = take_field::<Value>(&mut body, "key").to_string();
+        let write = match header["body"]["type"].as_str() {
+            Some("read") => None,
+            Some("write") => Some(take_field(&mut body, "value")),
+            _ => panic!("Invalid request {:?}", header),
+        };
+        let mut query = Map::new();
+        query.insert("key".to_owned(), json!(key));
+        let local = self.get(&key);
+        let phase = Phase::Query { request: header, key, write };
+        self.start(node, "register_get", &query, phase, local)
+    }
+
+    fn start(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        body: &Map<String, Value>,
+        phase: Phase,
+        local: Map<String, Value>,
+    ) -> Vec<Map<String, Value>> {
+        let majority = quorum::majority(node.node_ids().len());
+        let (round, mut messages) = self.quorum.start(node, msg_type, body, majority, phase);
+        if let Some(done) = self.quorum.reply_local(round, local) {
+            messages.extend(self.on_done(node, done));
+        }
+        messages
+    }
+
+    fn get(&self, key: &str) -> Map<String, Value> {
+        let mut body = Map::new();
+        body.insert("versioned".to_owned(), json!(self.registers.get(key)));
+        body
+    }
+
+    // A peer's query round is reading our replica.
+    pub fn handle_get(&self, node: &Node, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(key) = request["body"]["key"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let mut response = node.build_response(&request, "register_get_ok");
+        response["body"]["versioned"] = json!(self.registers.get(key));
+        vec![response]
+    }
+
+    // A peer's store round is writing our replica.
+    pub fn handle_put(
+        &mut self,
+        node: &Node,
+        mut request: Map<String, Value>,
+    ) -> Vec<Map<String, Value>> {
+        let response = node.build_response(&request, "register_put_ok");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let key: String = take_field(&mut body, "key");
+        let versioned: Versioned = take_field(&mut body, "versioned");
+        self.registers.put(&key, versioned);
+        vec![response]
+    }
+
+    // A peer's answer to one of our rounds.
+    pub fn handle_reply(
+        &mut self,
+        node: &Node,
+        reply: Map<String, Value>,
+    ) -> Vec<Map<String, Value>> {
+        match self.quorum.reply(reply) {
+            Some(done) => self.on_done(node, done),
+            None => Vec::new(),
+        }
+    }
+
+    fn on_done(&mut self, node: &Node, done: Done<Phase>) -> Vec<Map<String, Value>> {
+        match done.state {
+            Phase::Query { request, key, write } => {
+                let newest = quorum::newest(&done.replies);
+                let (versioned, reply_type) = match write {
+                    Some(value) => {
+                        let writer = node.node_id().to_owned();
+                        let ts = Timestamp { counter: newest.ts.counter + 1, writer };
+                        (Versioned { ts, value }, "write_ok")
+                    }
+                    None => (newest, "read_ok"),
+                };
+                let mut store = Map::new();
+                store.insert("key".to_owned(), json!(key));
+                store.insert("versioned".to_owned(), json!(versioned));
+                self.registers.put(&key, versioned.clone());
+                let phase = Phase::Store { request, versioned, reply_type };
+                self.start(node, "register_put", &store, phase, Map::new())
+            }
+            Phase::Store { request, versioned, reply_type: "read_ok" } => {
+                if versioned.value.is_null() {
+                    return vec![node.build_error(
+                        &request,
+                        ERROR_KEY_DOES_NOT_EXIST,
+                        "No such key",
+                    )];
+                }
+                let mut response = node.build_response(&request, "read_ok");
+                response["body"]["value"] = versioned.value;
+                vec![response]
+            }
+            Phase::Store { request, reply_type, .. } => {
+                vec![node.build_response(&request, reply_type)]
+            }
+        }
+    }
+
+    // Time out operations whose rounds couldn't reach a majority, returning the errors to send.
+    // Whether a timed out write took effect is indefinite.
+    pub fn expire(&mut self, node: &Node) -> Vec<Map<String, Value>> {
+        let text = "A majority didn't answer in time";
+        let expired = self.quorum.expired().into_iter().map(|phase| match phase {
+            Phase::Query { request, .. } | Phase::Store { request, .. } => request,
+        });
+        expired.map(|request| node.build_error(&request, ERROR_TIMEOUT, text)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linearizability::{self, Operation, Register, RegisterInput, RegisterOutput};
+    use crate::runtime::Outbox;
+
+    const NODE_IDS: [&str; 3] = ["n0", "n1", "n2"];
+
+    fn node(id: &str) -> Node {
+        Node::new(&json!(id), &json!(NODE_IDS), Outbox::spawn_writer())
+    }
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    fn request(msg_id: u64, body: Value) -> Map<String, Value> {
+        let mut request = message(json!({"src": "c1", "dest": "n0", "body": body}));
+        request["body"]["msg_id"] = json!(msg_id);
+        request
+    }
+
+    // `request`'s recipient answering it with `body`.
+    fn answer(request: &Map<String, Value>, mut body: Value) -> Map<String, Value> {
+        body["in_reply_to"] = request["body"]["msg_id"].clone();
+        message(json!({"src": request["dest"], "dest": request["src"], "body": body}))
+    }
+
+    fn versioned(counter: u64, value: i64) -> Value {
+        json!({"ts": {"counter": counter, "writer": "n1"}, "value": value})
+    }
+
+    fn of_type<'a>(
+        messages: &'a [Map<String, Value>],
+        msg_type: &str,
+    ) -> Vec<&'a Map<String, Value>> {
+        messages.iter().filter(|m| m["body"]["type"] == msg_type).collect()
+    }
+
+    #[tokio::test]
+    async fn writes_are_stored_above_the_newest_timestamp() {
+        let node = node("n0");
+        let mut abd = Abd::new(Duration::from_secs(60));
+        let gets =
+            abd.handle_request(&node, request(1, json!({"type": "write", "key": 0, "value": 7})));
+        assert_eq!(of_type(&gets, "register_get").len(), 2);
+
+        let reply = json!({"type": "register_get_ok", "versioned": versioned(5, 50)});
+        let puts = abd.handle_reply(&node, answer(&gets[0], reply));
+        let puts = of_type(&puts, "register_put");
+        assert_eq!(puts.len(), 2);
+        for put in &puts {
+            assert_eq!(put["body"]["versioned"]["ts"], json!({"counter": 6, "writer": "n0"}));
+            assert_eq!(put["body"]["versioned"]["value"], 7);
+        }
+
+        let done = abd.handle_reply(&node, answer(puts[0], json!({"type": "register_put_ok"})));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0]["dest"], "c1");
+        assert_eq!(done[0]["body"]["type"], "write_ok");
+        assert_eq!(done[0]["body"]["in_reply_to"], 1);
+    }
+
+    #[tokio::test]
+    async fn reads_write_back_before_answering() {
+        let node = node("n0");
+        let mut abd = Abd::new(Duration::from_secs(60));
+        let gets = abd.handle_request(&node, request(1, json!({"type": "read", "key": 0})));
+
+        let reply = json!({"type": "register_get_ok", "versioned": versioned(3, 30)});
+        let puts = abd.handle_reply(&node, answer(&gets[0], reply));
+        // Only the write-back goes out, the client isn't answered until a majority stored it.
+        assert!(of_type(&puts, "read_ok").is_empty());
+        let puts = of_type(&puts, "register_put");
+        assert_eq!(puts.len(), 2);
+        assert_eq!(puts[0]["body"]["versioned"], versioned(3, 30));
+        // Our own replica took the value as part of the round.
+        assert_eq!(abd.get("0")["versioned"], versioned(3, 30));
+
+        let done = abd.handle_reply(&node, answer(puts[0], json!({"type": "register_put_ok"})));
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0]["body"]["type"], "read_ok");
+        assert_eq!(done[0]["body"]["value"], 30);
+    }
+
+    #[tokio::test]
+    async fn rounds_without_a_majority_time_out() {
+        let node = node("n0");
+        let mut abd = Abd::new(Duration::ZERO);
+        abd.handle_request(&node, request(1, json!({"type": "read", "key": 0})));
+        let errors = abd.expire(&node);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["dest"], "c1");
+        assert_eq!(errors[0]["body"]["type"], "error");
+        assert_eq!(errors[0]["body"]["code"], ERROR_TIMEOUT);
+        assert!(abd.expire(&node).is_empty());
+    }
+
+    // Concurrent reads and writes on every node, with messages delivered newest first so rounds
+    // overlap as much as possible, must make a linearizable history.
+    #[tokio::test]
+    async fn concurrent_operations_are_linearizable() {
+        let nodes: Vec<Node> = NODE_IDS.iter().map(|&id| node(id)).collect();
+        let mut replicas: Vec<Abd> =
+            NODE_IDS.iter().map(|_| Abd::new(Duration::from_secs(60))).collect();
+        let inputs = [
+            RegisterInput::Write(1),
+            RegisterInput::Read,
+            RegisterInput::Write(2),
+            RegisterInput::Read,
+            RegisterInput::Read,
+            RegisterInput::Write(3),
+        ];
+
+        let mut history = Vec::new();
+        let mut in_flight = Vec::new();
+        for (i, input) in inputs.iter().enumerate() {
+            let body = match input {
+                RegisterInput::Write(value) => json!({"type": "write", "key": 0, "value": value}),
+                _ => json!({"type": "read", "key": 0}),
+            };
+            let target = i % NODE_IDS.len();
+            let mut request = request(i as u64, body);
+            request["dest"] = json!(NODE_IDS[target]);
+            in_flight.extend(replicas[target].handle_request(&nodes[target], request));
+            let call = history.len() as u64;
+            history.push(Operation { call, ret: u64::MAX, input: input.clone(), output: None });
+        }
+
+        let mut step = history.len() as u64;
+        while let Some(message) = in_flight.pop() {
+            step += 1;
+            let Some(target) = NODE_IDS.iter().position(|&id| message["dest"] == id) else {
+                let op = &mut history[message["body"]["in_reply_to"].as_u64().unwrap() as usize];
+                op.ret = step;
+                op.output = Some(match message["body"]["type"].as_str() {
+                    Some("write_ok") => RegisterOutput::Ok,
+                    Some("read_ok") => RegisterOutput::Read(message["body"]["value"].as_i64()),
+                    _ => RegisterOutput::Read(None),
+                });
+                continue;
+            };
+            let (node, abd) = (&nodes[target], &mut replicas[target]);
+            in_flight.extend(match message["body"]["type"].as_str() {
+                Some("register_get") => abd.handle_get(node, message),
+                Some("register_put") => abd.handle_put(node, message),
+                _ => abd.handle_reply(node, message),
+            });
+        }
+
+        assert!(history.iter().all(|op| op.output.is_some()), "{history:?}");
+        assert!(linearizability::check(&Register, &history), "{history:?}");
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::metrics;
+use crate::node::is_node;
+use crate::runtime::env_or;
+
+// With `MAELSTROM_AUDIT`, every message we send is checked against the protocol before it's
+// written, and violations are logged loudly. Maelstrom's checker otherwise reports such bugs as
+// timeouts or cryptic errors long after the fact. Off by default, as it remembers every request
+// received and every msg_id sent.
+static ENABLED: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_AUDIT", false));
+
+// Whether the invariant checks, auditing and the total availability assertion, are compiled in.
+// They always are in dev builds, but only with the `strict-checks` feature in release builds, so
+// those used for scoring pay nothing for them, not even checking whether they're enabled.
+pub const STRICT_CHECKS: bool = cfg!(any(debug_assertions, feature = "strict-checks"));
+
+// Maelstrom's own services, which we may send to before hearing from them.
+const SERVICES: &[&str] = &["lin-kv", "seq-kv", "lww-kv", "lin-tso"];
+
+#[derive(Default)]
+struct Audit {
+    // Nodes from init, and anyone who has sent us a message.
+    known: HashSet<String>,
+    // (src, msg_id) of every request received.
+    requests: HashSet<(String, u64)>,
+    msg_ids: HashSet<u64>,
+}
+
+static AUDIT: LazyLock<Mutex<Audit>> = LazyLock::new(Default::default);
+
+// Record a request received, as something which may be replied to.
+pub fn received(request: &Map<String, Value>) {
+    if !STRICT_CHECKS || !*ENABLED {
+        return;
+    }
+    let mut audit = AUDIT.lock();
+    if let Some(Value::Array(node_ids)) = request["body"].get("node_ids") {
+        if request["body"]["type"] == "init" {
+            audit.known.extend(node_ids.iter().filter_map(Value::as_str).map(str::to_owned));
+        }
+    }
+    let Some(src) = request["src"].as_str() else {
+        return;
+    };
+    audit.known.insert(src.to_owned());
+    if let Some(msg_id) = request["body"]["msg_id"].as_u64() {
+        audit.requests.insert((src.to_owned(), msg_id));
+    }
+}
+
+// Check `message`, which is about to be sent, logging whatever's wrong with it.
+pub fn check(message: &Map<String, Value>) {
+    if !STRICT_CHECKS || !*ENABLED {
+        return;
+    }
+    let mut audit = AUDIT.lock();
+    let mut violations = Vec::new();
+    let dest = message["dest"].as_str().unwrap_or_default();
+    if !message["body"]["type"].is_string() {
+        violations.push("missing body.type".to_owned());
+    }
+    if dest.is_empty() {
+        violations.push("missing dest".to_owned());
+    } else if is_node(dest) && !audit.known.contains(dest) {
+        violations.push(format!("unknown node {dest}"));
+    } else if !is_node(dest) && !dest.starts_with('c') && !SERVICES.contains(&dest) {
+        violations.push(format!("dest {dest} is neither a node, client nor service"));
+    }
+    if let Some(in_reply_to) = message["body"].get("in_reply_to") {
+        match in_reply_to.as_u64() {
+            Some(id) if audit.requests.contains(&(dest.to_owned(), id)) => (),
+            _ => violations.push(format!("in_reply_to {in_reply_to} isn't a request from {dest}")),
+        }
+    }
+    match message["body"]["msg_id"].as_u64() {
+        Some(msg_id) if !audit.msg_ids.insert(msg_id) => {
+            violations.push(format!("msg_id {msg_id} reused"));
+        }
+        Some(_) => (),
+        None => violations.push("missing msg_id".to_owned()),
+    }
+
+    for violation in violations {
+        metrics::incr("audit.violations");
+        eprintln!("AUDIT VIOLATION: {violation} in {}", serde_json::to_string(message).unwrap());
+    }
+}
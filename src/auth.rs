@@ -0,0 +1,68 @@
+use std::sync::LazyLock;
+
+use serde_json::{Map, Value};
+
+use crate::metrics;
+
+// An optional secret shared by the cluster, `MAELSTROM_CLUSTER_SECRET`. When set, every message to
+// another node is stamped with it and internal messages received without it are dropped, so a stray
+// client on an open network can't inject replication or gossip. Unset, the usual case under
+// Maelstrom, nothing is stamped or checked.
+static SECRET: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("MAELSTROM_CLUSTER_SECRET").ok());
+
+// Message types which are only ever sent from one node to another.
+const INTERNAL_TYPES: &[&str] = &[
+    "replicate",
+    "repair",
+    "report",
+    "total",
+    "gossip",
+    "gossip_ok",
+    "fragment",
+    "join",
+    "join_ok",
+    "member_added",
+    "state_request",
+    "delta",
+    "delta_ok",
+    "log_entries",
+    "log_entries_ok",
+    "retry_batch",
+    "register_get",
+    "register_get_ok",
+    "register_put",
+    "register_put_ok",
+    "bus_replicate",
+];
+
+pub fn stamp(message: &mut Map<String, Value>) {
+    let Some(secret) = SECRET.as_ref() else {
+        return;
+    };
+    if message["dest"].as_str().is_some_and(crate::node::is_node) {
+        message["body"]["auth"] = serde_json::json!(secret);
+    }
+}
+
+// Returns false if `request` must be dropped: it claims to come from a node, or is of an internal
+// type, but doesn't carry the secret. Strips the secret either way so it never reaches handlers.
+pub fn verify(request: &mut Map<String, Value>) -> bool {
+    let Some(secret) = SECRET.as_ref() else {
+        return true;
+    };
+    let auth = match &mut request["body"] {
+        Value::Object(body) => body.remove("auth"),
+        _ => None,
+    };
+    let msg_type = request["body"]["type"].as_str().unwrap_or_default();
+    let internal = request["src"].as_str().is_some_and(crate::node::is_node)
+        || INTERNAL_TYPES.contains(&msg_type);
+    if !internal || auth.as_ref().and_then(Value::as_str) == Some(secret.as_str()) {
+        return true;
+    }
+    metrics::incr("auth.rejected");
+    metrics::incr(&format!("auth.rejected.{msg_type}"));
+    eprintln!("Rejected unauthenticated {msg_type} from {}", request["src"]);
+    false
+}
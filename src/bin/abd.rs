@@ -0,0 +1,63 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use maelstrom_gossip_glommers::kv::Abd;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// A target for Maelstrom's lin-kv workload, serving `read` and `write` linearizably with the ABD
+// register, see `abd`. There's no `cas`, which ABD can't do without consensus, so it's answered as
+// not supported.
+
+// How long an operation may wait on a majority before it's answered with a timeout.
+static TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_ABD_TIMEOUT_MS", 1000)));
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    abd: Abd,
+}
+
+impl Workload for Node {
+    const NAME: &'static str = "abd";
+
+    fn on_init(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self { inner, abd: Abd::new(*TIMEOUT) }
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        Some(match msg_type {
+            "read" | "write" => self.abd.handle_request(&self.inner, request),
+            "register_get" => self.abd.handle_get(&self.inner, request),
+            "register_put" => self.abd.handle_put(&self.inner, request),
+            "register_get_ok" | "register_put_ok" | "error" => {
+                self.abd.handle_reply(&self.inner, request)
+            }
+            _ => return None,
+        })
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(*TIMEOUT / 10)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        self.abd.expire(&self.inner)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Synthetic client traffic for `--selfdrive`, whose cas requests are answered as not supported.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.kv(i)).await;
+}
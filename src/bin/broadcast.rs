@@ -1,241 +1,442 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use std::time::Duration;
-use std::{assert_eq, eprintln, panic};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
-use serde_json::{Map, Value};
-use tokio::time::sleep;
+use maelstrom_gossip_glommers::crdt::Health;
+use maelstrom_gossip_glommers::metrics;
+use maelstrom_gossip_glommers::overlay::Overlay;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::{clock, runtime, workload};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 
-struct MessageBuilder {
-    msg_id: u64,
+// How often to retransmit unacked gossip.
+static RETRY_INTERVAL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_RETRY_INTERVAL_MS", 100)));
+
+// How often to forget what we believe each neighbor has, see `Node::beliefs`, in case a belief has
+// gone stale, e.g. because the neighbor restarted and lost its messages. 0 disables it.
+static BELIEF_RESYNC_INTERVAL: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_BELIEF_RESYNC_MS", 10_000)));
+
+// With `MAELSTROM_BATCH_WINDOW_MS`, new client broadcasts are held for up to that long and gossiped
+// together, trading latency for fewer messages. The window follows the observed broadcast rate,
+// growing from 0 to the max as the rate approaches `MAELSTROM_BATCH_FULL_RATE` per second, so a
+// quiet node gossips right away and a busy one batches. 0 disables batching.
+static BATCH_WINDOW: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_BATCH_WINDOW_MS", 0)));
+static BATCH_FULL_RATE: LazyLock<f64> =
+    LazyLock::new(|| env_or("MAELSTROM_BATCH_FULL_RATE", 100.0));
+
+// With `MAELSTROM_ACK_WINDOW_MS`, gossip acks to a neighbor are held for up to that long and sent
+// as one, acking everything received from it in between, as acks are cumulative anyway. Keep it
+// well under the neighbor's retry interval, or it retransmits what we did receive. 0 acks each
+// gossip right away.
+static ACK_WINDOW: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_ACK_WINDOW_MS", 0)));
+
+// Maelstrom's broadcast values are integers which may be negative or need all 64 bits, so they're
+// held as i128, which round trips either exactly.
+type Message = i128;
+
+// Where a message came from and how often it was delivered again after that. Quantifies how much
+// redundancy the overlay has, which is what fanout/topology tuning trades against latency.
+#[derive(Serialize)]
+struct Provenance {
+    first_from: String,
+    duplicates: u64,
 }
 
-impl MessageBuilder {
-    fn new() -> Self {
-        Self { msg_id: 0 }
-    }
-    fn build_message(&mut self, src: &str, dest: &str, msg_type: &str) -> Map<String, Value> {
-        let msg_id = self.msg_id;
-        self.msg_id += 1;
-        let msg = serde_json::json!({
-            "src": src,
-            "dest": dest,
-            "body": {
-                "msg_id": msg_id,
-                "type": msg_type,
-            }
-        });
-        match msg {
-            Value::Object(obj) => obj,
-            _ => panic!("Invalid message {:?}", msg),
-        }
-    }
+// A summary of our messages, piggybacked on gossip acks so the gossiper notices if we've diverged.
+// Sets only grow, so a neighbor whose digest has a smaller `len`, or the same `len` but a different
+// `sum`, is missing some of ours. The sum wraps in a u64, as JSON can't carry an i128 beyond 64
+// bits.
+#[derive(Deserialize, PartialEq, Serialize)]
+struct Digest {
+    len: usize,
+    sum: u64,
 }
 
+#[derive(Serialize)]
 struct Node {
-    node_id: String,
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
     neighbors: Vec<String>,
-    messages: HashSet<u64>,
-    msg_builder: MessageBuilder,
-    // {msg_id: message}.
-    awaiting_reply: HashMap<u64, String>,
-}
-
-// Useful for moving fields instead of copying them.
-fn take_field<T>(input: &mut Map<String, Value>, name: &str) -> T
-where
-    T: serde::de::DeserializeOwned,
-{
-    let serde_json::map::Entry::Occupied(entry) = input.entry(name) else {
-        panic!("Invalid field removal {:?}", input)
-    };
-    serde_json::from_value(entry.remove()).unwrap()
+    messages: HashSet<Message>,
+    // {message: provenance}.
+    provenance: HashMap<Message, Provenance>,
+    // {neighbor: messages we believe it has}, because it gossiped them to us or acked our gossip of
+    // them. These are left out of gossip to the neighbor, including retransmissions, so one lost ack
+    // doesn't have us resend what the neighbor got some other way.
+    beliefs: HashMap<String, HashSet<Message>>,
+    // {neighbor: {seq: messages}} for gossip which hasn't been acked yet.
+    #[serde(skip)]
+    in_flight: HashMap<String, BTreeMap<u64, Vec<Message>>>,
+    #[serde(skip)]
+    beliefs_since: Instant,
+    // New client broadcasts not gossiped yet, see `BATCH_WINDOW`, and when the oldest arrived.
+    batch: Vec<Message>,
+    #[serde(skip)]
+    batch_since: Option<Instant>,
+    #[serde(skip)]
+    broadcast_rate: metrics::Rate,
+    // {neighbor: when the oldest gossip from it which we haven't acked arrived}, see `ACK_WINDOW`.
+    #[serde(skip)]
+    unacked_since: HashMap<String, Instant>,
+    #[serde(skip)]
+    health: Health,
 }
 
+// Handlers return the messages to send, which the caller commits to the outbox. Gossip is sent with
+// `send_expect_ok`, which commits it directly.
 impl Node {
-    fn new(node_id: &Value) -> Node {
-        let node_id = match node_id {
-            Value::String(id) => id.clone(),
-            _ => panic!("Non-string node_id {}", node_id),
-        };
-        Node {
-            msg_builder: MessageBuilder::new(),
-            node_id,
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self {
+            inner,
             neighbors: Vec::new(),
             messages: HashSet::new(),
-            awaiting_reply: HashMap::new(),
+            provenance: HashMap::new(),
+            beliefs: HashMap::new(),
+            in_flight: HashMap::new(),
+            beliefs_since: clock::now(),
+            batch: Vec::new(),
+            batch_since: None,
+            broadcast_rate: metrics::Rate::default(),
+            unacked_since: HashMap::new(),
+            health: Health::new(Duration::from_secs(1)),
         }
     }
 
-    fn build_response(
-        &mut self,
-        request: &Map<String, Value>,
-        msg_type: &str,
-    ) -> Map<String, Value> {
-        let mut response = self.msg_builder.build_message(
-            &self.node_id,
-            request["src"].as_str().unwrap(),
-            msg_type,
-        );
-        let Value::Object(response_body) = &mut response["body"] else {
-            panic!("Invalid response {:?}", response);
+    // Topologies Maelstrom generates, or which are passed in as files, aren't always well formed
+    // for us. Rather than panic on them, use what we can and reply with `warnings` describing the
+    // rest.
+    fn handle_topology(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        // Build response before taking fields from `request`.
+        let mut response = self.inner.build_response(&request, "topology_ok");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let mut warnings = Vec::new();
+
+        let neighbors = match body.remove("topology") {
+            Some(Value::Object(mut topology)) => topology.remove(self.inner.node_id()),
+            Some(topology) => {
+                warnings.push(format!("Invalid topology {topology}"));
+                None
+            }
+            None => None,
         };
-        let Value::Object(request_body) = &request["body"] else {
-            panic!("Invalid request {:?}", request);
+        // Better to gossip to everyone than be cut off.
+        let neighbors = match neighbors {
+            Some(Value::Array(neighbors)) => neighbors,
+            Some(neighbors) => {
+                warnings.push(format!("Invalid neighbors {neighbors}, using every peer"));
+                self.inner.node_ids().iter().map(|n| json!(n)).collect()
+            }
+            None => {
+                warnings.push("No neighbors given, using every peer".to_owned());
+                self.inner.node_ids().iter().map(|n| json!(n)).collect()
+            }
         };
-        response_body.insert("in_reply_to".to_owned(), request_body["msg_id"].clone());
-        response
+        self.neighbors.clear();
+        for neighbor in neighbors {
+            match neighbor {
+                Value::String(n) if n == self.inner.node_id() => (),
+                Value::String(n) if !self.inner.node_ids().contains(&n) => {
+                    warnings.push(format!("Unknown neighbor {n}"));
+                }
+                Value::String(n) if !self.neighbors.contains(&n) => self.neighbors.push(n),
+                Value::String(_) => (),
+                _ => warnings.push(format!("Invalid neighbor {neighbor}")),
+            }
+        }
+
+        eprintln!("My neighbors are {:?}", &self.neighbors);
+        if !warnings.is_empty() {
+            eprintln!("Topology warnings: {warnings:?}");
+            response["body"]["warnings"] = json!(warnings);
+        }
+        vec![response]
     }
 
-    fn handle_init(&mut self, request: Map<String, Value>) {
-        let response = self.build_response(&request, "init_ok");
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
+    // Record the delivery of `msg` from `src`. Returns true if the message is new to us.
+    fn deliver(&mut self, msg: Message, src: &str) -> bool {
+        if let Some(provenance) = self.provenance.get_mut(&msg) {
+            provenance.duplicates += 1;
+            metrics::incr("broadcast.duplicates");
+            metrics::incr("duplicates_suppressed");
+            metrics::incr(&format!("broadcast.duplicates_from.{src}"));
+            return false;
+        }
+        self.provenance.insert(msg, Provenance { first_from: src.to_owned(), duplicates: 0 });
+        metrics::incr(&format!("broadcast.first_from.{src}"));
+        self.messages.insert(msg)
     }
 
-    fn handle_topology(&mut self, mut request: Map<String, Value>) {
+    fn handle_broadcast(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
         // Build response before taking fields from `request`.
-        let response = self.build_response(&request, "topology_ok");
+        let response = self.inner.build_response(&request, "broadcast_ok");
+
+        let src: String = take_field(&mut request, "src");
         let mut body: Map<String, Value> = take_field(&mut request, "body");
-        let mut topology: Map<String, Value> = take_field(&mut body, "topology");
-        let neighbors: Vec<_> = take_field(&mut topology, &self.node_id);
-
-        self.neighbors = neighbors
-            .into_iter()
-            .map(|v| match v {
-                Value::String(s) => s,
-                _ => panic!("Invalid neighbor {:?}", v),
-            })
-            .collect();
-        eprintln!("My neighbors are {:?}", &self.neighbors);
+        // Some Maelstrom versions batch client broadcasts, sending an array of messages.
+        let msgs: Vec<Message> = match take_field(&mut body, "message") {
+            Value::Array(msgs) => {
+                msgs.into_iter().map(|msg| serde_json::from_value(msg).unwrap()).collect()
+            }
+            msg => vec![serde_json::from_value(msg).unwrap()],
+        };
+        let new: Vec<_> = msgs.into_iter().filter(|msg| self.deliver(*msg, &src)).collect();
+        eprintln!("Received broadcast with new messages {:?}.", new);
+
+        self.broadcast_rate.mark();
+        if !new.is_empty() {
+            self.batch.extend(new);
+            self.batch_since.get_or_insert_with(clock::now);
+        }
+        self.flush_batch();
+        // Ack the broadcast, once however many messages it carried.
+        vec![response]
+    }
 
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
+    // Gossip the batch of client broadcasts, if it's been held for the current window.
+    fn flush_batch(&mut self) {
+        let Some(since) = self.batch_since else {
+            return;
+        };
+        if clock::now() - since < self.batch_window() {
+            return;
+        }
+        let msgs = std::mem::take(&mut self.batch);
+        self.batch_since = None;
+        metrics::max("broadcast.max_batch", msgs.len() as u64);
+        self.gossip(&msgs, |_| true);
     }
 
-    fn handle_broadcast(&mut self, mut request: Map<String, Value>) {
-        // Build response before taking fields from `request`.
-        let response = self.build_response(&request, "broadcast_ok");
+    fn batch_window(&self) -> Duration {
+        let fill = (self.broadcast_rate.per_sec() / *BATCH_FULL_RATE).min(1.0);
+        let window = BATCH_WINDOW.mul_f64(fill);
+        metrics::set("broadcast.batch_window_us", window.as_micros() as u64);
+        window
+    }
 
+    fn handle_gossip(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        // Record receipt before taking fields from `request`.
+        self.inner.receive(&request);
+        let sampled = runtime::traced(&request);
         let src: String = take_field(&mut request, "src");
         let mut body: Map<String, Value> = take_field(&mut request, "body");
-        let msg: u64 = take_field(&mut body, "message");
-        let new = self.messages.insert(msg);
-        eprintln!("Received broadcast '{}', which is new? {}.", msg, new);
-
-        // Ack the broadcast.
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
-
-        // Broadcast to neighbors.
-        if new {
-            for n in self.neighbors.iter().filter(|&n| *n != src) {
-                // OWNERSHIP: If `build_message` was a method of Node this would not compile.
-                // `build_message` is mut because we increment `msg_id` and so would mutably borrow
-                // the entirety of self, but we already borrowed from self due to iterating over
-                // `neighbors`. Therefore we created MessageBuilder so that we could take advantage
-                // of split borrowing.
-                let mut message = self.msg_builder.build_message(&self.node_id, n, "broadcast");
-                message["body"]["message"] = serde_json::json!(msg);
-                let serialized = serde_json::to_string(&message).unwrap();
-
-                // Add to `awaiting_reply` first so that we don't miss an ack. This shouldn't make a
-                // big difference, but may help cut down on unnecessary retries a bit.
-                self.awaiting_reply
-                    .insert(message["body"]["msg_id"].as_u64().unwrap(), serialized.clone());
-                println!("{}", serialized);
+        let seq: u64 = take_field(&mut body, "seq");
+        let msgs: Vec<Message> = take_field(&mut body, "messages");
+
+        self.beliefs.entry(src.clone()).or_default().extend(&msgs);
+        let new: Vec<_> = msgs.into_iter().filter(|msg| self.deliver(*msg, &src)).collect();
+        if sampled {
+            eprintln!("Received gossip {seq} from {src} with new messages {:?}.", new);
+        }
+        if !new.is_empty() {
+            self.gossip(&new, |n| n != src);
+        }
+        if self.health.heard_from(&src) {
+            self.on_heal(&src);
+        }
+        self.unacked_since.entry(src).or_insert_with(clock::now);
+        self.flush_acks()
+    }
+
+    // Ack the neighbors whose gossip has waited out `ACK_WINDOW`, with our digest.
+    fn flush_acks(&mut self) -> Vec<Map<String, Value>> {
+        let now = clock::now();
+        let due: Vec<_> = self
+            .unacked_since
+            .iter()
+            .filter(|(_, &since)| now - since >= *ACK_WINDOW)
+            .map(|(neighbor, _)| neighbor.clone())
+            .collect();
+        let mut acks = Vec::new();
+        for neighbor in due {
+            self.unacked_since.remove(&neighbor);
+            let mut ack = self.inner.build_ack(&neighbor, "gossip_ok");
+            ack["body"]["digest"] = json!(self.digest());
+            acks.push(ack);
+        }
+        acks
+    }
+
+    fn digest(&self) -> Digest {
+        Digest {
+            len: self.messages.len(),
+            sum: self.messages.iter().fold(0, |a, b| a.wrapping_add(*b as u64)),
+        }
+    }
+
+    // As `digest`, but of only the messages we've gossiped, i.e. not those waiting in the batch.
+    fn gossiped_digest(&self) -> Digest {
+        let digest = self.digest();
+        Digest {
+            len: digest.len - self.batch.len(),
+            sum: self.batch.iter().fold(digest.sum, |a, b| a.wrapping_sub(*b as u64)),
+        }
+    }
+
+    // Called when a peer we couldn't reach is back. Rather than leave it to the retries to trickle
+    // in, send everything we know as a single batch.
+    fn on_heal(&mut self, peer: &str) {
+        let msgs: Vec<_> = self.messages.iter().copied().collect();
+        self.gossip(&msgs, |n| n == peer);
+    }
+
+    // Send `msgs` as one batch to every neighbor for which `to` returns true, less whatever the
+    // neighbor is believed to have.
+    fn gossip(&mut self, msgs: &[Message], to: impl Fn(&str) -> bool) {
+        for n in self.neighbors.iter().filter(|&n| to(n)) {
+            let msgs = self.unknown_to(n, msgs);
+            if msgs.is_empty() {
+                continue;
             }
+            let body = serde_json::json!({ "type": "gossip", "messages": msgs });
+            let Value::Object(body) = body else {
+                panic!("Invalid body {:?}", body);
+            };
+            self.health.sent_to(n);
+            let seq = self.inner.send_expect_ok(n, body);
+            self.in_flight.entry(n.clone()).or_default().insert(seq, msgs);
         }
     }
 
-    fn handle_broadcast_ok(&mut self, mut request: Map<String, Value>) {
-        let mut body: Map<String, Value> = take_field(&mut request, "body");
-        let msg_id: u64 = take_field(&mut body, "in_reply_to");
-        let present = self.awaiting_reply.remove(&msg_id).is_some();
-        eprintln!("Received ack for msg {msg_id} which was already acked? {present}");
+    // The messages in `msgs` which `neighbor` isn't believed to have.
+    fn unknown_to(&self, neighbor: &str, msgs: &[Message]) -> Vec<Message> {
+        let Some(known) = self.beliefs.get(neighbor) else {
+            return msgs.to_vec();
+        };
+        let unknown: Vec<_> = msgs.iter().copied().filter(|msg| !known.contains(msg)).collect();
+        metrics::add("broadcast.gossip_skipped", (msgs.len() - unknown.len()) as u64);
+        metrics::add("duplicates_suppressed", (msgs.len() - unknown.len()) as u64);
+        unknown
+    }
+
+    fn handle_gossip_ok(&mut self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(src) = request["src"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let Some(acked_through) = request["body"]["acked_through"].as_u64() else {
+            panic!("Missing acked_through {:?}", request);
+        };
+        // Everything through the watermark was received, so the neighbor has it.
+        let in_flight = self.in_flight.entry(src.to_owned()).or_default();
+        let still_in_flight = in_flight.split_off(&(acked_through + 1));
+        let acked = std::mem::replace(in_flight, still_in_flight);
+        self.beliefs.entry(src.to_owned()).or_default().extend(acked.into_values().flatten());
+
+        self.inner.handle_ack(&request);
+        if self.health.heard_from(src) {
+            self.on_heal(src);
+        }
+        // With nothing of ours still in flight to it, a neighbor missing some of what we've gossiped
+        // has lost it, e.g. to a restart, and what we believe it has is stale. Older nodes send no
+        // digest.
+        let digest: Option<Digest> =
+            request["body"].get("digest").map(|d| serde_json::from_value(d.clone()).unwrap());
+        let in_flight = self.in_flight.get(src).is_some_and(|in_flight| !in_flight.is_empty());
+        let ours = self.gossiped_digest();
+        let behind =
+            |digest: Digest| digest.len < ours.len || (digest.len == ours.len && digest != ours);
+        if digest.is_some_and(behind) && !in_flight {
+            metrics::incr("broadcast.digest_repairs");
+            self.beliefs.remove(src);
+            self.on_heal(src);
+        }
+        Vec::new()
     }
 
-    fn handle_read(&mut self, request: Map<String, Value>) {
-        let mut response = self.build_response(&request, "read_ok");
+    fn handle_read(&self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let mut response = self.inner.build_response(&request, "read_ok");
         response["body"]["messages"] = serde_json::json!(&self.messages);
+        self.health.tag_stale(&mut response);
         eprintln!("Received read: {:?}", &response);
+        vec![response]
+    }
 
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
+    fn log_provenance(&self) {
+        let mut most_duplicated: Vec<_> = self.provenance.iter().collect();
+        most_duplicated.sort_by_key(|(_, provenance)| std::cmp::Reverse(provenance.duplicates));
+        for (msg, provenance) in most_duplicated.into_iter().take(10) {
+            eprintln!(
+                "Message {msg} first from {}, {} duplicates",
+                provenance.first_from, provenance.duplicates
+            );
+        }
     }
 
-    // Resend broadcasts messages which are awaiting reply.
+    // Resend gossip which peers haven't acked, less what they're since believed to have.
     fn retry_messages(&mut self) {
-        for message in self.awaiting_reply.values() {
-            println!("{}", message);
+        self.health.check(self.inner.node_ids().len());
+        if !BELIEF_RESYNC_INTERVAL.is_zero()
+            && clock::now() - self.beliefs_since >= *BELIEF_RESYNC_INTERVAL
+        {
+            self.beliefs.clear();
+            self.beliefs_since = clock::now();
         }
+        self.inner.retransmit_unacked_with(|dest, message| {
+            let msgs: Vec<Message> =
+                serde_json::from_value(message["body"]["messages"].clone()).unwrap();
+            message["body"]["messages"] = serde_json::json!(self.unknown_to(dest, &msgs));
+        });
     }
 }
 
-fn await_request(stdin: &std::io::Stdin) -> Map<String, Value> {
-    let mut input = String::new();
-    let Ok(_) = stdin.read_line(&mut input) else {
-        panic!("Failed to read from stdin");
-    };
-    eprintln!("Received {}", input);
-    let Ok(request) = serde_json::from_str::<Map<String, Value>>(&input) else {
-        panic!("Failed to parse input: {input}");
-    };
-    request
-}
+impl Workload for Node {
+    const NAME: &'static str = "broadcast";
 
-fn create_node(stdin: &std::io::Stdin) -> Node {
-    let request = await_request(stdin);
-    assert_eq!(request["body"]["type"], "init", "{request:?}");
-    eprintln!("Initialized node {}", request["body"]["node_id"]);
-    let mut node = Node::new(&request["body"]["node_id"]);
-    node.handle_init(request);
-    node
-}
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        Some(match msg_type {
+            "topology" => self.handle_topology(request),
+            "broadcast" => self.handle_broadcast(request),
+            "gossip" => self.handle_gossip(request),
+            "gossip_ok" => self.handle_gossip_ok(request),
+            "read" => self.handle_read(request),
+            _ => return None,
+        })
+    }
 
-// Resends messages that require and haven't received an ack with a set sleep between.
-fn spawn_retry_loop(node: Arc<parking_lot::Mutex<Node>>) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        loop {
-            node.lock().retry_messages();
-            sleep(Duration::from_millis(100)).await;
+    // Resends messages that require and haven't received an ack with a set sleep between.
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(*RETRY_INTERVAL)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        self.flush_batch();
+        self.retry_messages();
+        self.flush_acks()
+    }
+
+    fn overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        overlay.add("neighbor", &self.neighbors);
+        overlay
+    }
+
+    fn on_shutdown(&mut self) {
+        self.log_provenance();
+        if !self.inner.converged() {
+            eprintln!(
+                "Shutting down with gossip unacked, acked through {:?}",
+                self.inner.acked_through()
+            );
         }
-    })
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let stdin = std::io::stdin();
-
-    // We wrap the node in a parking_lot::Mutex to make async simple. There will be lots of blocking
-    // between tasks, but that's fine. We just utilize tokio to schedule all of these tasks, we
-    // aren't worried about fine grained locking, or ReadWrite locking for performance.
-    let node = Arc::new(parking_lot::Mutex::new(create_node(&stdin)));
-    spawn_retry_loop(Arc::clone(&node));
-
-    // Main loop.
-    loop {
-        let request = await_request(&stdin);
-        let Value::String(msg_type) = &request["body"]["type"] else {
-            panic!("Invalid msg type encoding");
-        };
-
-        // Clone node so that we can pass it to the handler and keep a local pointer to the node.
-        let node = Arc::clone(&node);
-        // Rust creates a new type for each closure, therefore we need to wrap the closures inside
-        // of a Box and specify the Trait we care about, since otherwise the compiler sees this
-        // as multiple different return types. (polymorphism)
-        let handler: Box<dyn FnOnce() + Send> = match msg_type.as_str() {
-            "init" => panic!("Already initialized node: {:?}", request),
-            "topology" => Box::new(move || node.lock().handle_topology(request)),
-            "broadcast" => Box::new(move || node.lock().handle_broadcast(request)),
-            "broadcast_ok" => Box::new(move || node.lock().handle_broadcast_ok(request)),
-            "read" => Box::new(move || node.lock().handle_read(request)),
-            _ => panic!("Unknown msg type {:?}", request),
-        };
-        // Given that I lock node for the entirety of the async function I'm not sure how
-        // valuable it is to run this in a separate task, but it does unblock receiving the next
-        // request at least.
-        tokio::spawn(async move { handler() });
-    }
+    // Synthetic client traffic for `--selfdrive`.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.broadcast(i)).await;
 }
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use maelstrom_gossip_glommers::crdt::{Bus, Counter, LwwMap, Set};
+use maelstrom_gossip_glommers::overlay::Overlay;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// A counter, a grow-only set and a last-writer-wins map on one node, replicated together on a
+// shared `Bus`. Client requests are the usual ones for each, routed by their fields: a `read` or
+// `write` with a `key` goes to the map, an `add` with a `delta` to the counter and one with an
+// `element` to the set. A `read` without a key reads the counter, unless its `component` is "set".
+
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    bus: Bus,
+}
+
+impl Workload for Node {
+    const NAME: &'static str = "crdts";
+
+    fn on_init(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        let mut bus = Bus::new();
+        bus.register("map", LwwMap::new());
+        bus.register("counter", Counter::new(inner.node_id()));
+        bus.register("set", Set::new());
+        Self { inner, bus }
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        if msg_type == "bus_replicate" {
+            self.bus.handle_replicate(request);
+            return Some(Vec::new());
+        }
+        Some(vec![self.bus.apply(&self.inner, msg_type, &request)?])
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(REPLICATION_INTERVAL)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        self.bus.round(&self.inner)
+    }
+
+    fn overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        let peers = self.inner.node_ids().iter().filter(|&n| n != self.inner.node_id());
+        overlay.add("replica", peers);
+        overlay
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Synthetic client traffic for `--selfdrive`, which only exercises the counter.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.gcounter(i)).await;
+}
@@ -1,103 +1,768 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::panic;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::LazyLock;
+use std::time::{Duration, UNIX_EPOCH};
 
-use itertools::Itertools;
+use maelstrom_gossip_glommers::kv::{merge, KeyStats, OffsetLog, PersistentMap, Proxy, TxnOp};
+use maelstrom_gossip_glommers::overlay::Overlay;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use maelstrom_gossip_glommers::{clock, metrics};
+use serde::Serialize;
 use serde_json::{json, Map, Value};
 
+// Not for use under Maelstrom, whose clients expect a single `txn_ok`: split replies with more than
+// `MAELSTROM_TXN_CHUNK_OPS` micro-ops into several `txn_ok`s, each with a slice of `txn` and a
+// `chunk: {index, count}` for the client to reassemble them in order. 0, the default, disables it.
+static TXN_CHUNK_OPS: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_TXN_CHUNK_OPS", 0));
+
+// With `MAELSTROM_TXN_STRICT`, a txn reading a key which doesn't exist is aborted rather than
+// reading null.
+static TXN_STRICT: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_TXN_STRICT", false));
+
+// The fraction of txns to abort for no reason, to exercise clients' handling of aborts.
+static TXN_ABORT_RATE: LazyLock<f64> = LazyLock::new(|| env_or("MAELSTROM_TXN_ABORT_RATE", 0.0));
+
+// Not for use under Maelstrom, which doesn't send them: with `MAELSTROM_TXN_RANGE_READS`, a txn
+// may read every key in `lo..=hi` with `["rr", lo, hi]`, see `TxnOp`, which is answered with
+// `["rr", lo, hi, [[key, values], ...]]` in key order. Without it they're answered as not supported.
+static TXN_RANGE_READS: LazyLock<bool> =
+    LazyLock::new(|| env_or("MAELSTROM_TXN_RANGE_READS", false));
+
+// The isolation level txns run at, with `MAELSTROM_ISOLATION`. Txns are applied one at a time, so
+// every level at or above read-committed gets serial execution, which satisfies all of them, and
+// only differs in how appends are staged. Under read-uncommitted, the default being serializable,
+// appends go straight into the store, so an aborted txn's appends stay visible (G1a), which that
+// level allows.
+#[derive(Clone, Copy)]
+enum Isolation {
+    ReadUncommitted,
+    ReadCommitted,
+    Snapshot,
+    Serializable,
+}
+
+static ISOLATION: LazyLock<Isolation> =
+    LazyLock::new(|| match env_or("MAELSTROM_ISOLATION", "serializable".to_owned()).as_str() {
+        "read-uncommitted" => Isolation::ReadUncommitted,
+        "read-committed" => Isolation::ReadCommitted,
+        "snapshot" => Isolation::Snapshot,
+        "serializable" => Isolation::Serializable,
+        other => panic!(
+            "Invalid MAELSTROM_ISOLATION={other}, expected read-uncommitted, read-committed, \
+             snapshot or serializable"
+        ),
+    });
+
+// With `MAELSTROM_LOG_SHIPPING`, the node with the lowest id is the primary, and every txn with
+// appends is run there, followers forwarding them. The primary numbers the txns whose appends
+// reached its store, its commit log, and streams each follower the entries it hasn't applied, which
+// it applies strictly in order. A follower serves read-only txns itself, from a consistent prefix of
+// the primary's txns, though not necessarily the latest, nor one including the client's own
+// appends.
+//
+// Entries carry the primary's `epoch`, when it started. A primary which restarts loses its store
+// and numbers its log from 0 again, so a follower still holding what the previous incarnation
+// shipped has diverged from it, and its entries would be misnumbered. Such a follower is sent the
+// primary's whole store in a `log_snapshot` instead, which it reconciles with its own key by key
+// under the `MAELSTROM_MERGE_POLICY`, see `merge`.
+static LOG_SHIPPING: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_LOG_SHIPPING", false));
+
+// How long a follower waits on the primary to answer a forwarded txn before answering the client
+// with a timeout.
+static PROXY_TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_PROXY_TIMEOUT_MS", 1000)));
+
+// How often the primary resends entries followers haven't acked, and followers time out forwarded
+// txns.
+const SHIP_INTERVAL: Duration = Duration::from_millis(100);
+
+// The most commit log entries sent in one message.
+const MAX_SHIPPED_ENTRIES: usize = 100;
+
+// A txn's appends, in the order made, as shipped to followers.
+type WriteSet = Vec<(i64, i64)>;
+
+#[derive(Serialize)]
+#[serde(transparent)]
+struct Store {
+    // Persistent, so that a snapshot of the whole store is a cheap clone.
+    data: PersistentMap<i64, OffsetLog<i64>>,
+    // Appends since the commit log last took them.
+    #[serde(skip)]
+    unlogged: WriteSet,
+}
+
+impl Store {
+    fn new() -> Self {
+        Self { data: PersistentMap::new(), unlogged: Vec::new() }
+    }
+
+    fn get(&self, key: i64) -> Option<&OffsetLog<i64>> {
+        self.data.get(&key)
+    }
+
+    // The keys in `lo..=hi`, in order.
+    fn keys_in(&self, lo: i64, hi: i64) -> impl Iterator<Item = i64> + '_ {
+        self.data.iter_from(&lo).map(|(&key, _)| key).take_while(move |&key| key <= hi)
+    }
+
+    fn append(&mut self, key: i64, val: i64) {
+        if !self.data.contains_key(&key) {
+            self.data.insert(key, OffsetLog::new());
+        }
+        self.data.get_mut(&key).unwrap().append(val);
+        self.unlogged.push((key, val));
+    }
+
+    // Replace `key`'s history wholesale, as reconciled with a primary's. Not an append, so not
+    // logged.
+    fn replace(&mut self, key: i64, values: Vec<i64>) {
+        let mut log = OffsetLog::new();
+        for val in values {
+            log.append(val);
+        }
+        self.data.insert(key, log);
+    }
+}
+
+// The primary's view of a follower.
+#[derive(Default)]
+struct Follower {
+    // Txns below this have been applied.
+    applied: u64,
+    // Txns below this have been sent at least once.
+    sent: u64,
+}
+
+// How a txn's appends reach the store, per `ISOLATION`. A new one is made for each txn.
+trait Staging {
+    fn append(&mut self, data: &mut Store, key: i64, val: i64);
+    // Appends to `key` which the txn sees but `data` doesn't hold yet.
+    fn staged(&self, key: i64) -> &[i64];
+    // The keys in `lo..=hi` with appends `data` doesn't hold yet.
+    fn staged_keys(&self, lo: i64, hi: i64) -> Vec<i64>;
+    // Called once the whole txn has validated. Dropping the staging instead aborts the txn.
+    fn commit(self: Box<Self>, data: &mut Store);
+}
+
+// Appends are held until commit, so an abort has no effects.
+#[derive(Default)]
+struct Deferred {
+    // {key: values appended by this txn}. Ordered for range reads.
+    staged: BTreeMap<i64, Vec<i64>>,
+}
+
+impl Staging for Deferred {
+    fn append(&mut self, _: &mut Store, key: i64, val: i64) {
+        self.staged.entry(key).or_default().push(val);
+    }
+
+    fn staged(&self, key: i64) -> &[i64] {
+        self.staged.get(&key).map_or(&[], Vec::as_slice)
+    }
+
+    fn staged_keys(&self, lo: i64, hi: i64) -> Vec<i64> {
+        self.staged.range(lo..=hi).map(|(&key, _)| key).collect()
+    }
+
+    fn commit(self: Box<Self>, data: &mut Store) {
+        for (key, values) in self.staged {
+            for val in values {
+                data.append(key, val);
+            }
+        }
+    }
+}
+
+// Appends are applied as they're read off the txn, and stay even if it aborts.
+struct InPlace;
+
+impl Staging for InPlace {
+    fn append(&mut self, data: &mut Store, key: i64, val: i64) {
+        data.append(key, val);
+    }
+
+    fn staged(&self, _: i64) -> &[i64] {
+        &[]
+    }
+
+    fn staged_keys(&self, _: i64, _: i64) -> Vec<i64> {
+        Vec::new()
+    }
+
+    fn commit(self: Box<Self>, _: &mut Store) {}
+}
+
+fn staging() -> Box<dyn Staging> {
+    match *ISOLATION {
+        Isolation::ReadUncommitted => Box::new(InPlace),
+        Isolation::ReadCommitted | Isolation::Snapshot | Isolation::Serializable => {
+            Box::new(Deferred::default())
+        }
+    }
+}
+
+#[derive(Serialize)]
 struct Node {
-    inner: maelstrom_gossip_glommers::Node,
-    data: HashMap<i64, Vec<i64>>,
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    data: Store,
+    // Reads and appends per key, including those of aborted txns, as they contend all the same.
+    hot_keys: KeyStats<i64>,
+    // See `LOG_SHIPPING`, None without it.
+    primary: Option<String>,
+    // As the primary: txns by id, from the first some follower hasn't applied.
+    #[serde(skip)]
+    commit_log: OffsetLog<WriteSet>,
+    #[serde(skip)]
+    followers: HashMap<String, Follower>,
+    // As the primary: identifies this incarnation of us, see `LOG_SHIPPING`.
+    epoch: u64,
+    // As a follower: txns below this have been applied.
+    applied: u64,
+    // As a follower: the epoch of the primary whose txns we've applied, None until we've heard
+    // from it.
+    primary_epoch: Option<u64>,
+    // As a follower: txns with appends forwarded to the primary.
+    #[serde(skip)]
+    proxy: Proxy,
 }
 
 impl Node {
-    fn new(inner: maelstrom_gossip_glommers::Node) -> Self {
-        Self { inner, data: HashMap::new() }
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        let primary = LOG_SHIPPING.then(|| inner.node_ids().iter().min().unwrap().clone());
+        let followers = match &primary {
+            Some(primary) if primary == inner.node_id() => {
+                let others = inner.node_ids().iter().filter(|&n| n != primary);
+                others.map(|n| (n.clone(), Follower::default())).collect()
+            }
+            _ => HashMap::new(),
+        };
+        Self {
+            inner,
+            data: Store::new(),
+            hot_keys: KeyStats::new(),
+            primary,
+            commit_log: OffsetLog::new(),
+            followers,
+            epoch: clock::system_time().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64,
+            applied: 0,
+            primary_epoch: None,
+            proxy: Proxy::new(*PROXY_TIMEOUT),
+        }
     }
 
-    fn handle_txn(&mut self, mut request: Map<String, Value>) {
-        // Build response before taking fields from `request`.
-        let mut response = self.inner.build_response(&request, "txn_ok");
-        let Value::Object(response_body) = &mut response["body"] else {
-            panic!("Invalid response {:?}", response);
-        };
+    // The primary, if we're a follower.
+    fn follower_of(&self) -> Option<&str> {
+        self.primary.as_deref().filter(|&primary| primary != self.inner.node_id())
+    }
+
+    // Returns the messages to send, which the caller commits to the outbox.
+    fn handle_txn(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        if let Some(primary) = self.follower_of() {
+            let ops = request["body"]["txn"].as_array().into_iter().flatten();
+            if ops.clone().any(|op| op[0] == "append") {
+                let primary = primary.to_owned();
+                return vec![self.proxy.forward(&self.inner, &request, &primary, "txn")];
+            }
+        }
+        // Keep what's needed to reply before taking fields from `request`.
+        let header = request_header(&request);
         let mut response_txn = Vec::new();
 
-        let mut request_body: Map<String, Value> =
-            maelstrom_gossip_glommers::take_field(&mut request, "body");
-        let request_txn: Vec<Value> =
-            maelstrom_gossip_glommers::take_field(&mut request_body, "txn");
-
-        for txn in request_txn {
-            let Value::Array(txn) = txn else {
-                panic!("Invalid transaction {:?}", txn);
-            };
-            let Some((func, key, val)) = txn.into_iter().collect_tuple() else {
-                panic!("Transaction cannot be decomposed.");
-            };
-            let Value::String(func) = func else {
-                panic!("Invalid function {:?}", func);
-            };
-            let Value::Number(key) = key else {
-                panic!("Invalid key {:?}", key);
-            };
-            let key = key.as_i64().unwrap();
-
-            match func.as_str() {
-                "r" => self.read(key, &mut response_txn),
-                "append" => self.append(key, val, &mut response_txn),
-                _ => panic!("Unknown txn function {:?}", func),
+        let mut request_body: Map<String, Value> = take_field(&mut request, "body");
+        let request_txn: Vec<TxnOp> = take_field(&mut request_body, "txn");
+
+        let mut staging = staging();
+        for op in request_txn {
+            match op {
+                TxnOp::R(key) => {
+                    self.hot_keys.read(&key);
+                    let Some(values) = self.read(key, &*staging) else {
+                        if *TXN_STRICT {
+                            return self.abort(&header, &format!("Key {key} doesn't exist"));
+                        }
+                        response_txn.push(json!(["r", key, null]));
+                        continue;
+                    };
+                    response_txn.push(json!(["r", key, values]));
+                }
+                TxnOp::Append(key, val) => {
+                    self.hot_keys.write(&key);
+                    staging.append(&mut self.data, key, val);
+                    response_txn.push(json!(op));
+                }
+                TxnOp::RangeRead(..) if !*TXN_RANGE_READS => {
+                    let text = "Range reads need MAELSTROM_TXN_RANGE_READS";
+                    return vec![self.inner.build_error(&header, ERROR_NOT_SUPPORTED, text)];
+                }
+                TxnOp::RangeRead(lo, hi) => {
+                    let mut keys: BTreeSet<i64> = self.data.keys_in(lo, hi).collect();
+                    keys.extend(staging.staged_keys(lo, hi));
+                    let entries: Vec<_> = keys
+                        .into_iter()
+                        .map(|key| {
+                            self.hot_keys.read(&key);
+                            json!([key, self.read(key, &*staging)])
+                        })
+                        .collect();
+                    response_txn.push(json!(["rr", lo, hi, entries]));
+                }
+                TxnOp::W(..) => {
+                    let text = "Writes aren't supported, keys are lists";
+                    return vec![self.inner.build_error(&header, ERROR_NOT_SUPPORTED, text)];
+                }
             }
         }
+        if simulate_failure() {
+            return self.abort(&header, "Simulated failure");
+        }
+        staging.commit(&mut self.data);
 
-        response_body.insert("txn".to_string(), json!(response_txn));
-        let serialized = serde_json::to_string(&response).unwrap();
-        eprintln!("{}", &serialized);
-        println!("{}", serialized);
+        let responses = self.build_txn_ok(&header, response_txn);
+        for response in &responses {
+            eprintln!("{}", serde_json::to_string(response).unwrap());
+        }
+        responses
     }
 
-    fn read(&self, key: i64, txn: &mut Vec<Value>) {
-        let ret_val = match self.data.get(&key) {
-            None => Value::Null,
-            Some(v) => Value::Array(v.iter().map(|x| Value::from(*x)).collect()),
-        };
-        txn.push(json!(["r", key, ret_val]));
+    // A single `txn_ok`, unless chunking is enabled and the txn is over the limit. See
+    // `TXN_CHUNK_OPS`.
+    fn build_txn_ok(
+        &self,
+        request: &Map<String, Value>,
+        txn: Vec<Value>,
+    ) -> Vec<Map<String, Value>> {
+        if *TXN_CHUNK_OPS == 0 || txn.len() <= *TXN_CHUNK_OPS {
+            let mut response = self.inner.build_response(request, "txn_ok");
+            response["body"]["txn"] = json!(txn);
+            return vec![response];
+        }
+        let count = txn.len().div_ceil(*TXN_CHUNK_OPS);
+        txn.chunks(*TXN_CHUNK_OPS)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut response = self.inner.build_response(request, "txn_ok");
+                response["body"]["txn"] = json!(chunk);
+                response["body"]["chunk"] = json!({ "index": index, "count": count });
+                response
+            })
+            .collect()
     }
 
-    fn append(&mut self, key: i64, val: Value, txn: &mut Vec<Value>) {
-        txn.push(json!(["append", key, val]));
-        let Value::Number(val) = val else {
-            panic!("Invalid append value: {:?}", val)
-        };
-        let val = val.as_i64().unwrap();
+    // `key` as seen by a txn with `staging`, None if it doesn't exist.
+    fn read(&self, key: i64, staging: &dyn Staging) -> Option<Vec<i64>> {
+        let log = self.data.get(key);
+        let staged = staging.staged(key);
+        if log.is_none() && staged.is_empty() {
+            return None;
+        }
+        let committed = log.into_iter().flat_map(|log| log.iter());
+        Some(committed.chain(staged).copied().collect())
+    }
 
-        match self.data.entry(key) {
-            Entry::Occupied(mut entry) => entry.get_mut().push(val),
-            Entry::Vacant(entry) => {
-                entry.insert(vec![val]);
+    // Reply that the txn was aborted, with none of its effects applied. Clients may retry it.
+    fn abort(&self, request: &Map<String, Value>, text: &str) -> Vec<Map<String, Value>> {
+        eprintln!("Aborting txn {}: {text}", serde_json::to_string(request).unwrap());
+        metrics::incr("txn.aborts");
+        vec![self.inner.build_error(request, ERROR_TXN_CONFLICT, text)]
+    }
+
+    // The primary's reply to a txn we forwarded.
+    fn handle_forwarded_reply(&mut self, reply: Map<String, Value>) -> Vec<Map<String, Value>> {
+        self.proxy.translate(&self.inner, reply).into_iter().collect()
+    }
+
+    // As the primary, turn the appends made since the last call into a commit log entry and send it
+    // on. Followers' own appends, from applying entries, are dropped.
+    fn ship(&mut self) -> Vec<Map<String, Value>> {
+        let writes = std::mem::take(&mut self.data.unlogged);
+        if writes.is_empty() || self.followers.is_empty() {
+            return Vec::new();
+        }
+        self.commit_log.append(writes);
+        let end = self.commit_log.end();
+        let mut messages = Vec::new();
+        for (name, follower) in &mut self.followers {
+            let from = follower.sent.max(follower.applied);
+            messages.push(build_entries(&self.inner, &self.commit_log, self.epoch, name, from));
+            follower.sent = end;
+        }
+        messages
+    }
+
+    // As the primary, resend entries followers haven't acked, or a snapshot to those which need
+    // entries we've since dropped.
+    fn reship(&mut self) -> Vec<Map<String, Value>> {
+        let (start, end) = (self.commit_log.start(), self.commit_log.end());
+        let mut messages = Vec::new();
+        let mut behind = Vec::new();
+        for (name, follower) in &mut self.followers {
+            let lag = end.saturating_sub(follower.applied);
+            metrics::set(&format!("datomic.follower_lag.{name}"), lag);
+            if follower.applied < start {
+                behind.push(name.clone());
+            } else if lag > 0 {
+                let from = follower.applied;
+                messages.push(build_entries(&self.inner, &self.commit_log, self.epoch, name, from));
+                follower.sent = end;
+            }
+        }
+        messages.extend(behind.iter().map(|name| self.build_snapshot(name)));
+        messages
+    }
+
+    // As a follower, apply the primary's entries which are next in order. Those we already have are
+    // skipped, and those past a gap wait to be resent. Entries from a primary which has restarted
+    // since we applied its txns aren't applied at all, and our ack tells it to send a snapshot.
+    fn handle_log_entries(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let mut response = self.inner.build_response(&request, "log_entries_ok");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let epoch: u64 = take_field(&mut body, "epoch");
+        let from: u64 = take_field(&mut body, "from");
+        let entries: Vec<WriteSet> = take_field(&mut body, "entries");
+        let primary_epoch = *self.primary_epoch.get_or_insert(epoch);
+        response["body"]["epoch"] = json!(primary_epoch);
+        if primary_epoch != epoch {
+            response["body"]["applied"] = json!(self.applied);
+            return vec![response];
+        }
+        for (txn, writes) in (from..).zip(entries) {
+            if txn > self.applied {
+                break;
+            }
+            if txn < self.applied {
+                continue;
+            }
+            for (key, val) in writes {
+                self.data.append(key, val);
+            }
+            self.applied += 1;
+        }
+        response["body"]["applied"] = json!(self.applied);
+        vec![response]
+    }
+
+    // As a follower, the primary's whole store, through txn `through`, as we've diverged from it.
+    // Each key is reconciled with our own history of it, see `merge`.
+    fn handle_log_snapshot(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let mut response = self.inner.build_response(&request, "log_entries_ok");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let epoch: u64 = take_field(&mut body, "epoch");
+        let through: u64 = take_field(&mut body, "through");
+        let data: Vec<(i64, Vec<i64>)> = take_field(&mut body, "data");
+        let data: BTreeMap<i64, Vec<i64>> = data.into_iter().collect();
+        let keys: BTreeSet<i64> =
+            self.data.keys_in(i64::MIN, i64::MAX).chain(data.keys().copied()).collect();
+        for key in keys {
+            let local: Vec<i64> =
+                self.data.get(key).into_iter().flat_map(|log| log.iter()).copied().collect();
+            let remote = data.get(&key).map_or(&[][..], Vec::as_slice);
+            match merge::merge(key, &local, remote) {
+                Ok(merged) if merged != local => self.data.replace(key, merged),
+                Ok(_) => {}
+                Err(conflict) => {
+                    eprintln!("Keeping our history of diverged key: {conflict:?}");
+                    metrics::incr("datomic.merge_conflicts");
+                }
             }
+        }
+        self.primary_epoch = Some(epoch);
+        self.applied = through;
+        response["body"]["epoch"] = json!(epoch);
+        response["body"]["applied"] = json!(self.applied);
+        vec![response]
+    }
+
+    // As the primary, a follower's progress. Entries every follower has applied are dropped. A
+    // follower which restarted reports less than before, which is taken as is, and is sent a
+    // snapshot if it needs entries which have been dropped.
+    fn handle_log_entries_ok(
+        &mut self,
+        mut request: Map<String, Value>,
+    ) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let epoch: u64 = take_field(&mut body, "epoch");
+        let applied: u64 = take_field(&mut body, "applied");
+        let Some(follower) = self.followers.get_mut(&src) else {
+            eprintln!("Ignoring commit log ack from {src}, which isn't a follower");
+            return Vec::new();
         };
+        if epoch != self.epoch {
+            eprintln!("{src} holds txns from before we restarted, sending a snapshot");
+            metrics::incr("datomic.snapshots");
+            follower.sent = self.commit_log.end();
+            return vec![self.build_snapshot(&src)];
+        }
+        let end = self.commit_log.end();
+        // Nothing past the end of our log was ever sent.
+        follower.applied = applied.min(end);
+        let lag = end.saturating_sub(follower.applied);
+        metrics::set(&format!("datomic.follower_lag.{src}"), lag);
+        metrics::max("datomic.max_follower_lag", lag);
+        if follower.applied < self.commit_log.start() {
+            eprintln!("{src} restarted and needs txns we've dropped, sending a snapshot");
+            metrics::incr("datomic.snapshots");
+            follower.sent = end;
+            return vec![self.build_snapshot(&src)];
+        }
+        let oldest = self.followers.values().map(|follower| follower.applied).min();
+        self.commit_log.truncate(oldest.unwrap_or(0));
+        Vec::new()
+    }
+
+    // A `log_snapshot` of our whole store, which covers every txn in our commit log.
+    fn build_snapshot(&self, dest: &str) -> Map<String, Value> {
+        let data: Vec<_> =
+            self.data.data.iter().map(|(key, log)| (key, log.iter().collect::<Vec<_>>())).collect();
+        let mut message = self.inner.build_message(self.inner.node_id(), dest, "log_snapshot");
+        message["body"]["epoch"] = json!(self.epoch);
+        message["body"]["through"] = json!(self.commit_log.end());
+        message["body"]["data"] = json!(data);
+        message
+    }
+}
+
+// A `log_entries` message with `log`'s entries from `from`, as many as fit. Entries which have been
+// dropped are skipped, leaving a gap the follower acks, to be sent a snapshot.
+fn build_entries(
+    node: &maelstrom_gossip_glommers::node::Node,
+    log: &OffsetLog<WriteSet>,
+    epoch: u64,
+    dest: &str,
+    from: u64,
+) -> Map<String, Value> {
+    let from = from.max(log.start());
+    let entries: Vec<_> = log.read(from..).take(MAX_SHIPPED_ENTRIES).map(|(_, w)| w).collect();
+    let mut message = node.build_message(node.node_id(), dest, "log_entries");
+    message["body"]["epoch"] = json!(epoch);
+    message["body"]["from"] = json!(from);
+    message["body"]["entries"] = json!(entries);
+    message
+}
+
+// Whether to abort a txn, see `TXN_ABORT_RATE`.
+fn simulate_failure() -> bool {
+    if *TXN_ABORT_RATE == 0.0 {
+        return false;
+    }
+    // RandomState is seeded randomly per instance, which is plenty for injecting failures.
+    let random = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+    (random as f64 / u64::MAX as f64) < *TXN_ABORT_RATE
+}
+
+impl Workload for Node {
+    const NAME: &'static str = "datomic";
+    // Strict serializability means txns are applied one at a time, in the order received. Once
+    // every stage is complete will go back and restructure to take advantage of async environ.
+    const CONCURRENT: bool = false;
+
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        let mut messages = match msg_type {
+            "txn" => self.handle_txn(request),
+            "txn_ok" | "error" => self.handle_forwarded_reply(request),
+            "log_entries" => self.handle_log_entries(request),
+            "log_entries_ok" => self.handle_log_entries_ok(request),
+            "log_snapshot" => self.handle_log_snapshot(request),
+            _ => return None,
+        };
+        messages.extend(self.ship());
+        Some(messages)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        LOG_SHIPPING.then_some(SHIP_INTERVAL)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        let mut messages = self.reship();
+        messages.extend(self.proxy.expire(&self.inner));
+        messages
+    }
+
+    fn overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        overlay.add("follower", self.followers.keys());
+        if let Some(primary) = &self.primary {
+            overlay.add("primary", std::iter::once(primary).filter(|&p| p != self.inner.node_id()));
+        }
+        overlay
+    }
+
+    fn on_shutdown(&mut self) {
+        self.hot_keys.publish("txn");
     }
 }
 
-// Strict serializability means we aren't spawning any tasks. Once every stage is complete will go
-// back and restructure to take advantage of async environ.
 #[tokio::main]
 async fn main() {
-    let stdin = async_std::io::stdin();
-    let mut node = Node::new(maelstrom_gossip_glommers::create_node(&stdin).await);
-
-    // Main loop.
-    loop {
-        let request = maelstrom_gossip_glommers::await_request(&stdin).await;
-        let Value::String(msg_type) = &request["body"]["type"] else {
-            panic!("Invalid msg type encoding");
+    // Synthetic client traffic for `--selfdrive`.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.txn(i)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use maelstrom_gossip_glommers::runtime::Outbox;
+
+    use super::*;
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Not a message {value}");
+        };
+        message
+    }
+
+    // `node_id` of a three node cluster shipping its log, n0 being the primary.
+    fn node(node_id: &str) -> Node {
+        std::env::set_var("MAELSTROM_LOG_SHIPPING", "true");
+        let inner = maelstrom_gossip_glommers::node::Node::new(
+            &json!(node_id),
+            &json!(["n0", "n1", "n2"]),
+            Outbox::spawn_writer(),
+        );
+        Node::new(inner)
+    }
+
+    fn append(primary: &mut Node, key: i64, val: i64) -> Vec<Map<String, Value>> {
+        let body = json!({"type": "txn", "msg_id": 1, "txn": [["append", key, val]]});
+        let txn = json!({"src": "c1", "dest": "n0", "body": body});
+        primary.on_message("txn", message(txn)).unwrap()
+    }
+
+    fn ack(primary: &mut Node, src: &str, applied: u64) -> Vec<Map<String, Value>> {
+        let body = json!({
+            "type": "log_entries_ok",
+            "in_reply_to": 1,
+            "epoch": primary.epoch,
+            "applied": applied,
+        });
+        let ack = json!({"src": src, "dest": "n0", "body": body});
+        primary.on_message("log_entries_ok", message(ack)).unwrap()
+    }
+
+    fn of_type<'a>(
+        messages: &'a [Map<String, Value>],
+        msg_type: &str,
+    ) -> Vec<&'a Map<String, Value>> {
+        messages.iter().filter(|message| message["body"]["type"] == msg_type).collect()
+    }
+
+    #[tokio::test]
+    async fn ships_each_txn_to_every_follower() {
+        let mut primary = node("n0");
+        let messages = append(&mut primary, 1, 10);
+        assert_eq!(of_type(&messages, "txn_ok").len(), 1);
+        let shipped = of_type(&messages, "log_entries");
+        let mut dests: Vec<_> =
+            shipped.iter().map(|message| message["dest"].as_str().unwrap()).collect();
+        dests.sort();
+        assert_eq!(dests, ["n1", "n2"]);
+        for message in shipped {
+            assert_eq!(message["body"]["from"], 0);
+            assert_eq!(message["body"]["entries"], json!([[[1, 10]]]));
+        }
+        // Only what's new is shipped, and nothing is resent before it's due.
+        let messages = append(&mut primary, 1, 11);
+        for message in of_type(&messages, "log_entries") {
+            assert_eq!(message["body"]["from"], 1);
+            assert_eq!(message["body"]["entries"], json!([[[1, 11]]]));
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_truncate_what_every_follower_has_applied() {
+        let mut primary = node("n0");
+        append(&mut primary, 1, 10);
+        append(&mut primary, 2, 20);
+        assert!(ack(&mut primary, "n1", 2).is_empty());
+        assert_eq!(primary.commit_log.start(), 0);
+        // Reordered acks don't matter to truncation, which only goes forward.
+        ack(&mut primary, "n2", 1);
+        assert_eq!(primary.commit_log.start(), 1);
+        // n2 is resent only what it hasn't applied.
+        let resent = primary.reship();
+        assert_eq!(resent.len(), 1);
+        assert_eq!((&resent[0]["dest"], &resent[0]["body"]["from"]), (&json!("n2"), &json!(1)));
+        ack(&mut primary, "n2", 2);
+        assert_eq!(primary.commit_log.start(), 2);
+        assert!(primary.reship().is_empty());
+    }
+
+    #[tokio::test]
+    async fn acks_beyond_the_log_are_clamped() {
+        let mut primary = node("n0");
+        append(&mut primary, 1, 10);
+        ack(&mut primary, "n1", 100);
+        ack(&mut primary, "n2", 1);
+        assert_eq!(primary.followers["n1"].applied, 1);
+        assert_eq!(primary.commit_log.start(), 1);
+        assert!(primary.reship().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_restarted_follower_is_sent_a_snapshot() {
+        let mut primary = node("n0");
+        append(&mut primary, 1, 10);
+        append(&mut primary, 1, 11);
+        ack(&mut primary, "n1", 2);
+        ack(&mut primary, "n2", 2);
+        assert_eq!(primary.commit_log.start(), 2);
+
+        // n1 restarted with nothing, and the entries it needs are gone.
+        let messages = ack(&mut primary, "n1", 0);
+        let [snapshot] = &messages[..] else {
+            panic!("Expected a snapshot, got {messages:?}");
         };
+        assert_eq!(snapshot["body"]["type"], "log_snapshot");
+        assert_eq!(snapshot["body"]["through"], 2);
+        assert_eq!(snapshot["body"]["data"], json!([[1, [10, 11]]]));
+        // And again until it acks.
+        let resent = primary.reship();
+        assert_eq!(of_type(&resent, "log_snapshot").len(), 1);
+
+        let mut follower = node("n1");
+        let snapshot = snapshot.clone();
+        let acks = follower.on_message("log_snapshot", snapshot).unwrap();
+        assert_eq!(acks[0]["body"]["applied"], 2);
+        assert_eq!(follower.read(1, &InPlace), Some(vec![10, 11]));
+        ack(&mut primary, "n1", 2);
+        assert!(primary.reship().is_empty());
+    }
 
-        match msg_type.as_str() {
-            "init" => panic!("Already initialized node: {:?}", request),
-            "txn" => node.handle_txn(request),
-            _ => panic!("Unknown msg type {:?}", request),
+    #[tokio::test]
+    async fn followers_apply_entries_in_order() {
+        let mut follower = node("n1");
+        let entries = |from: u64, entries: Value| {
+            let body = json!({
+                "type": "log_entries",
+                "msg_id": 1,
+                "epoch": 7,
+                "from": from,
+                "entries": entries,
+            });
+            message(json!({"src": "n0", "dest": "n1", "body": body}))
         };
+        let acks = follower.on_message("log_entries", entries(1, json!([[[1, 11]]]))).unwrap();
+        assert_eq!(acks[0]["body"]["applied"], 0);
+        let acks =
+            follower.on_message("log_entries", entries(0, json!([[[1, 10]], [[1, 11]]]))).unwrap();
+        assert_eq!(acks[0]["body"]["applied"], 2);
+        let acks =
+            follower.on_message("log_entries", entries(1, json!([[[1, 11]], [[2, 20]]]))).unwrap();
+        assert_eq!(acks[0]["body"]["applied"], 3);
+        assert_eq!(follower.read(1, &InPlace), Some(vec![10, 11]));
+        assert_eq!(follower.read(2, &InPlace), Some(vec![20]));
     }
 }
@@ -1,5 +1,18 @@
+use std::io::Write;
+
 use serde_json::{json, Value};
 
+// Payloads can be megabytes, so only this much of each message is logged.
+const LOG_PREVIEW_BYTES: usize = 1000;
+
+fn preview(s: &str) -> &str {
+    let mut end = s.len().min(LOG_PREVIEW_BYTES);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
 struct MessageBuilder {
     msg_id: i32,
 }
@@ -28,10 +41,11 @@ impl MessageBuilder {
         response
     }
 
-    fn build_echo_ok(&mut self, request: &Value) -> Value {
+    // Moves the payload out of `request` rather than copying it.
+    fn build_echo_ok(&mut self, request: &mut Value) -> Value {
         let mut response = self.build_response(request);
         response["body"]["type"] = "echo_ok".into();
-        response["body"]["echo"] = request["body"]["echo"].clone();
+        response["body"]["echo"] = request["body"]["echo"].take();
         response
     }
 }
@@ -41,13 +55,19 @@ fn main() {
     let mut msg_builder = MessageBuilder::new();
     loop {
         let mut input = String::new();
-        let Ok(_) = stdin.read_line(&mut input) else {
+        let Ok(num_bytes) = stdin.read_line(&mut input) else {
             panic!("Failed to read from stdin");
         };
-        eprintln!("Received {}", input);
-        let Ok(json) = serde_json::from_str::<Value>(&input) else {
-            panic!("Failed to parse input: {input}");
+        if num_bytes == 0 {
+            eprintln!("Stdin closed");
+            return;
+        }
+        eprintln!("Received {} ({num_bytes} bytes)", preview(&input));
+        let Ok(mut json) = serde_json::from_str::<Value>(&input) else {
+            panic!("Failed to parse input: {}", preview(&input));
         };
+        // Free the raw line before building the reply, rather than holding both at once.
+        drop(input);
 
         if json["body"]["type"] == "init" {
             eprintln!("Initialized node {}", json["body"]["node_id"]);
@@ -55,10 +75,11 @@ fn main() {
             let serialized = serde_json::to_string(&init_ok).unwrap();
             println!("{}", serialized);
         } else if json["body"]["type"] == "echo" {
-            eprintln!("Echoing {}", json["body"]);
-            let echo_ok = msg_builder.build_echo_ok(&json);
-            let serialized = serde_json::to_string(&echo_ok).unwrap();
-            println!("{}", serialized);
+            let echo_ok = msg_builder.build_echo_ok(&mut json);
+            // Serialized straight to stdout, without an intermediate String of the whole reply.
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer(&mut stdout, &echo_ok).unwrap();
+            writeln!(stdout).unwrap();
         }
     }
 }
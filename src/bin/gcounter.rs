@@ -1,113 +1,876 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::panic;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::LazyLock;
 use std::time::Duration;
 
-use parking_lot::RwLock;
+use maelstrom_gossip_glommers::crdt::{persist, Health, Versions, Warmup};
+use maelstrom_gossip_glommers::metrics;
+use maelstrom_gossip_glommers::overlay::Overlay;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+// The format of replicate messages, see `Versions`. 0 is from before versioning, which 1 only adds
+// `version` to. 2 adds `keys`, the named counters. 3 adds `through`, see `Deltas`, and peers at 3
+// are sent `delta`s rather than full replicates.
+const REPLICATE_VERSION: u64 = 3;
+
+// The most deltas held for peers which haven't acked them. A peer further behind is sent our full
+// state instead.
+const MAX_DELTAS: usize = 1000;
+
+// With `MAELSTROM_GCOUNTER_TREE`, counts are aggregated up a tree instead of every node gossiping
+// the full map to every other. Each node reports its subtree's total to its parent and the root
+// pushes the global total back down, so replication messages carry a single number rather than n
+// entries. The cost is that a partition cuts off a whole subtree until it heals, and that a node
+// restarting without persisted state can't recover its count from peers, who only hold sums.
+static TREE_MODE: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_GCOUNTER_TREE", false));
+
+// With `MAELSTROM_GCOUNTER_FANOUT`, each replication round outside tree mode goes to at most that
+// many peers rather than all of them, picked at random favoring those with a lower round trip
+// time, see `Node::nearby_peers`. Deltas are tracked per peer, so a peer skipped for some rounds
+// is sent everything it missed when it's next picked. 0, the default, replicates to every peer.
+static FANOUT: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_GCOUNTER_FANOUT", 0));
+
+// With `MAELSTROM_READ_REPAIR`, serving a read also sends a nearby peer our sum as a digest, and
+// the peer replies with a replicate if its sum differs. Reads then actively drive convergence
+// instead of waiting on the next replication round.
+static READ_REPAIR: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_READ_REPAIR", false));
+
+// With `MAELSTROM_GCOUNTER_BATCH_ADDS`, an `add` to the default counter is acked without locking
+// the node, by adding its delta to `PENDING`, which is folded into our count before any other
+// message is handled and on every tick. Under a burst of adds, they no longer queue for the lock
+// behind each other and replication. The cost is durability: adds acked but not yet folded are
+// lost if we crash, as they haven't been persisted.
+static BATCH_ADDS: LazyLock<bool> =
+    LazyLock::new(|| env_or("MAELSTROM_GCOUNTER_BATCH_ADDS", false));
+static PENDING: AtomicI64 = AtomicI64::new(0);
+
+// A binary tree over the sorted node ids, which every node derives identically from init. The
+// parent is fixed rather than picked by round trip time like other peers: parent and child have
+// to agree on it, and a subtree reported to two parents, even briefly while switching, would be
+// counted twice in totals which only ever grow.
+#[derive(Serialize)]
+struct Tree {
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+impl Tree {
+    fn new(node_id: &str, node_ids: &[String]) -> Self {
+        let mut node_ids = node_ids.to_vec();
+        node_ids.sort();
+        let Some(i) = node_ids.iter().position(|n| n == node_id) else {
+            panic!("{node_id} missing from {node_ids:?}");
+        };
+        let parent = (i > 0).then(|| node_ids[(i - 1) / 2].clone());
+        let children =
+            [2 * i + 1, 2 * i + 2].iter().filter_map(|&c| node_ids.get(c).cloned()).collect();
+        Self { parent, children }
+    }
+}
+
+// One node's entry in a named counter: the totals of its increments and of its decrements. Each
+// only grows, so entries merge by max even though the counter itself can go down.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct PnCount {
+    inc: i64,
+    dec: i64,
+}
+
+impl PnCount {
+    fn add(&mut self, delta: i64) {
+        if delta >= 0 {
+            self.inc += delta;
+        } else {
+            self.dec -= delta;
+        }
+    }
+
+    // Returns true if `other` was ahead of us.
+    fn merge(&mut self, other: PnCount) -> bool {
+        let before = (self.inc, self.dec);
+        self.inc = self.inc.max(other.inc);
+        self.dec = self.dec.max(other.dec);
+        before != (self.inc, self.dec)
+    }
+
+    fn value(&self) -> i64 {
+        self.inc - self.dec
+    }
+}
+
+// An entry which changed: a node's count in the default counter, or a (key, node) count in a named
+// one.
+#[derive(Clone, Eq, Hash, PartialEq)]
+enum Changed {
+    Count(String),
+    Keyed(String, String),
+}
+
+// Delta-interval replication, outside tree mode. Rather than the whole map every round, a peer is
+// sent the entries which changed since the last round it acked. Changes are collected in
+// `pending`, and each round seals them into the next numbered delta. A peer which has acked every
+// delta below `acked` is sent a `delta` with the current values of the entries changed in deltas
+// `acked` onwards, and acks its `through`. A peer which has never acked, or is further behind than
+// the deltas we still hold, is sent a full replicate instead, which covers every delta below its
+// `through`. On the receiving side, a delta starting above what we've merged from its sender means
+// we missed some, e.g. because we restarted, and we ask for full state with a `state_request`.
+#[derive(Default)]
+struct Deltas {
+    pending: HashSet<Changed>,
+    // {seq: entries changed}.
+    sealed: BTreeMap<u64, HashSet<Changed>>,
+    next: u64,
+    // {peer: it has acked every delta below this}.
+    acked: HashMap<String, u64>,
+    // {peer: we've merged every delta of its below this}.
+    merged: HashMap<String, u64>,
+}
+
+impl Deltas {
+    fn seal(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        self.sealed.insert(self.next, std::mem::take(&mut self.pending));
+        self.next += 1;
+        // Deltas every peer has acked aren't needed any more, and past the cap peers get full
+        // state.
+        let oldest_needed = self.acked.values().min().copied().unwrap_or(self.next);
+        self.sealed = self.sealed.split_off(&oldest_needed);
+        while self.sealed.len() > MAX_DELTAS {
+            self.sealed.pop_first();
+        }
+    }
+
+    // The entries changed in deltas `from` onwards, or None if we no longer hold them all.
+    fn since(&self, from: u64) -> Option<HashSet<&Changed>> {
+        let oldest = self.sealed.keys().next().copied().unwrap_or(self.next);
+        if from < oldest {
+            return None;
+        }
+        Some(self.sealed.range(from..).flat_map(|(_, changed)| changed).collect())
+    }
+
+    fn acked(&mut self, peer: &str, through: u64) {
+        // An ack beyond what we've sealed is for a previous incarnation of us, from before a
+        // restart. Forget it, so the peer is sent full state.
+        if through > self.next {
+            self.acked.remove(peer);
+            return;
+        }
+        let acked = self.acked.entry(peer.to_owned()).or_default();
+        *acked = through.max(*acked);
+    }
+}
+
+// The counter an add or read is for, if its body names one with `key`. Any JSON value names a
+// counter, so clients can use numbers as well as strings.
+fn counter_key(body: &mut Map<String, Value>) -> Option<String> {
+    body.remove("key").map(|key| match key {
+        Value::String(key) => key,
+        key => key.to_string(),
+    })
+}
+
+#[derive(Serialize)]
 struct Node {
-    inner: maelstrom_gossip_glommers::Node,
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
     node_to_count: HashMap<String, i64>,
+    // Nodes which have left the cluster, see `handle_node_leave`, and the sum of their final counts.
+    // Their entries are folded out of `node_to_count`, as they'll never change again.
+    departed: HashSet<String>,
+    retired_count: i64,
+    // Named counters, for requests with a `key`: {key: {node: count}}. Each is independent of the
+    // others and of the default counter above, and is replicated alongside it. Departed nodes'
+    // entries are folded into `retired_keyed`. Not supported in tree mode.
+    keyed: HashMap<String, HashMap<String, PnCount>>,
+    retired_keyed: HashMap<String, PnCount>,
+    // Tree mode only: the tree, {child: highest total reported for its subtree} and the highest
+    // global total pushed down from our parent. Counts only grow, so both are merged by max.
+    tree: Option<Tree>,
+    subtree_counts: HashMap<String, i64>,
+    global_count: i64,
+    #[serde(skip)]
+    replicate_versions: Versions,
+    #[serde(skip)]
+    health: Health,
+    #[serde(skip)]
+    warmup: Option<Warmup>,
+    #[serde(skip)]
+    deltas: Deltas,
 }
 
 impl Node {
-    fn new(inner: maelstrom_gossip_glommers::Node) -> Self {
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        // Reload our own contribution if we're restarting, so the global sum doesn't go backwards.
+        // The first replication round then re-announces it to our peers.
+        let count: i64 = persist::load(inner.node_id(), "count").unwrap_or(0);
         let mut node_to_count = HashMap::new();
-        node_to_count.insert(inner.node_id.clone(), 0_i64);
-        Self { inner, node_to_count }
+        node_to_count.insert(inner.node_id().to_owned(), count);
+        let tree = TREE_MODE.then(|| Tree::new(inner.node_id(), inner.node_ids()));
+        let (departed, retired_count) =
+            persist::load(inner.node_id(), "retired").unwrap_or_default();
+        let own_keyed: HashMap<String, PnCount> =
+            persist::load(inner.node_id(), "keyed").unwrap_or_default();
+        let keyed = own_keyed
+            .into_iter()
+            .map(|(key, count)| (key, HashMap::from([(inner.node_id().to_owned(), count)])))
+            .collect();
+        let retired_keyed = persist::load(inner.node_id(), "retired_keyed").unwrap_or_default();
+        // Peers only hold subtree sums in tree mode, which we can't learn our state from.
+        let warmup = if *TREE_MODE { None } else { Warmup::start(&inner) };
+        Self {
+            inner,
+            node_to_count,
+            departed,
+            retired_count,
+            keyed,
+            retired_keyed,
+            tree,
+            subtree_counts: HashMap::new(),
+            global_count: 0,
+            replicate_versions: Versions::new(REPLICATE_VERSION, 0),
+            health: Health::new(3 * REPLICATION_INTERVAL),
+            warmup,
+            deltas: Deltas::default(),
+        }
     }
 
-    fn handle_add(&mut self, mut request: Map<String, Value>) {
+    // Handlers return the messages to send, which the caller commits to the outbox.
+    fn handle_add(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
         // Build response before taking fields from `request`.
         let response = self.inner.build_response(&request, "add_ok");
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
 
-        let mut body: Map<String, Value> =
-            maelstrom_gossip_glommers::take_field(&mut request, "body");
-        let delta: i64 = maelstrom_gossip_glommers::take_field(&mut body, "delta");
-        let entry = self.node_to_count.get_mut(&self.inner.node_id).unwrap();
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let delta: i64 = take_field(&mut body, "delta");
+        if let Some(key) = counter_key(&mut body) {
+            self.add_keyed(key, delta);
+            return vec![response];
+        }
+        let entry = self.node_to_count.get_mut(self.inner.node_id()).unwrap();
+        // Persist before the add is visible to reads, replication or the client's ack.
+        persist::store(self.inner.node_id(), "count", &(*entry + delta));
+        *entry += delta;
+        self.deltas.pending.insert(Changed::Count(self.inner.node_id().to_owned()));
+        vec![response]
+    }
+
+    // Add the deltas batched in `PENDING` to our count.
+    fn fold_pending(&mut self) {
+        let delta = PENDING.swap(0, Ordering::Relaxed);
+        if delta == 0 {
+            return;
+        }
+        let entry = self.node_to_count.get_mut(self.inner.node_id()).unwrap();
+        persist::store(self.inner.node_id(), "count", &(*entry + delta));
         *entry += delta;
+        self.deltas.pending.insert(Changed::Count(self.inner.node_id().to_owned()));
+        metrics::incr("gcounter.folds");
+    }
+
+    fn add_keyed(&mut self, key: String, delta: i64) {
+        let node_id = self.inner.node_id().to_owned();
+        let mut count =
+            self.keyed.get(&key).and_then(|c| c.get(&node_id)).copied().unwrap_or_default();
+        count.add(delta);
+        // As with the default counter, persist before the add is visible.
+        let mut own = self.own_keyed();
+        own.insert(&key, count);
+        persist::store(&node_id, "keyed", &own);
+        self.deltas.pending.insert(Changed::Keyed(key.clone(), node_id.clone()));
+        self.keyed.entry(key).or_default().insert(node_id, count);
+    }
+
+    // Our own entry in each named counter, which is what a restart must not lose.
+    fn own_keyed(&self) -> HashMap<&str, PnCount> {
+        let node_id = self.inner.node_id();
+        let own = self.keyed.iter().filter_map(|(key, counts)| Some((key, counts.get(node_id)?)));
+        own.map(|(key, &count)| (key.as_str(), count)).collect()
     }
 
-    fn handle_read(&self, request: Map<String, Value>) {
+    fn keyed_count(&self, key: &str) -> i64 {
+        let retired = self.retired_keyed.get(key).map_or(0, PnCount::value);
+        let counts = self.keyed.get(key).into_iter().flat_map(|counts| counts.values());
+        retired + counts.map(PnCount::value).sum::<i64>()
+    }
+
+    // Reads held during warm-up, now that it's done, see `Warmup`.
+    fn serve_held(&mut self) -> Vec<Map<String, Value>> {
+        let held = self.warmup.as_mut().map(Warmup::take_held).unwrap_or_default();
+        held.into_iter().flat_map(|request| self.handle_read(request)).collect()
+    }
+
+    fn handle_read(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        if let Some(warmup) = self.warmup.as_mut().filter(|warmup| !warmup.done()) {
+            return warmup.defer(&self.inner, request);
+        }
         let mut response = self.inner.build_response(&request, "read_ok");
-        let sum: i64 = self.node_to_count.values().sum();
-        response["body"]["value"] = serde_json::json!(sum);
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
+        self.health.tag_stale(&mut response);
+        let key = request.get_mut("body").and_then(|body| counter_key(body.as_object_mut()?));
+        if let Some(key) = key {
+            response["body"]["value"] = serde_json::json!(self.keyed_count(&key));
+            return vec![response];
+        }
+        response["body"]["value"] = serde_json::json!(self.count());
+        let mut messages = vec![response];
+        // Peers only hold subtree sums in tree mode, which aren't comparable.
+        if *READ_REPAIR && self.tree.is_none() {
+            messages.extend(self.build_repair());
+        }
+        messages
+    }
+
+    fn build_repair(&self) -> Option<Map<String, Value>> {
+        let peer = self.inner.nearby_peer()?;
+        let mut msg = self.inner.build_message(self.inner.node_id(), peer, "repair");
+        msg["body"]["digest"] = serde_json::json!(self.count());
+        metrics::incr("read_repair.requests");
+        Some(msg)
+    }
+
+    // A peer serving a read is checking whether it's behind us, or we're behind it.
+    fn handle_repair(&self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let digest: i64 = take_field(&mut body, "digest");
+        if digest == self.count() {
+            return Vec::new();
+        }
+        metrics::incr("read_repair.repairs");
+        vec![self.build_replicate(&src)]
+    }
+
+    fn count(&self) -> i64 {
+        if self.tree.is_none() {
+            return self.retired_count + self.node_to_count.values().sum::<i64>();
+        }
+        // At the root the subtree total is the global total. Elsewhere our parent's push may lag
+        // behind adds in our own subtree, so don't let reads go backwards.
+        self.global_count.max(self.subtree_count())
+    }
+
+    fn subtree_count(&self) -> i64 {
+        self.node_to_count[self.inner.node_id()] + self.subtree_counts.values().sum::<i64>()
+    }
+
+    // Tree mode: a child's subtree total.
+    fn handle_report(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let value: i64 = take_field(&mut body, "value");
+        let count = self.subtree_counts.entry(src.clone()).or_default();
+        *count = value.max(*count);
+
+        if self.health.heard_from(&src) {
+            return self.on_heal(&src);
+        }
+        Vec::new()
+    }
+
+    // Tree mode: the global total, from our parent.
+    fn handle_total(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let value: i64 = take_field(&mut body, "value");
+        self.global_count = value.max(self.global_count);
+
+        if self.health.heard_from(&src) {
+            return self.on_heal(&src);
+        }
+        Vec::new()
+    }
+
+    // A peer which just started is asking for our state, see `Warmup`.
+    fn handle_state_request(&self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(src) = request["src"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        vec![self.build_replicate(src)]
+    }
+
+    fn handle_replicate(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let (value, keys, through): (_, _, Option<u64>) =
+            match self.replicate_versions.received(&src, &body) {
+                None => return Vec::new(),
+                Some(0 | 1) => (take_field(&mut body, "value"), HashMap::new(), None),
+                Some(2) => (take_field(&mut body, "value"), take_field(&mut body, "keys"), None),
+                Some(_) => (
+                    take_field(&mut body, "value"),
+                    take_field(&mut body, "keys"),
+                    Some(take_field(&mut body, "through")),
+                ),
+            };
+        self.merge(&src, value, keys);
+
+        let mut messages = Vec::new();
+        // Full state covers every delta below `through`, whatever we'd merged before.
+        if let Some(through) = through {
+            self.deltas.merged.insert(src.clone(), through);
+            messages.push(self.build_delta_ok(&src));
+        }
+        if self.health.heard_from(&src) {
+            messages.extend(self.on_heal(&src));
+        }
+        messages
     }
 
-    fn handle_replicate(&mut self, mut request: Map<String, Value>) {
-        let mut body: Map<String, Value> =
-            maelstrom_gossip_glommers::take_field(&mut request, "body");
-        let value: Map<String, Value> = maelstrom_gossip_glommers::take_field(&mut body, "value");
+    fn handle_delta(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        // Deltas are only sent to peers at version 3 or newer.
+        let version = self.replicate_versions.received(&src, &body);
+        let Some(3..) = version else {
+            eprintln!("Dropping delta from {src} at version {version:?}");
+            return Vec::new();
+        };
+        let from: u64 = take_field(&mut body, "from");
+        let through: u64 = take_field(&mut body, "through");
+        self.merge(&src, take_field(&mut body, "value"), take_field(&mut body, "keys"));
+
+        let mut messages = Vec::new();
+        let merged = self.deltas.merged.get(&src).copied().unwrap_or(0);
+        if from > merged {
+            eprintln!("Missed deltas {merged} to {from} from {src}, asking for full state");
+            metrics::incr("delta.gaps");
+            messages.push(self.inner.build_message(self.inner.node_id(), &src, "state_request"));
+        } else {
+            self.deltas.merged.insert(src.clone(), through.max(merged));
+            messages.push(self.build_delta_ok(&src));
+        }
+        if self.health.heard_from(&src) {
+            messages.extend(self.on_heal(&src));
+        }
+        messages
+    }
 
-        // Record the highest value for each node.
-        for (k, v) in value.into_iter().filter(|(k, _v)| *k != self.inner.node_id) {
-            match self.node_to_count.entry(k) {
+    fn build_delta_ok(&self, dest: &str) -> Map<String, Value> {
+        let mut msg = self.inner.build_message(self.inner.node_id(), dest, "delta_ok");
+        msg["body"]["through"] = serde_json::json!(self.deltas.merged[dest]);
+        msg
+    }
+
+    fn handle_delta_ok(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let through: u64 = take_field(&mut body, "through");
+        self.inner.ack_received(&src, through);
+        // A late ack from a node which has left mustn't hold back collecting deltas again.
+        if self.departed.contains(&src) {
+            return Vec::new();
+        }
+        self.deltas.acked(&src, through);
+        if self.health.heard_from(&src) {
+            return self.on_heal(&src);
+        }
+        Vec::new()
+    }
+
+    // Merge a peer's counts, full or delta, recording the entries which changed for our own deltas.
+    fn merge(
+        &mut self,
+        src: &str,
+        value: Map<String, Value>,
+        keys: HashMap<String, HashMap<String, PnCount>>,
+    ) {
+        self.merge_keyed(src, keys);
+        if let Some(warmup) = &mut self.warmup {
+            warmup.heard_from(src);
+        }
+
+        // Record the highest value for each node. That includes our own, which a peer only knows
+        // a higher value for if we restarted without our persisted state.
+        let own_count = self.node_to_count[self.inner.node_id()];
+        for (k, v) in value.into_iter().filter(|(k, _)| !self.departed.contains(k)) {
+            let v = v.as_i64().unwrap();
+            match self.node_to_count.entry(k.clone()) {
+                Entry::Occupied(entry) if *entry.get() >= v => continue,
                 Entry::Occupied(mut entry) => {
-                    let v = v.as_i64().unwrap();
-                    if *entry.get() < v {
-                        entry.insert(v);
-                    }
+                    entry.insert(v);
                 }
                 Entry::Vacant(entry) => {
-                    entry.insert(v.as_i64().unwrap());
+                    entry.insert(v);
+                }
+            }
+            self.deltas.pending.insert(Changed::Count(k));
+        }
+        let recovered_count = self.node_to_count[self.inner.node_id()];
+        if recovered_count != own_count {
+            eprintln!("Recovered our count {recovered_count} from {src}");
+            persist::store(self.inner.node_id(), "count", &recovered_count);
+        }
+    }
+
+    fn merge_keyed(&mut self, src: &str, keys: HashMap<String, HashMap<String, PnCount>>) {
+        let node_id = self.inner.node_id();
+        let mut recovered = false;
+        for (key, counts) in keys {
+            let entries = self.keyed.entry(key.clone()).or_default();
+            for (node, count) in counts.into_iter().filter(|(n, _)| !self.departed.contains(n)) {
+                if !entries.entry(node.clone()).or_default().merge(count) {
+                    continue;
                 }
+                recovered |= node == node_id;
+                self.deltas.pending.insert(Changed::Keyed(key.clone(), node));
             }
         }
+        if recovered {
+            eprintln!("Recovered our named counts from {src}");
+            persist::store(node_id, "keyed", &self.own_keyed());
+        }
     }
 
-    fn send_replication(&self) {
-        let counters = serde_json::json!(&self.node_to_count);
-        for n in self.inner.node_ids.iter().filter(|&n| *n != self.inner.node_id) {
-            let mut msg = self.inner.build_message(&self.inner.node_id, n, "replicate");
-            msg["body"]["value"] = counters.clone();
-            let serialized = serde_json::to_string(&msg).unwrap();
-            println!("{}", serialized);
+    // A node has left the cluster for good, with `count` as its final count and `keys` as its final
+    // entry in each named counter, e.g. as read from the node before it was stopped. Both are
+    // required, as every node is told and must retire the same totals, which it can't if each used
+    // its own view of the node. Replicates still carrying the node's entry are ignored from now on.
+    // Not supported in tree mode, where the node's count is mixed into its ancestors' subtree
+    // totals.
+    fn handle_node_leave(&mut self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let body = &request["body"];
+        let keys = body.get("keys").cloned().unwrap_or_else(|| serde_json::json!({}));
+        let keys: Option<HashMap<String, PnCount>> = serde_json::from_value(keys).ok();
+        let (Some(node), Some(count), Some(keys)) =
+            (body["node"].as_str(), body["count"].as_i64(), keys)
+        else {
+            let text = "node_leave needs a node, its final count and its named counts, if any";
+            return vec![self.inner.build_error(&request, ERROR_MALFORMED_REQUEST, text)];
+        };
+        if node == self.inner.node_id() {
+            let text = "Can't retire ourselves";
+            return vec![self.inner.build_error(&request, ERROR_PRECONDITION_FAILED, text)];
         }
+
+        if self.departed.insert(node.to_owned()) {
+            self.node_to_count.remove(node);
+            self.retired_count += count;
+            persist::store(self.inner.node_id(), "retired", &(&self.departed, self.retired_count));
+            for counts in self.keyed.values_mut() {
+                counts.remove(node);
+            }
+            for (key, count) in keys {
+                let retired = self.retired_keyed.entry(key).or_default();
+                retired.inc += count.inc;
+                retired.dec += count.dec;
+            }
+            persist::store(self.inner.node_id(), "retired_keyed", &self.retired_keyed);
+            // It will never ack again, and would otherwise hold back collecting every delta since.
+            self.deltas.acked.remove(node);
+            self.deltas.merged.remove(node);
+            eprintln!("{node} left, retired count is now {}", self.retired_count);
+        }
+        vec![self.inner.build_response(&request, "node_leave_ok")]
     }
-}
 
-fn spawn_periodic_replication(node: Arc<RwLock<Node>>) {
-    tokio::spawn(async move {
-        loop {
-            node.read().send_replication();
-            tokio::time::sleep(Duration::from_secs(1)).await;
+    // Called when a peer we couldn't reach is back. Catch it up right away instead of waiting for
+    // the next replication round, with only what changed since the last delta it acked, see
+    // `Deltas`. Deltas lost to the partition were never acked, so they're covered.
+    fn on_heal(&self, peer: &str) -> Vec<Map<String, Value>> {
+        if let Some(tree) = &self.tree {
+            return self.build_tree_replication(tree, |n| n == peer);
         }
-    });
+        self.build_replication(peer).into_iter().collect()
+    }
+
+    fn build_replicate(&self, dest: &str) -> Map<String, Value> {
+        let mut msg = self.inner.build_message(self.inner.node_id(), dest, "replicate");
+        msg["body"]["value"] = serde_json::json!(&self.node_to_count);
+        msg["body"]["keys"] = serde_json::json!(&self.keyed);
+        msg["body"]["through"] = serde_json::json!(self.deltas.next);
+        self.replicate_versions.stamp(&mut msg);
+        self.health.sent_to(dest);
+        self.inner.await_ack(dest, self.deltas.next);
+        msg
+    }
+
+    // What `dest` is missing, see `Deltas`, or None if it's up to date.
+    fn build_replication(&self, dest: &str) -> Option<Map<String, Value>> {
+        let Some(&acked) = self.deltas.acked.get(dest) else {
+            return Some(self.build_replicate(dest));
+        };
+        if acked >= self.deltas.next {
+            return None;
+        }
+        let changed = match self.deltas.since(acked) {
+            Some(changed) if self.replicate_versions.for_peer(dest) >= 3 => changed,
+            _ => return Some(self.build_replicate(dest)),
+        };
+        let mut value = Map::new();
+        let mut keys: HashMap<&str, HashMap<&str, PnCount>> = HashMap::new();
+        for changed in changed {
+            match changed {
+                Changed::Count(node) => {
+                    if let Some(&count) = self.node_to_count.get(node) {
+                        value.insert(node.clone(), serde_json::json!(count));
+                    }
+                }
+                Changed::Keyed(key, node) => {
+                    if let Some(&count) = self.keyed.get(key).and_then(|c| c.get(node)) {
+                        keys.entry(key).or_default().insert(node, count);
+                    }
+                }
+            }
+        }
+        let mut msg = self.inner.build_message(self.inner.node_id(), dest, "delta");
+        msg["body"]["from"] = serde_json::json!(acked);
+        msg["body"]["through"] = serde_json::json!(self.deltas.next);
+        msg["body"]["value"] = serde_json::json!(value);
+        msg["body"]["keys"] = serde_json::json!(keys);
+        self.replicate_versions.stamp(&mut msg);
+        self.health.sent_to(dest);
+        self.inner.await_ack(dest, self.deltas.next);
+        metrics::incr("delta.sent");
+        Some(msg)
+    }
+
+    fn send_replication(&mut self) -> Vec<Map<String, Value>> {
+        self.health.check(self.inner.node_ids().len());
+        if let Some(tree) = &self.tree {
+            return self.build_tree_replication(tree, |_| true);
+        }
+        self.deltas.seal();
+        let fanout = if *FANOUT == 0 { usize::MAX } else { *FANOUT };
+        // Every peer, nearest first with some randomness, see `Node::nearby_peers`.
+        let peers = self.inner.nearby_peers(usize::MAX);
+        peers
+            .into_iter()
+            .filter(|&n| !self.departed.contains(n))
+            .take(fanout)
+            .filter_map(|n| self.build_replication(n))
+            .collect()
+    }
+
+    // Our subtree's total up to our parent and the global total down to our children, for the
+    // tree neighbors for which `to` returns true.
+    fn build_tree_replication(
+        &self,
+        tree: &Tree,
+        to: impl Fn(&str) -> bool,
+    ) -> Vec<Map<String, Value>> {
+        let mut messages = Vec::new();
+        for parent in tree.parent.iter().filter(|&n| to(n)) {
+            let mut msg = self.inner.build_message(self.inner.node_id(), parent, "report");
+            msg["body"]["value"] = serde_json::json!(self.subtree_count());
+            self.health.sent_to(parent);
+            messages.push(msg);
+        }
+        for child in tree.children.iter().filter(|&n| to(n)) {
+            let mut msg = self.inner.build_message(self.inner.node_id(), child, "total");
+            msg["body"]["value"] = serde_json::json!(self.count());
+            self.health.sent_to(child);
+            messages.push(msg);
+        }
+        messages
+    }
 }
 
-fn spawn_handler(node: Arc<RwLock<Node>>, request: Map<String, Value>) {
-    tokio::spawn(async move {
-        let Value::String(msg_type) = &request["body"]["type"] else {
-            panic!("Invalid msg type encoding");
+impl Workload for Node {
+    const NAME: &'static str = "gcounter";
+
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message_unlocked(
+        node: &maelstrom_gossip_glommers::node::Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        if !*BATCH_ADDS || msg_type != "add" || request["body"].get("key").is_some() {
+            return None;
+        }
+        let Some(delta) = request["body"]["delta"].as_i64() else {
+            panic!("Invalid request {:?}", request);
         };
+        PENDING.fetch_add(delta, Ordering::Relaxed);
+        metrics::incr("gcounter.batched_adds");
+        Some(vec![node.build_response(request, "add_ok")])
+    }
 
-        match msg_type.as_str() {
-            "init" => panic!("Already initialized node: {:?}", request),
-            "add" => node.write().handle_add(request),
-            "read" => node.read().handle_read(request),
-            "replicate" => node.write().handle_replicate(request),
-            _ => panic!("Unknown msg type {:?}", request),
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        self.fold_pending();
+        // Named counters are only replicated by gossip, not up and down the tree.
+        if self.tree.is_some() && request["body"].get("key").is_some() {
+            return None;
+        }
+        let mut messages = match msg_type {
+            "add" => self.handle_add(request),
+            "read" => self.handle_read(request),
+            "replicate" => self.handle_replicate(request),
+            "delta" => self.handle_delta(request),
+            "delta_ok" => self.handle_delta_ok(request),
+            "state_request" => self.handle_state_request(request),
+            "report" => self.handle_report(request),
+            "total" => self.handle_total(request),
+            "repair" => self.handle_repair(request),
+            "node_leave" if self.tree.is_none() => self.handle_node_leave(request),
+            _ => return None,
         };
-    });
+        messages.extend(self.serve_held());
+        Some(messages)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(REPLICATION_INTERVAL)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        self.fold_pending();
+        let mut messages = self.send_replication();
+        messages.extend(self.serve_held());
+        messages
+    }
+
+    // Persist adds acked since the last fold.
+    fn on_shutdown(&mut self) {
+        self.fold_pending();
+    }
+
+    fn overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        match &self.tree {
+            Some(tree) => {
+                overlay.add("parent", &tree.parent);
+                overlay.add("child", &tree.children);
+            }
+            None => {
+                let peers = self.inner.node_ids().iter().filter(|&n| n != self.inner.node_id());
+                overlay.add("replica", peers);
+            }
+        }
+        overlay
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let stdin = async_std::io::stdin();
-    let node = Node::new(maelstrom_gossip_glommers::create_node(&stdin).await);
-    let node = Arc::new(RwLock::new(node));
+    // Synthetic client traffic for `--selfdrive`.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.gcounter(i)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use maelstrom_gossip_glommers::runtime::Outbox;
+    use maelstrom_gossip_glommers::testing::xorshift;
+
+    use super::*;
+
+    fn rng(seed: u64) -> impl FnMut() -> i64 {
+        let mut next = xorshift(seed);
+        move || (next() % 100) as i64
+    }
+
+    fn merged(mut a: PnCount, b: PnCount) -> PnCount {
+        a.merge(b);
+        a
+    }
+
+    fn parts(count: PnCount) -> (i64, i64) {
+        (count.inc, count.dec)
+    }
+
+    #[test]
+    fn pn_count_merge_is_a_semilattice() {
+        for seed in 1..=1000 {
+            let mut next = rng(seed);
+            let mut random = || PnCount { inc: next(), dec: next() };
+            let (a, b, c) = (random(), random(), random());
+            assert_eq!(parts(merged(a, b)), parts(merged(b, a)), "Seed {seed}");
+            let (ab_c, a_bc) = (merged(merged(a, b), c), merged(a, merged(b, c)));
+            assert_eq!(parts(ab_c), parts(a_bc), "Seed {seed}");
+            assert_eq!(parts(merged(a, a)), parts(a), "Seed {seed}");
+            assert_eq!(parts(merged(merged(a, b), b)), parts(merged(a, b)), "Seed {seed}");
+        }
+    }
+
+    #[test]
+    fn pn_count_merge_reports_whether_it_changed() {
+        for seed in 1..=1000 {
+            let mut next = rng(seed);
+            let (a, b) =
+                (PnCount { inc: next(), dec: next() }, PnCount { inc: next(), dec: next() });
+            let mut merged = a;
+            let changed = merged.merge(b);
+            assert_eq!(changed, b.inc > a.inc || b.dec > a.dec, "Seed {seed}");
+            assert!(!merged.merge(b), "Seed {seed}");
+        }
+    }
+
+    #[test]
+    fn pn_count_value_follows_adds() {
+        for seed in 1..=100 {
+            let mut next = rng(seed);
+            let mut count = PnCount::default();
+            let mut sum = 0;
+            for _ in 0..50 {
+                let delta = next() - 50;
+                count.add(delta);
+                sum += delta;
+                assert!(count.inc >= 0 && count.dec >= 0, "Seed {seed}");
+            }
+            assert_eq!(count.value(), sum, "Seed {seed}");
+        }
+    }
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    // A change of our own, sealed into the next delta.
+    fn seal_change(node: &mut Node) {
+        node.deltas.pending.insert(Changed::Count("n0".to_owned()));
+        node.deltas.seal();
+    }
+
+    #[tokio::test]
+    async fn deltas_are_collected_once_a_lagging_node_leaves() {
+        let inner = maelstrom_gossip_glommers::node::Node::new(
+            &serde_json::json!("n0"),
+            &serde_json::json!(["n0", "n1", "n2"]),
+            Outbox::spawn_writer(),
+        );
+        let mut node = Node::new(inner);
+        node.deltas.acked("n1", 0);
+        node.deltas.acked("n2", 0);
+        for _ in 0..3 {
+            seal_change(&mut node);
+            node.deltas.acked("n1", node.deltas.next);
+        }
+        // n2 never acked, so every delta is held for it.
+        assert_eq!(node.deltas.sealed.len(), 3);
 
-    spawn_periodic_replication(Arc::clone(&node));
+        let leave = serde_json::json!({
+            "src": "c1",
+            "dest": "n0",
+            "body": {"type": "node_leave", "msg_id": 1, "node": "n2", "count": 0},
+        });
+        let response = node.handle_node_leave(message(leave));
+        assert_eq!(response[0]["body"]["type"], "node_leave_ok");
+        let late_ack = serde_json::json!({
+            "src": "n2",
+            "dest": "n0",
+            "body": {"type": "delta_ok", "through": 0},
+        });
+        node.handle_delta_ok(message(late_ack));
 
-    // Main loop.
-    loop {
-        let request = maelstrom_gossip_glommers::await_request(&stdin).await;
-        spawn_handler(Arc::clone(&node), request);
+        seal_change(&mut node);
+        assert_eq!(node.deltas.sealed.keys().collect::<Vec<_>>(), [&3]);
     }
 }
@@ -1,94 +1,509 @@
-use std::collections::{HashSet};
-use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::LazyLock;
 use std::time::Duration;
-use std::{panic};
 
-use parking_lot::RwLock;
+use maelstrom_gossip_glommers::crdt::{Health, Versions, Warmup};
+use maelstrom_gossip_glommers::kv::OffsetLog;
+use maelstrom_gossip_glommers::metrics;
+use maelstrom_gossip_glommers::overlay::Overlay;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(5);
 
+// The format of replicate messages, see `Versions`. 0 is from before versioning, which 1 only adds
+// `version` to. Peers at 2 are sent `delta`s rather than our whole set, see `Watermarks`.
+const REPLICATE_VERSION: u64 = 2;
+
+// With `MAELSTROM_READ_REPAIR`, serving a read also sends a nearby peer a digest of our state, and
+// the peer replies with a replicate if its state differs. Reads then actively drive convergence
+// instead of waiting on the next replication round.
+static READ_REPAIR: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_READ_REPAIR", false));
+
+// A node started with `MAELSTROM_JOIN_VIA` joins a running cluster through that node, its sponsor,
+// rather than assuming the init node_ids are everyone. It sends the sponsor a `join` until it gets
+// back a `join_ok` with the cluster's node_ids and the sponsor's state. The sponsor tells the other
+// nodes with `member_added`, and a replicate from a node we didn't know about adds it too, so the
+// new node becomes a replication target everywhere.
+static JOIN_VIA: LazyLock<Option<String>> =
+    LazyLock::new(|| std::env::var("MAELSTROM_JOIN_VIA").ok());
+
+// With `MAELSTROM_GSET_PAGE_SIZE`, our set, or what a peer is missing of it, is sent in replicates
+// or deltas of at most that many elements each, rather than in one message, which at hundreds of
+// thousands of elements makes for a giant one. Merging is a union, so pages need no reassembly. 0,
+// the default, sends everything at once.
+static PAGE_SIZE: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_GSET_PAGE_SIZE", 0));
+
+// Not for use under Maelstrom, whose clients expect the whole set: with
+// `MAELSTROM_GSET_READ_PAGING`, a `read` with a `limit` is answered with at most that many
+// elements, in ascending order, and a `next` continuation token if there are more. The token is
+// passed back as `after` to read the next page.
+static READ_PAGING: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_GSET_READ_PAGING", false));
+
+// Cheap to compare, and elements are only ever added, so equal digests almost always mean equal
+// sets.
+#[derive(Deserialize, PartialEq, Serialize)]
+struct Digest {
+    len: usize,
+    sum: u64,
+}
+
+// Per-peer watermarks, so that a replication round, or catching up a peer after a heal, sends only
+// the elements the peer hasn't acked rather than the whole set. Every element we add, whether from
+// a client or a peer, is appended to `log`. A peer is sent a `delta` with the elements at offsets
+// `from` to `through`, and acks how far it has merged with a `delta_ok`. A delta starting beyond
+// what we've merged from its sender means we missed some, e.g. because we restarted, and acking
+// what we have makes the sender go back. The log holds every element, so a peer can always be
+// caught up from it, however far behind it is.
+#[derive(Default)]
+struct Watermarks {
+    log: OffsetLog<u64>,
+    // {peer: it has acked every offset below this}.
+    acked: HashMap<String, u64>,
+    // {peer: we've merged every offset of its log below this}.
+    merged: HashMap<String, u64>,
+}
+
+// Our set, and the log of it which peers are caught up from.
+#[derive(Default, Serialize)]
+struct GSet {
+    // Ordered, so it can be paged through.
+    messages: BTreeSet<u64>,
+    #[serde(skip)]
+    watermarks: Watermarks,
+}
+
+impl GSet {
+    // Join `elements` into the set, logging the new ones for peers, see `Watermarks`.
+    fn extend(&mut self, elements: impl IntoIterator<Item = u64>) {
+        for element in elements {
+            if self.messages.insert(element) {
+                self.watermarks.log.append(element);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
 struct Node {
-    inner: maelstrom_gossip_glommers::Node,
-    messages: HashSet<u64>,
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    #[serde(flatten)]
+    set: GSet,
+    // Our sponsor, until we've joined. See `JOIN_VIA`.
+    joining_via: Option<String>,
+    #[serde(skip)]
+    replicate_versions: Versions,
+    #[serde(skip)]
+    health: Health,
+    #[serde(skip)]
+    warmup: Option<Warmup>,
 }
 
 impl Node {
-    fn new(inner: maelstrom_gossip_glommers::Node) -> Self {
-        Self { inner, messages: HashSet::new() }
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        let warmup = Warmup::start(&inner);
+        Self {
+            inner,
+            set: GSet::default(),
+            joining_via: JOIN_VIA.clone(),
+            replicate_versions: Versions::new(REPLICATE_VERSION, 0),
+            health: Health::new(3 * REPLICATION_INTERVAL),
+            warmup,
+        }
     }
 
-    fn handle_add(&mut self, mut request: Map<String, Value>) {
+    // Handlers return the messages to send, which the caller commits to the outbox.
+    fn handle_add(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
         // Build response before taking fields from `request`.
         let response = self.inner.build_response(&request, "add_ok");
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
 
-        let mut body: Map<String, Value> =
-            maelstrom_gossip_glommers::take_field(&mut request, "body");
-        let element: u64 = maelstrom_gossip_glommers::take_field(&mut body, "element");
-        self.messages.insert(element);
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let element: u64 = take_field(&mut body, "element");
+        self.set.extend([element]);
+        vec![response]
+    }
+
+    // Reads held during warm-up, now that it's done, see `Warmup`.
+    fn serve_held(&mut self) -> Vec<Map<String, Value>> {
+        let held = self.warmup.as_mut().map(Warmup::take_held).unwrap_or_default();
+        held.into_iter().flat_map(|request| self.handle_read(request)).collect()
     }
 
-    fn handle_read(&self, request: Map<String, Value>) {
+    fn handle_read(&mut self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        if let Some(warmup) = self.warmup.as_mut().filter(|warmup| !warmup.done()) {
+            return warmup.defer(&self.inner, request);
+        }
         let mut response = self.inner.build_response(&request, "read_ok");
-        response["body"]["value"] = serde_json::json!(&self.messages);
-        let serialized = serde_json::to_string(&response).unwrap();
-        println!("{}", serialized);
+        match request["body"]["limit"].as_u64().filter(|_| *READ_PAGING) {
+            Some(limit) => {
+                let (page, next) = self.page(request["body"]["after"].as_u64(), limit as usize);
+                response["body"]["value"] = serde_json::json!(page);
+                if let Some(next) = next {
+                    response["body"]["next"] = serde_json::json!(next);
+                }
+            }
+            None => response["body"]["value"] = serde_json::json!(&self.set.messages),
+        }
+        self.health.tag_stale(&mut response);
+        let mut messages = vec![response];
+        if *READ_REPAIR {
+            messages.extend(self.build_repair());
+        }
+        messages
     }
 
-    fn handle_replicate(&mut self, mut request: Map<String, Value>) {
-        let mut body: Map<String, Value> =
-            maelstrom_gossip_glommers::take_field(&mut request, "body");
-        let value: HashSet<u64> = maelstrom_gossip_glommers::take_field(&mut body, "value");
-        self.messages.extend(value.into_iter());
+    // Up to `limit` elements above `after`, and the token to continue from if there are more.
+    fn page(&self, after: Option<u64>, limit: usize) -> (Vec<u64>, Option<u64>) {
+        let from = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut elements = self.set.messages.range((from, Bound::Unbounded)).copied();
+        let page: Vec<_> = elements.by_ref().take(limit).collect();
+        let next = page.last().copied().filter(|_| elements.next().is_some());
+        (page, next)
     }
 
-    fn send_replication(&self) {
-        for n in self.inner.node_ids.iter().filter(|&n| *n != self.inner.node_id) {
-            let mut msg = self.inner.build_message(&self.inner.node_id, n, "replicate");
-            msg["body"]["value"] = serde_json::json!(&self.messages);
-            let serialized = serde_json::to_string(&msg).unwrap();
-            println!("{}", serialized);
+    fn digest(&self) -> Digest {
+        Digest {
+            len: self.set.messages.len(),
+            sum: self.set.messages.iter().fold(0, |a, b| a.wrapping_add(*b)),
         }
     }
-}
 
-fn spawn_periodic_replication(node: Arc<RwLock<Node>>) {
-    tokio::spawn(async move {
+    fn build_repair(&self) -> Option<Map<String, Value>> {
+        let peer = self.inner.nearby_peer()?;
+        let mut msg = self.inner.build_message(self.inner.node_id(), peer, "repair");
+        msg["body"]["digest"] = serde_json::json!(self.digest());
+        metrics::incr("read_repair.requests");
+        Some(msg)
+    }
+
+    // A peer serving a read is checking whether it's missing anything we have.
+    fn handle_repair(&self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let digest: Digest = take_field(&mut body, "digest");
+        if digest == self.digest() {
+            return Vec::new();
+        }
+        metrics::incr("read_repair.repairs");
+        self.build_replicates(&src)
+    }
+
+    // A peer which just started is asking for our state, see `Warmup`.
+    fn handle_state_request(&self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(src) = request["src"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        self.build_replicates(src)
+    }
+
+    fn handle_replicate(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        // Every version so far has the same fields.
+        if self.replicate_versions.received(&src, &body).is_none() {
+            return Vec::new();
+        }
+        let value: HashSet<u64> = take_field(&mut body, "value");
+        self.set.extend(value);
+        if let Some(warmup) = &mut self.warmup {
+            warmup.heard_from(&src);
+        }
+        if self.inner.add_node(&src) {
+            eprintln!("{src} joined, having replicated to us");
+        }
+
+        if self.health.heard_from(&src) {
+            return self.on_heal(&src);
+        }
+        Vec::new()
+    }
+
+    fn handle_delta(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        // Deltas are only sent to peers at version 2 or newer.
+        let version = self.replicate_versions.received(&src, &body);
+        let Some(2..) = version else {
+            eprintln!("Dropping delta from {src} at version {version:?}");
+            return Vec::new();
+        };
+        let from: u64 = take_field(&mut body, "from");
+        let through: u64 = take_field(&mut body, "through");
+        let value: Vec<u64> = take_field(&mut body, "value");
+        self.set.extend(value);
+
+        let merged = self.set.watermarks.merged.entry(src.clone()).or_default();
+        if from <= *merged {
+            // Not the greater of the two, as a sender which restarted numbers its log from 0 again.
+            *merged = through;
+        } else {
+            eprintln!("Missed elements {merged} to {from} from {src}, acking what we have");
+            metrics::incr("delta.gaps");
+        }
+        let mut ack = self.inner.build_message(self.inner.node_id(), &src, "delta_ok");
+        ack["body"]["through"] = serde_json::json!(*merged);
+        let mut messages = vec![ack];
+        if self.health.heard_from(&src) {
+            messages.extend(self.on_heal(&src));
+        }
+        messages
+    }
+
+    fn handle_delta_ok(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let src: String = take_field(&mut request, "src");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let through: u64 = take_field(&mut body, "through");
+        self.inner.ack_received(&src, through);
+        // An ack beyond our log is for a previous incarnation of us, from before a restart, so the
+        // peer is sent everything. An older ack than before means the peer missed some, see
+        // `Watermarks`, so it's taken as is.
+        let through = if through > self.set.watermarks.log.end() { 0 } else { through };
+        self.set.watermarks.acked.insert(src.clone(), through);
+        if self.health.heard_from(&src) {
+            return self.on_heal(&src);
+        }
+        Vec::new()
+    }
+
+    fn build_join(&self) -> Option<Map<String, Value>> {
+        let sponsor = self.joining_via.as_ref()?;
+        Some(self.inner.build_message(self.inner.node_id(), sponsor, "join"))
+    }
+
+    // As a sponsor: a new node is joining through us. Send it the cluster and our state, and tell
+    // everyone else about it. A repeated join, because our `join_ok` was lost, is answered again.
+    fn handle_join(&mut self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(joiner) = request["src"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let mut messages = Vec::new();
+        if self.inner.add_node(joiner) {
+            eprintln!("{joiner} joined through us");
+            let peers = self.inner.node_ids().iter();
+            for n in peers.filter(|&n| n != joiner && n != self.inner.node_id()) {
+                let mut msg = self.inner.build_message(self.inner.node_id(), n, "member_added");
+                msg["body"]["node"] = serde_json::json!(joiner);
+                messages.push(msg);
+            }
+        }
+        let mut response = self.inner.build_response(&request, "join_ok");
+        response["body"]["node_ids"] = serde_json::json!(self.inner.node_ids());
+        response["body"]["value"] = serde_json::json!(&self.set.messages);
+        self.health.sent_to(joiner);
+        messages.push(response);
+        messages
+    }
+
+    fn handle_join_ok(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let node_ids: Vec<String> = take_field(&mut body, "node_ids");
+        let value: HashSet<u64> = take_field(&mut body, "value");
+        for n in &node_ids {
+            self.inner.add_node(n);
+        }
+        self.set.extend(value);
+        if self.joining_via.take().is_some() {
+            eprintln!("Joined, the cluster is {:?}", self.inner.node_ids());
+        }
+        Vec::new()
+    }
+
+    fn handle_member_added(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let node: String = take_field(&mut body, "node");
+        if node != self.inner.node_id() && self.inner.add_node(&node) {
+            eprintln!("{node} joined");
+            return self.build_replicates(&node);
+        }
+        Vec::new()
+    }
+
+    // Called when a peer we couldn't reach is back. Catch it up right away instead of waiting for
+    // the next replication round, with only the elements it hasn't acked, see `Watermarks`.
+    // Elements sent during the partition were never acked, so they're covered.
+    fn on_heal(&self, peer: &str) -> Vec<Map<String, Value>> {
+        self.build_replication(peer)
+    }
+
+    // The elements `dest` hasn't acked, in deltas of at most `PAGE_SIZE` elements, or our whole
+    // set to a peer from before deltas. Nothing if it's up to date.
+    fn build_replication(&self, dest: &str) -> Vec<Map<String, Value>> {
+        if self.replicate_versions.for_peer(dest) < 2 {
+            return self.build_replicates(dest);
+        }
+        let page_size = if *PAGE_SIZE == 0 { u64::MAX } else { *PAGE_SIZE as u64 };
+        let log = &self.set.watermarks.log;
+        let mut from = self.set.watermarks.acked.get(dest).copied().unwrap_or(0);
+        let mut deltas = Vec::new();
+        while from < log.end() {
+            let through = log.end().min(from.saturating_add(page_size));
+            let value: Vec<u64> = log.read(from..through).map(|(_, &element)| element).collect();
+            let mut msg = self.inner.build_message(self.inner.node_id(), dest, "delta");
+            msg["body"]["from"] = serde_json::json!(from);
+            msg["body"]["through"] = serde_json::json!(through);
+            msg["body"]["value"] = serde_json::json!(value);
+            self.replicate_versions.stamp(&mut msg);
+            metrics::incr("delta.sent");
+            deltas.push(msg);
+            from = through;
+        }
+        if !deltas.is_empty() {
+            self.health.sent_to(dest);
+            self.inner.await_ack(dest, from);
+        }
+        deltas
+    }
+
+    // Our set, in pages if `PAGE_SIZE` is set.
+    fn build_replicates(&self, dest: &str) -> Vec<Map<String, Value>> {
+        self.health.sent_to(dest);
+        if *PAGE_SIZE == 0 {
+            return vec![self.build_replicate(dest, serde_json::json!(&self.set.messages))];
+        }
+        let mut replicates = Vec::new();
+        let mut after = None;
         loop {
-            node.read().send_replication();
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            let (page, next) = self.page(after, *PAGE_SIZE);
+            replicates.push(self.build_replicate(dest, serde_json::json!(page)));
+            if next.is_none() {
+                return replicates;
+            }
+            after = next;
         }
-    });
+    }
+
+    fn build_replicate(&self, dest: &str, value: Value) -> Map<String, Value> {
+        let mut msg = self.inner.build_message(self.inner.node_id(), dest, "replicate");
+        msg["body"]["value"] = value;
+        self.replicate_versions.stamp(&mut msg);
+        msg
+    }
+
+    fn send_replication(&self) -> Vec<Map<String, Value>> {
+        self.health.check(self.inner.node_ids().len());
+        self.inner
+            .node_ids()
+            .iter()
+            .filter(|&n| *n != self.inner.node_id())
+            .flat_map(|n| self.build_replication(n))
+            .collect()
+    }
 }
 
-fn spawn_handler(node: Arc<RwLock<Node>>, request: Map<String, Value>) {
-    tokio::spawn(async move {
-        let Value::String(msg_type) = &request["body"]["type"] else {
-            panic!("Invalid msg type encoding");
-        };
+impl Workload for Node {
+    const NAME: &'static str = "gset";
 
-        match msg_type.as_str() {
-            "init" => panic!("Already initialized node: {:?}", request),
-            "add" => node.write().handle_add(request),
-            "read" => node.read().handle_read(request),
-            "replicate" => node.write().handle_replicate(request),
-            _ => panic!("Unknown msg type {:?}", request),
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        let mut messages = match msg_type {
+            "add" => self.handle_add(request),
+            "read" => self.handle_read(request),
+            "replicate" => self.handle_replicate(request),
+            "delta" => self.handle_delta(request),
+            "delta_ok" => self.handle_delta_ok(request),
+            "state_request" => self.handle_state_request(request),
+            "repair" => self.handle_repair(request),
+            "join" => self.handle_join(request),
+            "join_ok" => self.handle_join_ok(request),
+            "member_added" => self.handle_member_added(request),
+            _ => return None,
         };
-    });
+        messages.extend(self.serve_held());
+        Some(messages)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(REPLICATION_INTERVAL)
+    }
+
+    // The first tick is right after init, which is when a joining node first asks to join.
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        let mut messages = self.send_replication();
+        messages.extend(self.build_join());
+        messages.extend(self.serve_held());
+        messages
+    }
+
+    fn overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        let peers = self.inner.node_ids().iter().filter(|&n| n != self.inner.node_id());
+        overlay.add("replica", peers);
+        overlay
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let stdin = async_std::io::stdin();
-    let node = Node::new(maelstrom_gossip_glommers::create_node(&stdin).await);
-    let node = Arc::new(RwLock::new(node));
+    // Synthetic client traffic for `--selfdrive`.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.gset(i)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use maelstrom_gossip_glommers::testing::xorshift;
+
+    use super::*;
 
-    spawn_periodic_replication(Arc::clone(&node));
+    fn random_set(seed: u64) -> GSet {
+        let mut next = xorshift(seed);
+        let mut set = GSet::default();
+        let len = next() % 20;
+        set.extend((0..len).map(|_| next() % 30));
+        set
+    }
+
+    fn merged(mut a: GSet, b: &GSet) -> GSet {
+        a.extend(b.messages.iter().copied());
+        a
+    }
+
+    // What a merge must agree on: the elements and, order aside, the log peers are caught up
+    // from, which must hold every element exactly once.
+    fn state(set: &GSet) -> (BTreeSet<u64>, BTreeSet<u64>) {
+        let log = &set.watermarks.log;
+        let logged: BTreeSet<u64> = log.iter().copied().collect();
+        assert_eq!(logged.len(), log.len());
+        (set.messages.clone(), logged)
+    }
+
+    #[test]
+    fn union_is_a_semilattice() {
+        for seed in 1..=300 {
+            let (a, b, c) = (random_set(seed), random_set(seed + 1000), random_set(seed + 2000));
+            let ab = merged(merged(GSet::default(), &a), &b);
+            let ba = merged(merged(GSet::default(), &b), &a);
+            assert_eq!(state(&ab), state(&ba), "Seed {seed}");
+            let ab_c = merged(merged(merged(GSet::default(), &a), &b), &c);
+            let a_bc =
+                merged(merged(GSet::default(), &a), &merged(merged(GSet::default(), &b), &c));
+            assert_eq!(state(&ab_c), state(&a_bc), "Seed {seed}");
+            assert_eq!(state(&merged(merged(GSet::default(), &a), &a)), state(&a), "Seed {seed}");
+            assert_eq!(state(&merged(merged(GSet::default(), &ab), &b)), state(&ab), "Seed {seed}");
+            let expected: BTreeSet<u64> = a.messages.union(&b.messages).copied().collect();
+            assert_eq!(state(&ab), (expected.clone(), expected), "Seed {seed}");
+        }
+    }
 
-    // Main loop.
-    loop {
-        let request = maelstrom_gossip_glommers::await_request(&stdin).await;
-        spawn_handler(Arc::clone(&node), request);
+    #[test]
+    fn merging_leaves_peer_watermarks_alone() {
+        let mut set = random_set(1);
+        set.watermarks.acked.insert("n1".to_owned(), 3);
+        set.watermarks.merged.insert("n1".to_owned(), 2);
+        let set = merged(set, &random_set(2));
+        assert_eq!(set.watermarks.acked["n1"], 3);
+        assert_eq!(set.watermarks.merged["n1"], 2);
     }
 }
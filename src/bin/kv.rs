@@ -0,0 +1,95 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use maelstrom_gossip_glommers::kv::Proxy;
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// A target for Maelstrom's own kv workloads, which passes every `read`, `write` and `cas` through
+// to one of Maelstrom's kv services, `MAELSTROM_KV_SERVICE`. Checks the plumbing we build on top
+// of those services (forwarding, reply translation, error codes) against a known correct store.
+static SERVICE: LazyLock<String> =
+    LazyLock::new(|| env_or("MAELSTROM_KV_SERVICE", "lin-kv".to_owned()));
+
+// How long a request may wait on the service before it's answered with a timeout.
+static TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_PROXY_TIMEOUT_MS", 1000)));
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    service: String,
+    #[serde(skip)]
+    proxy: Proxy,
+}
+
+impl Node {
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        let service = SERVICE.clone();
+        assert!(
+            ["lin-kv", "seq-kv", "lww-kv"].contains(&service.as_str()),
+            "Invalid MAELSTROM_KV_SERVICE={service}"
+        );
+        Self { inner, service, proxy: Proxy::new(*TIMEOUT) }
+    }
+
+    // A client's request, which the service answers in the same format.
+    fn handle_request(&mut self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(msg_type) = request["body"]["type"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        vec![self.proxy.forward(&self.inner, &request, &self.service, msg_type)]
+    }
+
+    // The service's reply, including errors, which the clients of Maelstrom's kv workloads expect
+    // the same codes for (20 for a missing key, 22 for a failed cas).
+    fn handle_reply(&mut self, reply: Map<String, Value>) -> Vec<Map<String, Value>> {
+        match self.proxy.translate(&self.inner, reply) {
+            Some(response) => vec![response],
+            None => {
+                eprintln!("Dropping reply to a request we didn't forward");
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Workload for Node {
+    const NAME: &'static str = "kv";
+
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        Some(match msg_type {
+            "read" | "write" | "cas" => self.handle_request(request),
+            "read_ok" | "write_ok" | "cas_ok" | "error" => self.handle_reply(request),
+            _ => return None,
+        })
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(*TIMEOUT / 10)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        self.proxy.expire(&self.inner)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Synthetic client traffic for `--selfdrive`. There's no service to answer, so this only
+    // exercises the forwarding.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.kv(i)).await;
+}
@@ -0,0 +1,236 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use maelstrom_gossip_glommers::kv::quorum::{self, Quorum, Registers, Timestamp, Versioned};
+use maelstrom_gossip_glommers::prelude::*;
+use maelstrom_gossip_glommers::testing::Generator;
+use maelstrom_gossip_glommers::workload;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+// A target for Maelstrom's kv workloads, serving `read`, `write` and `cas` from registers
+// replicated on every node, with read and write quorums rather than consensus. Every operation
+// first reads a quorum of `MAELSTROM_QUORUM_R` nodes for the newest version of the key, then a
+// write or successful cas stores its value, a version newer than that, on a quorum of
+// `MAELSTROM_QUORUM_W`. Both default to a majority, and R + W must be more than the cluster size.
+// A read sees every write which completed before it, but two concurrent cas of the same key can
+// both succeed, so this isn't linearizable, which needs `abd` or consensus.
+static READ_QUORUM: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_QUORUM_R", 0));
+static WRITE_QUORUM: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_QUORUM_W", 0));
+
+// How long to wait on a quorum before replying with a timeout, which leaves it indefinite whether
+// a write took effect.
+static TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_QUORUM_TIMEOUT_MS", 1000)));
+
+// What a client request is waiting on, carried through its rounds.
+enum Pending {
+    Read { request: Map<String, Value> },
+    Write { request: Map<String, Value>, key: String, value: Value },
+    Cas { request: Map<String, Value>, key: String, from: Value, to: Value },
+    // The store round of a write or cas, which is answered with `reply_type`.
+    Store { request: Map<String, Value>, reply_type: &'static str },
+}
+
+impl Pending {
+    fn request(&self) -> &Map<String, Value> {
+        match self {
+            Pending::Read { request, .. }
+            | Pending::Write { request, .. }
+            | Pending::Cas { request, .. }
+            | Pending::Store { request, .. } => request,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(skip)]
+    inner: maelstrom_gossip_glommers::node::Node,
+    registers: Registers,
+    #[serde(skip)]
+    quorum: Quorum<Pending>,
+    read_quorum: usize,
+    write_quorum: usize,
+}
+
+impl Node {
+    fn new(inner: maelstrom_gossip_glommers::node::Node) -> Self {
+        let n = inner.node_ids().len();
+        let or_majority = |size: usize| if size == 0 { quorum::majority(n) } else { size };
+        let (read_quorum, write_quorum) = (or_majority(*READ_QUORUM), or_majority(*WRITE_QUORUM));
+        assert!(
+            read_quorum <= n && write_quorum <= n && read_quorum + write_quorum > n,
+            "Invalid quorums R={read_quorum} W={write_quorum} for {n} nodes, need R + W > N"
+        );
+        Self {
+            inner,
+            registers: Registers::new(),
+            quorum: Quorum::new(*TIMEOUT),
+            read_quorum,
+            write_quorum,
+        }
+    }
+
+    // A client's read, write or cas, which starts with a read round.
+    fn handle_request(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let header = request_header(&request);
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let key = take_field::<Value>(&mut body, "key").to_string();
+        let pending = match header["body"]["type"].as_str() {
+            Some("read") => Pending::Read { request: header },
+            Some("write") => Pending::Write {
+                request: header,
+                key: key.clone(),
+                value: take_field(&mut body, "value"),
+            },
+            Some("cas") => Pending::Cas {
+                request: header,
+                key: key.clone(),
+                from: take_field(&mut body, "from"),
+                to: take_field(&mut body, "to"),
+            },
+            _ => panic!("Invalid request {:?}", header),
+        };
+        let mut round_body = Map::new();
+        round_body.insert("key".to_owned(), json!(key));
+        let (round, mut messages) =
+            self.quorum.start(&self.inner, "register_get", &round_body, self.read_quorum, pending);
+        let local = self.get(&key);
+        if let Some(done) = self.quorum.reply_local(round, local) {
+            messages.extend(self.on_done(done));
+        }
+        messages
+    }
+
+    fn get(&self, key: &str) -> Map<String, Value> {
+        let mut body = Map::new();
+        body.insert("versioned".to_owned(), json!(self.registers.get(key)));
+        body
+    }
+
+    // A peer's round is reading our replica.
+    fn handle_get(&self, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let Some(key) = request["body"]["key"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let mut response = self.inner.build_response(&request, "register_get_ok");
+        response["body"]["versioned"] = json!(self.registers.get(key));
+        vec![response]
+    }
+
+    // A peer's round is writing our replica.
+    fn handle_put(&mut self, mut request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        let response = self.inner.build_response(&request, "register_put_ok");
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let key: String = take_field(&mut body, "key");
+        let versioned: Versioned = take_field(&mut body, "versioned");
+        self.registers.put(&key, versioned);
+        vec![response]
+    }
+
+    fn handle_reply(&mut self, reply: Map<String, Value>) -> Vec<Map<String, Value>> {
+        match self.quorum.reply(reply) {
+            Some(done) => self.on_done(done),
+            None => Vec::new(),
+        }
+    }
+
+    // A round completed: answer the client, or follow a read round with a store round.
+    fn on_done(&mut self, done: quorum::Done<Pending>) -> Vec<Map<String, Value>> {
+        if let Pending::Store { request, reply_type } = done.state {
+            return vec![self.inner.build_response(&request, reply_type)];
+        }
+        let newest = quorum::newest(&done.replies);
+        match done.state {
+            Pending::Read { request, .. } if newest.value.is_null() => {
+                vec![self.inner.build_error(&request, ERROR_KEY_DOES_NOT_EXIST, "No such key")]
+            }
+            Pending::Read { request, .. } => {
+                let mut response = self.inner.build_response(&request, "read_ok");
+                response["body"]["value"] = newest.value;
+                vec![response]
+            }
+            Pending::Write { request, key, value } => {
+                let pending = Pending::Store { request, reply_type: "write_ok" };
+                self.store(key, newest.ts, value, pending)
+            }
+            Pending::Cas { request, .. } if newest.value.is_null() => {
+                vec![self.inner.build_error(&request, ERROR_KEY_DOES_NOT_EXIST, "No such key")]
+            }
+            Pending::Cas { request, from, .. } if newest.value != from => {
+                let text = format!("Expected {from}, but had {}", newest.value);
+                vec![self.inner.build_error(&request, ERROR_PRECONDITION_FAILED, &text)]
+            }
+            Pending::Cas { request, key, to, .. } => {
+                let pending = Pending::Store { request, reply_type: "cas_ok" };
+                self.store(key, newest.ts, to, pending)
+            }
+            Pending::Store { .. } => unreachable!(),
+        }
+    }
+
+    // Start a round storing `value` at a timestamp newer than `newest`.
+    fn store(
+        &mut self,
+        key: String,
+        newest: Timestamp,
+        value: Value,
+        pending: Pending,
+    ) -> Vec<Map<String, Value>> {
+        let ts = Timestamp { counter: newest.counter + 1, writer: self.inner.node_id().to_owned() };
+        let versioned = Versioned { ts, value };
+        let mut body = Map::new();
+        body.insert("key".to_owned(), json!(key));
+        body.insert("versioned".to_owned(), json!(versioned));
+        let (round, mut messages) =
+            self.quorum.start(&self.inner, "register_put", &body, self.write_quorum, pending);
+        self.registers.put(&key, versioned);
+        if let Some(done) = self.quorum.reply_local(round, Map::new()) {
+            messages.extend(self.on_done(done));
+        }
+        messages
+    }
+}
+
+impl Workload for Node {
+    const NAME: &'static str = "quorum-kv";
+
+    fn on_init(node: maelstrom_gossip_glommers::node::Node) -> Self {
+        Self::new(node)
+    }
+
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        Some(match msg_type {
+            "read" | "write" | "cas" => self.handle_request(request),
+            "register_get" => self.handle_get(request),
+            "register_put" => self.handle_put(request),
+            "register_get_ok" | "register_put_ok" | "error" => self.handle_reply(request),
+            _ => return None,
+        })
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(*TIMEOUT / 10)
+    }
+
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        let expired = self.quorum.expired();
+        let text = "Not enough nodes answered in time";
+        expired
+            .iter()
+            .map(|pending| self.inner.build_error(pending.request(), ERROR_TIMEOUT, text))
+            .collect()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Synthetic client traffic for `--selfdrive`.
+    let mut requests = Generator::new();
+    workload::run::<Node>(move |i| requests.kv(i)).await;
+}
@@ -0,0 +1,109 @@
+use std::io::BufRead;
+
+use serde_json::{Map, Value};
+
+// Render the `Event {...}` lines nodes write to stderr on exit (see `events`) as a single timeline,
+// ordered by time across every log given. Nodes only record events with `MAELSTROM_EVENT_LOG_SIZE`
+// set.
+//
+//   timeline [--msg-id ID] [--node NODE] LOG...
+//
+// `--msg-id` keeps only messages with that msg_id or in_reply_to, i.e. a request and its reply.
+// msg_ids are only unique per node, so combine it with `--node` to follow a single exchange.
+// `--node` keeps only messages sent or received by that node.
+struct Filter {
+    msg_id: Option<u64>,
+    node: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, event: &Map<String, Value>) -> bool {
+        let message = &event["message"];
+        if let Some(msg_id) = self.msg_id {
+            let body = &message["body"];
+            if body["msg_id"].as_u64() != Some(msg_id)
+                && body["in_reply_to"].as_u64() != Some(msg_id)
+            {
+                return false;
+            }
+        }
+        if let Some(node) = &self.node {
+            if message["src"] != *node && message["dest"] != *node {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn parse_args() -> (Filter, Vec<String>) {
+    let mut filter = Filter { msg_id: None, node: None };
+    let mut logs = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--msg-id" => {
+                let Some(Ok(msg_id)) = args.next().map(|id| id.parse()) else {
+                    panic!("--msg-id takes an integer");
+                };
+                filter.msg_id = Some(msg_id);
+            }
+            "--node" => {
+                let Some(node) = args.next() else {
+                    panic!("--node takes a node id");
+                };
+                filter.node = Some(node);
+            }
+            _ => logs.push(arg),
+        }
+    }
+    if logs.is_empty() {
+        panic!("Usage: timeline [--msg-id ID] [--node NODE] LOG...");
+    }
+    (filter, logs)
+}
+
+fn main() {
+    let (filter, logs) = parse_args();
+
+    let mut events = Vec::new();
+    for log in logs {
+        let Ok(file) = std::fs::File::open(&log) else {
+            panic!("Failed to open {log}");
+        };
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line.unwrap();
+            let Some(event) = line.strip_prefix("Event ") else {
+                continue;
+            };
+            let Ok(event) = serde_json::from_str::<Map<String, Value>>(event) else {
+                panic!("Invalid event in {log}: {line}");
+            };
+            if filter.matches(&event) {
+                events.push(event);
+            }
+        }
+    }
+    // Stable, so events with the same timestamp keep their order within a log.
+    events.sort_by_key(|event| event["unix_micros"].as_u64().unwrap());
+
+    let Some(start) = events.first().map(|event| event["unix_micros"].as_u64().unwrap()) else {
+        eprintln!("No matching events");
+        return;
+    };
+    for event in events {
+        let message = &event["message"];
+        let elapsed_ms = (event["unix_micros"].as_u64().unwrap() - start) as f64 / 1000.0;
+        let (node, arrow, peer) = match event["direction"].as_str() {
+            Some("send") => (&message["src"], "->", &message["dest"]),
+            Some("recv") => (&message["dest"], "<-", &message["src"]),
+            _ => panic!("Invalid direction {:?}", event),
+        };
+        println!(
+            "{elapsed_ms:>10.3}ms {} {arrow} {} {}",
+            node.as_str().unwrap_or_default(),
+            peer.as_str().unwrap_or_default(),
+            serde_json::to_string(&message["body"]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::LazyLock;
+
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+use crate::metrics;
+use crate::node::Node;
+use crate::quorum::{Registers, Timestamp, Versioned};
+use crate::rpc::{take_field, ERROR_KEY_DOES_NOT_EXIST};
+use crate::runtime::env_or;
+
+// Several CRDTs on one node, replicated together: each round sends every peer a single
+// `bus_replicate` carrying each registered component's changes since the last round, rather than a
+// replicate per component. Deltas lost to a partition are made up for by sending every component's
+// full state every `MAELSTROM_BUS_FULL_EVERY` rounds. Merges are joins, so a component can't tell,
+// and needn't, whether it was sent a delta or its peer's whole state.
+static FULL_EVERY: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_BUS_FULL_EVERY", 10));
+
+pub trait Component: Send {
+    // Answer a client's `msg_type` request, or None if it isn't one of ours.
+    fn apply(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Map<String, Value>>;
+
+    // Our changes since the last call, or None if there are none.
+    fn delta(&mut self) -> Option<Value>;
+
+    fn state(&self) -> Value;
+
+    // Join a peer's delta or state into ours.
+    fn merge(&mut self, state: Value);
+}
+
+#[derive(Default)]
+pub struct Bus {
+    // In the order registered, which is the order client requests are offered to them in.
+    components: Vec<(String, Box<dyn Component>)>,
+    rounds: u64,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, name: &str, component: impl Component + 'static) {
+        assert!(self.components.iter().all(|(n, _)| n != name), "{name} registered twice");
+        self.components.push((name.to_owned(), Box::new(component)));
+    }
+
+    // Answer a client request with the component named by its `component` field, or else the
+    // first which handles its type.
+    pub fn apply(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        let named = request["body"]["component"].as_str();
+        self.components
+            .iter_mut()
+            .filter(|(name, _)| named.is_none_or(|named| named == name))
+            .find_map(|(_, component)| component.apply(node, msg_type, request))
+    }
+
+    // A round of replication: a `bus_replicate` for every peer, if anything changed or it's time to
+    // send full state.
+    pub fn round(&mut self, node: &Node) -> Vec<Map<String, Value>> {
+        self.rounds += 1;
+        let full = self.rounds.is_multiple_of((*FULL_EVERY).max(1));
+        let mut components = Map::new();
+        for (name, component) in &mut self.components {
+            let delta = component.delta();
+            let changes = if full { Some(component.state()) } else { delta };
+            if let Some(changes) = changes {
+                components.insert(name.clone(), changes);
+            }
+        }
+        if components.is_empty() {
+            return Vec::new();
+        }
+        let peers = node.node_ids().iter().filter(|&n| n != node.node_id());
+        let messages: Vec<_> = peers
+            .map(|peer| {
+                let mut msg = node.build_message(node.node_id(), peer, "bus_replicate");
+                msg["body"]["components"] = Value::Object(components.clone());
+                msg
+            })
+            .collect();
+        metrics::add("bus.replicates", messages.len() as u64);
+        metrics::add("bus.components_sent", (components.len() * messages.len()) as u64);
+        messages
+    }
+
+    pub fn handle_replicate(&mut self, mut request: Map<String, Value>) {
+        let mut body: Map<String, Value> = take_field(&mut request, "body");
+        let components: Map<String, Value> = take_field(&mut body, "components");
+        for (name, changes) in components {
+            let Some((_, component)) = self.components.iter_mut().find(|(n, _)| *n == name) else {
+                // A peer built with components we weren't.
+                eprintln!("Ignoring changes to unknown component {name}");
+                continue;
+            };
+            component.merge(changes);
+        }
+    }
+}
+
+// Each component's state, for snapshots.
+impl Serialize for Bus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.components.len()))?;
+        for (name, component) in &self.components {
+            map.serialize_entry(name, &component.state())?;
+        }
+        map.end()
+    }
+}
+
+// A PN-counter: `add` with a `delta` and `read`. Each node's increments and decrements are counted
+// separately, so both only grow and merge by max.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+struct Totals {
+    inc: u64,
+    dec: u64,
+}
+
+pub struct Counter {
+    node_id: String,
+    totals: HashMap<String, Totals>,
+    changed: bool,
+}
+
+impl Counter {
+    pub fn new(node_id: &str) -> Self {
+        Self { node_id: node_id.to_owned(), totals: HashMap::new(), changed: false }
+    }
+}
+
+impl Component for Counter {
+    fn apply(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        match msg_type {
+            "add" => {
+                let delta = request["body"]["delta"].as_i64()?;
+                let totals = self.totals.entry(self.node_id.clone()).or_default();
+                if delta >= 0 {
+                    totals.inc += delta as u64;
+                } else {
+                    totals.dec += delta.unsigned_abs();
+                }
+                self.changed = true;
+                Some(node.build_response(request, "add_ok"))
+            }
+            "read" => {
+                let value: i64 = self.totals.values().map(|t| t.inc as i64 - t.dec as i64).sum();
+                let mut response = node.build_response(request, "read_ok");
+                response["body"]["value"] = json!(value);
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    // Only our own entry changes locally.
+    fn delta(&mut self) -> Option<Value> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some(json!({ &self.node_id: self.totals[&self.node_id] }))
+    }
+
+    fn state(&self) -> Value {
+        json!(self.totals)
+    }
+
+    fn merge(&mut self, state: Value) {
+        let Ok(totals) = serde_json::from_value::<HashMap<String, Totals>>(state) else {
+            panic!("Invalid counter state");
+        };
+        for (node, theirs) in totals {
+            let ours = self.totals.entry(node).or_default();
+            ours.inc = ours.inc.max(theirs.inc);
+            ours.dec = ours.dec.max(theirs.dec);
+        }
+    }
+}
+
+// A grow-only set: `add` with an `element` and `read`.
+#[derive(Default)]
+pub struct Set {
+    elements: BTreeSet<u64>,
+    added: Vec<u64>,
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Component for Set {
+    fn apply(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        match msg_type {
+            "add" => {
+                let element = request["body"]["element"].as_u64()?;
+                if self.elements.insert(element) {
+                    self.added.push(element);
+                }
+                Some(node.build_response(request, "add_ok"))
+            }
+            "read" => {
+                let mut response = node.build_response(request, "read_ok");
+                response["body"]["value"] = json!(self.elements);
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    fn delta(&mut self) -> Option<Value> {
+        if self.added.is_empty() {
+            return None;
+        }
+        Some(json!(std::mem::take(&mut self.added)))
+    }
+
+    fn state(&self) -> Value {
+        json!(self.elements)
+    }
+
+    fn merge(&mut self, state: Value) {
+        let Ok(elements) = serde_json::from_value::<Vec<u64>>(state) else {
+            panic!("Invalid set state");
+        };
+        self.elements.extend(elements);
+    }
+}
+
+// A last-writer-wins map: `write` and `read` with a `key`. Writes are ordered by a Lamport clock,
+// then by writer, see `Timestamp`.
+#[derive(Default)]
+pub struct LwwMap {
+    registers: Registers,
+    clock: u64,
+    written: HashSet<String>,
+}
+
+impl LwwMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Component for LwwMap {
+    fn apply(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        request: &Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        let key = request["body"].get("key")?.to_string();
+        match msg_type {
+            "write" => {
+                self.clock += 1;
+                let ts = Timestamp { counter: self.clock, writer: node.node_id().to_owned() };
+                let value = request["body"]["value"].clone();
+                self.registers.put(&key, Versioned { ts, value });
+                self.written.insert(key);
+                Some(node.build_response(request, "write_ok"))
+            }
+            "read" => {
+                let versioned = self.registers.get(&key);
+                if versioned.value.is_null() {
+                    return Some(node.build_error(
+                        request,
+                        ERROR_KEY_DOES_NOT_EXIST,
+                        "No such key",
+                    ));
+                }
+                let mut response = node.build_response(request, "read_ok");
+                response["body"]["value"] = versioned.value;
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    fn delta(&mut self) -> Option<Value> {
+        if self.written.is_empty() {
+            return None;
+        }
+        let written = std::mem::take(&mut self.written);
+        let delta: HashMap<_, _> =
+            written.into_iter().map(|key| (key.clone(), self.registers.get(&key))).collect();
+        Some(json!(delta))
+    }
+
+    fn state(&self) -> Value {
+        json!(self.registers.entries().collect::<HashMap<_, _>>())
+    }
+
+    fn merge(&mut self, state: Value) {
+        let Ok(registers) = serde_json::from_value::<HashMap<String, Versioned>>(state) else {
+            panic!("Invalid map state");
+        };
+        for (key, versioned) in registers {
+            // Keep our clock ahead of every write we've seen, so our next write wins over them.
+            self.clock = self.clock.max(versioned.ts.counter);
+            self.registers.put(&key, versioned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Outbox;
+
+    fn node(id: &str) -> Node {
+        Node::new(&json!(id), &json!(["n0", "n1", "n2"]), Outbox::spawn_writer())
+    }
+
+    fn bus(node_id: &str) -> Bus {
+        let mut bus = Bus::new();
+        bus.register("counter", Counter::new(node_id));
+        bus.register("set", Set::new());
+        bus.register("map", LwwMap::new());
+        bus
+    }
+
+    fn request(body: Value) -> Map<String, Value> {
+        let Value::Object(mut request) = json!({"src": "c1", "dest": "n0", "body": body}) else {
+            panic!("Invalid request");
+        };
+        request["body"]["msg_id"] = json!(1);
+        request
+    }
+
+    fn apply(bus: &mut Bus, node: &Node, body: Value) -> Map<String, Value> {
+        let request = request(body);
+        let Some(msg_type) = request["body"]["type"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let Some(response) = bus.apply(node, msg_type, &request) else {
+            panic!("Unhandled request {:?}", request);
+        };
+        response
+    }
+
+    fn read(bus: &mut Bus, node: &Node, component: &str) -> Value {
+        let response = apply(bus, node, json!({"type": "read", "component": component, "key": 1}));
+        response["body"]["value"].clone()
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_named_or_first_component() {
+        let n0 = node("n0");
+        let mut bus = bus("n0");
+        // Both the counter and the set take an `add`.
+        apply(&mut bus, &n0, json!({"type": "add", "delta": 5}));
+        apply(&mut bus, &n0, json!({"type": "add", "component": "set", "element": 3}));
+        assert_eq!(read(&mut bus, &n0, "counter"), 5);
+        assert_eq!(read(&mut bus, &n0, "set"), json!([3]));
+        let request = request(json!({"type": "cas", "key": 1}));
+        assert_eq!(bus.apply(&n0, "cas", &request), None);
+    }
+
+    #[tokio::test]
+    async fn rounds_carry_only_changed_components() {
+        let n0 = node("n0");
+        let mut bus = bus("n0");
+        assert!(bus.round(&n0).is_empty());
+
+        apply(&mut bus, &n0, json!({"type": "add", "component": "set", "element": 3}));
+        let messages = bus.round(&n0);
+        let dests: Vec<_> = messages.iter().map(|m| m["dest"].clone()).collect();
+        assert_eq!(dests.len(), 2);
+        assert!(dests.contains(&json!("n1")) && dests.contains(&json!("n2")));
+        assert_eq!(messages[0]["body"]["type"], "bus_replicate");
+        assert_eq!(messages[0]["body"]["components"], json!({"set": [3]}));
+
+        // Every `MAELSTROM_BUS_FULL_EVERY` rounds, every component's full state goes out.
+        for _ in 3..*FULL_EVERY {
+            assert!(bus.round(&n0).is_empty());
+        }
+        let messages = bus.round(&n0);
+        let components = messages[0]["body"]["components"].as_object().unwrap();
+        assert_eq!(components.keys().collect::<Vec<_>>(), ["counter", "map", "set"]);
+        assert_eq!(components["set"], json!([3]));
+    }
+
+    #[tokio::test]
+    async fn replicas_converge() {
+        let (n0, n1) = (node("n0"), node("n1"));
+        let (mut bus0, mut bus1) = (bus("n0"), bus("n1"));
+        apply(&mut bus0, &n0, json!({"type": "add", "delta": 5}));
+        apply(&mut bus1, &n1, json!({"type": "add", "delta": -2}));
+        apply(&mut bus0, &n0, json!({"type": "add", "component": "set", "element": 1}));
+        apply(&mut bus1, &n1, json!({"type": "add", "component": "set", "element": 2}));
+        apply(&mut bus0, &n0, json!({"type": "write", "key": 1, "value": 10}));
+
+        for message in bus0.round(&n0).into_iter().filter(|m| m["dest"] == "n1") {
+            bus1.handle_replicate(message);
+        }
+        // n1 has seen n0's write, so its own write to the key is ordered after it.
+        apply(&mut bus1, &n1, json!({"type": "write", "key": 1, "value": 11}));
+        for message in bus1.round(&n1).into_iter().filter(|m| m["dest"] == "n0") {
+            bus0.handle_replicate(message);
+        }
+
+        for (bus, node) in [(&mut bus0, &n0), (&mut bus1, &n1)] {
+            assert_eq!(read(bus, node, "counter"), 3);
+            assert_eq!(read(bus, node, "set"), json!([1, 2]));
+            assert_eq!(read(bus, node, "map"), 11);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_components_are_ignored() {
+        let n0 = node("n0");
+        let mut bus = bus("n0");
+        let mut replicate = request(json!({"components": {"queue": [1], "set": [4]}}));
+        replicate["src"] = json!("n1");
+        bus.handle_replicate(replicate);
+        assert_eq!(read(&mut bus, &n0, "set"), json!([4]));
+        assert_eq!(serde_json::to_value(&bus).unwrap()["set"], json!([4]));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::node::is_node;
+use crate::runtime::env_or;
+use crate::{clock, metrics};
+
+// Self-inflicted network failures, to harden retries and dedup without a Maelstrom nemesis. Each
+// message we send to another node is dropped with probability `MAELSTROM_CHAOS_DROP`, else sent
+// twice with probability `MAELSTROM_CHAOS_DUPLICATE`, else held for up to `MAELSTROM_CHAOS_DELAY_MS`
+// with probability `MAELSTROM_CHAOS_DELAY`, reordering it with later messages. All default to 0.
+// Only compiled in with the `chaos` feature, so builds used for scoring can't inject failures
+// whatever their environment.
+pub const COMPILED: bool = cfg!(feature = "chaos");
+
+struct Config {
+    drop: f64,
+    duplicate: f64,
+    delay: f64,
+    max_delay: Duration,
+}
+
+impl Config {
+    fn enabled(&self) -> bool {
+        self.drop > 0.0 || self.duplicate > 0.0 || self.delay > 0.0
+    }
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(|| Config {
+    drop: env_or("MAELSTROM_CHAOS_DROP", 0.0),
+    duplicate: env_or("MAELSTROM_CHAOS_DUPLICATE", 0.0),
+    delay: env_or("MAELSTROM_CHAOS_DELAY", 0.0),
+    max_delay: Duration::from_millis(env_or("MAELSTROM_CHAOS_DELAY_MS", 100)),
+});
+
+#[derive(Default)]
+struct Delayed {
+    // {(when it's due, the order it was delayed in): message}.
+    messages: BTreeMap<(Instant, u64), Map<String, Value>>,
+    next: u64,
+}
+
+static DELAYED: LazyLock<Mutex<Delayed>> = LazyLock::new(Default::default);
+
+// Uniform in [0, 1). RandomState is seeded randomly per instance, which is plenty for injecting
+// failures.
+fn random() -> f64 {
+    let random = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Called by the writer on the messages it's about to write. Returns those to write now: `messages`
+// less any dropped or delayed, plus duplicates and delayed messages which are now due.
+pub fn inject(messages: Vec<Map<String, Value>>) -> Vec<Map<String, Value>> {
+    if !COMPILED || !CONFIG.enabled() {
+        return messages;
+    }
+    let mut sent = Vec::with_capacity(messages.len());
+    for message in messages {
+        if !message["dest"].as_str().is_some_and(is_node) {
+            sent.push(message);
+            continue;
+        }
+        if random() < CONFIG.drop {
+            metrics::incr("chaos.dropped");
+        } else if random() < CONFIG.duplicate {
+            metrics::incr("chaos.duplicated");
+            sent.push(message.clone());
+            sent.push(message);
+        } else if random() < CONFIG.delay {
+            metrics::incr("chaos.delayed");
+            let due = clock::now() + CONFIG.max_delay.mul_f64(random());
+            let mut delayed = DELAYED.lock();
+            let order = delayed.next;
+            delayed.next += 1;
+            delayed.messages.insert((due, order), message);
+        } else {
+            sent.push(message);
+        }
+    }
+    sent.extend(release());
+    sent
+}
+
+// Delayed messages which are now due.
+fn release() -> Vec<Map<String, Value>> {
+    let mut delayed = DELAYED.lock();
+    let later = delayed.messages.split_off(&(clock::now(), u64::MAX));
+    std::mem::replace(&mut delayed.messages, later).into_values().collect()
+}
+
+// Every delayed message, due or not, e.g. to flush before exiting.
+pub fn drain() -> Vec<Map<String, Value>> {
+    std::mem::take(&mut DELAYED.lock().messages).into_values().collect()
+}
+
+// How long until the next delayed message is due, if any are waiting.
+pub fn until_release() -> Option<Duration> {
+    let delayed = DELAYED.lock();
+    let &(due, _) = delayed.messages.keys().next()?;
+    Some(due.saturating_duration_since(clock::now()))
+}
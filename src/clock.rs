@@ -0,0 +1,171 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::watch;
+
+// All time the library and binaries observe goes through a Clock, so that tests and simulations can
+// swap real time for `SimulatedClock` and advance it at will. The clock is process wide: `install`
+// one before starting the node, otherwise `SystemClock` is used.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // Wall clock time, for timestamps which are compared across processes.
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// Time which only moves when `advance` is called. Sleepers wake once it has been advanced past their
+// deadline.
+pub struct SimulatedClock {
+    start: Instant,
+    start_system_time: SystemTime,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            start_system_time: SystemTime::now(),
+            elapsed: watch::Sender::new(Duration::ZERO),
+        }
+    }
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + *self.elapsed.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut elapsed = self.elapsed.subscribe();
+        let deadline = *elapsed.borrow() + duration;
+        Box::pin(async move {
+            // Only fails once the clock is dropped, in which case time will never reach `deadline`.
+            if elapsed.wait_for(|elapsed| *elapsed >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+static CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+
+// Use `clock` for the rest of the process. Must be called before anything has read the time.
+pub fn install(clock: Arc<dyn Clock>) {
+    let Ok(()) = CLOCK.set(clock) else {
+        panic!("Clock already installed or in use");
+    };
+}
+
+fn clock() -> &'static dyn Clock {
+    CLOCK.get_or_init(|| Arc::new(SystemClock)).as_ref()
+}
+
+pub fn now() -> Instant {
+    clock().now()
+}
+
+pub fn system_time() -> SystemTime {
+    clock().system_time()
+}
+
+pub fn sleep(duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    clock().sleep(duration)
+}
+
+// Periodic work waits `jittered(period)` rather than `period`, so nodes started together don't keep
+// bursting in lockstep, which shows up as spikes in Maelstrom's latency percentiles.
+// `MAELSTROM_TIMER_JITTER` is the fraction by which each wait is randomly shortened or lengthened,
+// 0 to disable.
+static JITTER: LazyLock<f64> =
+    LazyLock::new(|| crate::runtime::env_or("MAELSTROM_TIMER_JITTER", 0.1));
+
+pub fn jittered(period: Duration) -> Duration {
+    if *JITTER == 0.0 {
+        return period;
+    }
+    // RandomState is seeded randomly per instance, which is plenty for desynchronizing nodes.
+    let random = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+    // Uniform in [-1, 1].
+    let offset = random as f64 / u64::MAX as f64 * 2.0 - 1.0;
+    period.mul_f64((1.0 + *JITTER * offset).max(0.0))
+}
+
+// Ticks every `period`, the first tick being immediate. A tick which is late doesn't delay the
+// ones after it, so after a stall ticks fire back to back until caught up (tokio's `Burst`).
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+}
+
+pub fn interval(period: Duration) -> Interval {
+    Interval { next: now(), period }
+}
+
+impl Interval {
+    pub async fn tick(&mut self) {
+        let wait = self.next.saturating_duration_since(now());
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+        self.next += self.period;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_time_only_moves_when_advanced() {
+        let clock = SimulatedClock::new();
+        let (start, start_system_time) = (clock.now(), clock.system_time());
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        assert_eq!(clock.system_time().duration_since(start_system_time).unwrap().as_secs(), 5);
+    }
+
+    #[tokio::test]
+    async fn simulated_sleep_wakes_once_advanced_past_its_deadline() {
+        let clock = SimulatedClock::new();
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(9));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        clock.advance(Duration::from_secs(1));
+        sleep.await.unwrap();
+    }
+}
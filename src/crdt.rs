@@ -0,0 +1,11 @@
+// Building blocks for replicated data types gossiped between nodes: the CRDTs themselves, and what
+// gossiping them needs, i.e. tracking which peers are reachable, versioning payloads, catching up
+// on start and persisting state across restarts.
+pub use crate::bus::{Bus, Component, Counter, LwwMap, Set};
+pub use crate::health::Health;
+pub use crate::version::Versions;
+pub use crate::warmup::Warmup;
+
+pub mod persist {
+    pub use crate::persist::{enabled, load, store};
+}
@@ -0,0 +1,131 @@
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use crate::node::is_node;
+use crate::runtime::env_or;
+use crate::{clock, metrics};
+
+// Deadlines for chains of internal RPCs made on a client's behalf, e.g. a follower forwarding a txn
+// to the primary. With `MAELSTROM_CLIENT_TIMEOUT_MS`, how long clients wait for a reply, a request
+// forwarded to another node carries a `deadline`: when its client gives up, in unix milliseconds.
+// Nodes share the host's wall clock under Maelstrom, so deadlines are comparable across them. A
+// request forwarded again keeps its deadline, and a node receiving a request with less than
+// `MAELSTROM_DEADLINE_MARGIN_MS` left refuses it as temporarily unavailable rather than doing work
+// nobody is waiting for. 0, the default, disables it.
+static CLIENT_TIMEOUT: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_CLIENT_TIMEOUT_MS", 0));
+static MARGIN: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_DEADLINE_MARGIN_MS", 10));
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// Give `forwarded`, sent on behalf of `request`, the deadline `request` carried, or else one
+// `CLIENT_TIMEOUT` from now. Only requests to other nodes carry one, as Maelstrom's services don't
+// know about deadlines.
+pub fn propagate(request: &Map<String, Value>, forwarded: &mut Map<String, Value>) {
+    propagate_at(clock::system_time(), *CLIENT_TIMEOUT, request, forwarded)
+}
+
+fn propagate_at(
+    now: SystemTime,
+    client_timeout: u64,
+    request: &Map<String, Value>,
+    forwarded: &mut Map<String, Value>,
+) {
+    if client_timeout == 0 || !forwarded["dest"].as_str().is_some_and(is_node) {
+        return;
+    }
+    let deadline = request["body"]["deadline"].as_u64();
+    let deadline = deadline.unwrap_or_else(|| millis(now) + client_timeout);
+    forwarded["body"]["deadline"] = serde_json::json!(deadline);
+}
+
+// Whether `request`'s deadline is too close, or past, for it to be worth handling.
+pub fn expired(request: &Map<String, Value>) -> bool {
+    expired_at(clock::system_time(), *MARGIN, request)
+}
+
+fn expired_at(now: SystemTime, margin: u64, request: &Map<String, Value>) -> bool {
+    let Some(deadline) = request["body"]["deadline"].as_u64() else {
+        return false;
+    };
+    if millis(now) + margin < deadline {
+        return false;
+    }
+    metrics::incr("deadline.refused");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::clock::{Clock, SimulatedClock};
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    fn forwarded(dest: &str) -> Map<String, Value> {
+        message(json!({"src": "n0", "dest": dest, "body": {"type": "txn"}}))
+    }
+
+    #[test]
+    fn propagate_starts_a_deadline() {
+        let clock = SimulatedClock::new();
+        let request = message(json!({"src": "c1", "dest": "n0", "body": {"type": "txn"}}));
+        let mut to_node = forwarded("n1");
+        propagate_at(clock.system_time(), 1000, &request, &mut to_node);
+        assert_eq!(to_node["body"]["deadline"], millis(clock.system_time()) + 1000);
+    }
+
+    #[test]
+    fn propagate_keeps_the_deadline() {
+        let clock = SimulatedClock::new();
+        let request = message(json!({"src": "n2", "dest": "n0", "body": {"deadline": 5}}));
+        let mut to_node = forwarded("n1");
+        propagate_at(clock.system_time(), 1000, &request, &mut to_node);
+        assert_eq!(to_node["body"]["deadline"], 5);
+    }
+
+    #[test]
+    fn propagate_skips_services_and_disabled() {
+        let clock = SimulatedClock::new();
+        let request = message(json!({"src": "c1", "dest": "n0", "body": {"deadline": 5}}));
+        let mut to_service = forwarded("lin-kv");
+        propagate_at(clock.system_time(), 1000, &request, &mut to_service);
+        assert!(to_service["body"].get("deadline").is_none());
+        let mut disabled = forwarded("n1");
+        propagate_at(clock.system_time(), 0, &request, &mut disabled);
+        assert!(disabled["body"].get("deadline").is_none());
+    }
+
+    #[test]
+    fn expires_within_the_margin() {
+        let clock = SimulatedClock::new();
+        let deadline = millis(clock.system_time()) + 100;
+        let request = message(json!({"body": {"deadline": deadline}}));
+        assert!(!expired_at(clock.system_time(), 10, &request));
+        clock.advance(Duration::from_millis(89));
+        assert!(!expired_at(clock.system_time(), 10, &request));
+        clock.advance(Duration::from_millis(1));
+        assert!(expired_at(clock.system_time(), 10, &request));
+        clock.advance(Duration::from_secs(1));
+        assert!(expired_at(clock.system_time(), 10, &request));
+    }
+
+    #[test]
+    fn without_a_deadline_never_expires() {
+        let clock = SimulatedClock::new();
+        clock.advance(Duration::from_secs(1_000_000));
+        assert!(!expired_at(clock.system_time(), 10, &message(json!({"body": {}}))));
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::LazyLock;
+use std::time::UNIX_EPOCH;
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+// The last `MAELSTROM_EVENT_LOG_SIZE` messages received and sent, with timestamps, kept in memory
+// and written to stderr as `Event {...}` lines by `dump`. The `timeline` binary merges these from
+// each node's log into one ordered timeline, for tracking down lost acks and reordering. 0, the
+// default, disables recording, which costs a copy of every message.
+static CAPACITY: LazyLock<usize> =
+    LazyLock::new(|| crate::runtime::env_or("MAELSTROM_EVENT_LOG_SIZE", 0));
+// Messages longer than `MAELSTROM_EVENT_PREVIEW_BYTES` once serialized are recorded as a
+// `preview`, so a log of big replicates or batches doesn't hold the whole state many times over.
+static PREVIEW_BYTES: LazyLock<usize> =
+    LazyLock::new(|| crate::runtime::env_or("MAELSTROM_EVENT_PREVIEW_BYTES", 1024));
+static EVENTS: LazyLock<Mutex<VecDeque<Event>>> = LazyLock::new(|| Mutex::new(VecDeque::new()));
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Recv,
+    Send,
+}
+
+struct Event {
+    unix_micros: u128,
+    direction: Direction,
+    // Serialized JSON, since that's what both the reader and writer have on hand.
+    message: String,
+}
+
+// Record `message`, which serializes to `serialized`.
+pub fn record(direction: Direction, message: &Map<String, Value>, serialized: &str) {
+    if *CAPACITY == 0 {
+        return;
+    }
+    let serialized = serialized.trim_end();
+    let message = if serialized.len() > *PREVIEW_BYTES {
+        preview(message, serialized.len())
+    } else {
+        serialized.to_owned()
+    };
+    let unix_micros = crate::clock::system_time().duration_since(UNIX_EPOCH).unwrap().as_micros();
+    let mut events = EVENTS.lock();
+    if events.len() == *CAPACITY {
+        events.pop_front();
+    }
+    events.push_back(Event { unix_micros, direction, message });
+}
+
+// For received messages, which are recorded after `auth::verify` has stripped the cluster secret
+// rather than as the raw input line, so have to be serialized again.
+pub fn record_message(direction: Direction, message: &Map<String, Value>) {
+    if *CAPACITY > 0 {
+        record(direction, message, &serde_json::to_string(message).unwrap());
+    }
+}
+
+// What's kept of a message of `bytes` bytes too big to record whole: who it's between and what
+// the `timeline` needs to match it with its reply.
+fn preview(message: &Map<String, Value>, bytes: usize) -> String {
+    let body = message.get("body").unwrap_or(&Value::Null);
+    let preview = serde_json::json!({
+        "src": message.get("src"),
+        "dest": message.get("dest"),
+        "body": {
+            "type": body["type"],
+            "msg_id": body["msg_id"],
+            "in_reply_to": body["in_reply_to"],
+            "truncated_bytes": bytes,
+        },
+    });
+    preview.to_string()
+}
+
+// Write out and clear the buffered events.
+pub fn dump() {
+    let events = std::mem::take(&mut *EVENTS.lock());
+    let mut stderr = std::io::stderr().lock();
+    for event in events {
+        let direction = match event.direction {
+            Direction::Recv => "recv",
+            Direction::Send => "send",
+        };
+        // `message` is already JSON, so splice it in rather than parsing it back out.
+        let _ = writeln!(
+            stderr,
+            "Event {{\"unix_micros\":{},\"direction\":\"{direction}\",\"message\":{}}}",
+            event.unix_micros, event.message
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_keep_what_identifies_the_message() {
+        let serde_json::Value::Object(message) = serde_json::json!({
+            "src": "n1",
+            "dest": "n2",
+            "body": {"type": "replicate", "msg_id": 7, "value": vec![0; 1000]},
+        }) else {
+            panic!("Not an object");
+        };
+        let preview: Value = serde_json::from_str(&preview(&message, 2011)).unwrap();
+        let expected = serde_json::json!({
+            "src": "n1",
+            "dest": "n2",
+            "body": {"type": "replicate", "msg_id": 7, "in_reply_to": null, "truncated_bytes": 2011},
+        });
+        assert_eq!(preview, expected);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::node::is_node;
+use crate::runtime::env_or;
+use crate::{clock, metrics};
+
+// Credit based flow control between nodes. With `MAELSTROM_CREDITS`, every message to another node
+// advertises a `credit`: how many messages we're willing to receive from it per
+// `MAELSTROM_CREDIT_WINDOW_MS`. The writer holds each peer to the last credit it advertised and
+// queues the excess for a later window, so a fast node can't bury a slow one in gossip it would only
+// fall further behind on. Peers which haven't advertised a credit, e.g. because they run without
+// flow control, aren't limited. 0, the default, disables it.
+static CREDITS: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_CREDITS", 0));
+static WINDOW: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_CREDIT_WINDOW_MS", 100)));
+
+// Windows are numbered from here.
+static EPOCH: LazyLock<Instant> = LazyLock::new(clock::now);
+
+#[derive(Default)]
+struct Peer {
+    credit: Option<u64>,
+    // Messages sent to the peer in window number `window`.
+    window: u64,
+    sent: u64,
+    // Messages waiting for credit, in the order they were committed.
+    queued: VecDeque<Map<String, Value>>,
+}
+
+impl Peer {
+    fn has_credit(&mut self, window: u64) -> bool {
+        if self.window != window {
+            self.window = window;
+            self.sent = 0;
+        }
+        self.credit.is_none_or(|credit| self.sent < credit)
+    }
+}
+
+static FLOW: LazyLock<Mutex<Flow>> = LazyLock::new(Default::default);
+
+fn current_window() -> u64 {
+    ((clock::now() - *EPOCH).as_nanos() / WINDOW.as_nanos()) as u64
+}
+
+// Record the credit `request`'s sender advertised, and strip it so it never reaches handlers.
+pub fn received(request: &mut Map<String, Value>) {
+    let credit = match &mut request["body"] {
+        Value::Object(body) => body.remove("credit"),
+        _ => None,
+    };
+    let (Some(src), Some(credit)) = (request["src"].as_str(), credit) else {
+        return;
+    };
+    if *CREDITS > 0 && is_node(src) {
+        FLOW.lock().received(src, credit.as_u64());
+    }
+}
+
+// Called by the writer on every message committed. Returns the message if it can be written now,
+// otherwise queues it for `release`.
+pub fn admit(message: Map<String, Value>) -> Option<Map<String, Value>> {
+    if *CREDITS == 0 {
+        return Some(message);
+    }
+    FLOW.lock().admit(message, *CREDITS, current_window())
+}
+
+// Queued messages which the current window has credit for, to be written now.
+pub fn release() -> Vec<Map<String, Value>> {
+    FLOW.lock().release(current_window())
+}
+
+// Every queued message regardless of credit, e.g. to flush before exiting.
+pub fn drain() -> Vec<Map<String, Value>> {
+    FLOW.lock().drain()
+}
+
+// How long until the next window, if any messages are waiting for one.
+pub fn until_release() -> Option<Duration> {
+    if !FLOW.lock().queued() {
+        return None;
+    }
+    let next = *EPOCH + *WINDOW * (current_window() + 1) as u32;
+    Some(next.saturating_duration_since(clock::now()))
+}
+
+// The writer's view of every peer, with windows and our own credit passed in.
+#[derive(Default)]
+struct Flow {
+    peers: HashMap<String, Peer>,
+}
+
+impl Flow {
+    fn received(&mut self, src: &str, credit: Option<u64>) {
+        self.peers.entry(src.to_owned()).or_default().credit = credit;
+    }
+
+    fn admit(
+        &mut self,
+        mut message: Map<String, Value>,
+        credits: u64,
+        window: u64,
+    ) -> Option<Map<String, Value>> {
+        let Some(dest) = message["dest"].as_str().filter(|&dest| is_node(dest)) else {
+            return Some(message);
+        };
+        let dest = dest.to_owned();
+        message["body"]["credit"] = serde_json::json!(credits);
+        let peer = self.peers.entry(dest).or_default();
+        if peer.queued.is_empty() && peer.has_credit(window) {
+            peer.sent += 1;
+            return Some(message);
+        }
+        peer.queued.push_back(message);
+        metrics::incr("flow.queued");
+        metrics::max("flow.max_queued", peer.queued.len() as u64);
+        None
+    }
+
+    fn release(&mut self, window: u64) -> Vec<Map<String, Value>> {
+        let mut released = Vec::new();
+        for peer in self.peers.values_mut() {
+            while !peer.queued.is_empty() && peer.has_credit(window) {
+                peer.sent += 1;
+                released.extend(peer.queued.pop_front());
+            }
+        }
+        released
+    }
+
+    fn drain(&mut self) -> Vec<Map<String, Value>> {
+        self.peers.values_mut().flat_map(|peer| std::mem::take(&mut peer.queued)).collect()
+    }
+
+    fn queued(&self) -> bool {
+        self.peers.values().any(|peer| !peer.queued.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(dest: &str, n: u64) -> Map<String, Value> {
+        let message = serde_json::json!({"src": "n0", "dest": dest, "body": {"n": n}});
+        let Value::Object(message) = message else {
+            panic!("Invalid message {:?}", message);
+        };
+        message
+    }
+
+    fn ns(messages: &[Map<String, Value>]) -> Vec<u64> {
+        messages.iter().map(|message| message["body"]["n"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn peers_are_held_to_their_credit() {
+        let mut flow = Flow::default();
+        flow.received("n1", Some(2));
+        let admitted: Vec<_> =
+            (1..=5).filter_map(|n| flow.admit(message("n1", n), 10, 0)).collect();
+        assert_eq!(ns(&admitted), [1, 2]);
+        // Our own credit is advertised on everything we send.
+        assert_eq!(admitted[0]["body"]["credit"], 10);
+        assert!(flow.queued());
+
+        // Nothing more until the next window, which releases what it has credit for in order.
+        assert!(flow.release(0).is_empty());
+        assert_eq!(ns(&flow.release(1)), [3, 4]);
+        // A message committed meanwhile waits behind those already queued.
+        assert_eq!(flow.admit(message("n1", 6), 10, 2), None);
+        assert_eq!(ns(&flow.release(2)), [5, 6]);
+        assert!(!flow.queued());
+    }
+
+    #[test]
+    fn peers_without_credit_and_clients_are_not_limited() {
+        let mut flow = Flow::default();
+        assert!((1..=5).all(|n| flow.admit(message("n2", n), 10, 0).is_some()));
+        let reply = message("c1", 1);
+        flow.received("n2", Some(0));
+        assert_eq!(flow.admit(reply.clone(), 10, 0), Some(reply));
+        assert_eq!(flow.admit(message("n2", 6), 10, 0), None);
+    }
+
+    #[test]
+    fn drain_ignores_credit() {
+        let mut flow = Flow::default();
+        flow.received("n1", Some(0));
+        flow.received("n2", Some(0));
+        flow.admit(message("n1", 1), 10, 0);
+        flow.admit(message("n2", 2), 10, 0);
+        let mut drained = ns(&flow.drain());
+        drained.sort();
+        assert_eq!(drained, [1, 2]);
+        assert!(!flow.queued());
+        assert!(flow.release(1).is_empty());
+    }
+}
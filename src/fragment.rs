@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::metrics;
+
+// Internal messages whose serialized form exceeds `MAELSTROM_MAX_MESSAGE_BYTES` are split into
+// numbered `fragment` messages carrying slices of the serialized body, and reassembled by the
+// receiver before being handed to the handlers. Very long single-line JSON messages otherwise
+// stress Maelstrom and stdio buffering, e.g. a replicate carrying a huge set.
+static MAX_MESSAGE_BYTES: LazyLock<usize> =
+    LazyLock::new(|| crate::runtime::env_or("MAELSTROM_MAX_MESSAGE_BYTES", 1 << 20));
+
+// Partially received messages are dropped if not completed within this window, since a lost
+// fragment is never resent on its own. The sender's retry logic resends the whole message.
+static REASSEMBLY_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(crate::runtime::env_or("MAELSTROM_REASSEMBLY_TIMEOUT_MS", 5000))
+});
+
+static REASSEMBLER: LazyLock<Mutex<Reassembler>> = LazyLock::new(Default::default);
+
+// Split `message` into fragments if it is an oversized message to another node. Client replies are
+// never fragmented, since clients don't speak this protocol.
+pub fn fragment(message: Map<String, Value>, serialized: &str) -> Vec<Map<String, Value>> {
+    let max_bytes = *MAX_MESSAGE_BYTES;
+    if serialized.len() <= max_bytes || !crate::node::is_node(message["dest"].as_str().unwrap()) {
+        return vec![message];
+    }
+
+    let body = serde_json::to_string(&message["body"]).unwrap();
+    // Leave room for the fragment's own envelope.
+    let chunk_bytes = max_bytes.saturating_sub(256).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < body.len() {
+        let mut end = (start + chunk_bytes).min(body.len());
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A chunk smaller than a single char, only possible with a tiny limit.
+        if end == start {
+            end = start + body[start..].chars().next().unwrap().len_utf8();
+        }
+        chunks.push(&body[start..end]);
+        start = end;
+    }
+
+    metrics::incr("fragment.fragmented_messages");
+    metrics::add("fragment.sent_fragments", chunks.len() as u64);
+    let count = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| {
+            let fragment = serde_json::json!({
+                "src": message["src"],
+                "dest": message["dest"],
+                "body": {
+                    "type": "fragment",
+                    "fragment_of": message["body"]["msg_id"],
+                    "index": index,
+                    "count": count,
+                    "data": data,
+                }
+            });
+            let Value::Object(fragment) = fragment else {
+                panic!("Invalid fragment {:?}", fragment);
+            };
+            fragment
+        })
+        .collect()
+}
+
+// Feed a received `fragment` message to the reassembler. Returns the original message once all of
+// its fragments have arrived.
+pub fn reassemble(fragment: Map<String, Value>) -> Option<Map<String, Value>> {
+    REASSEMBLER.lock().receive(fragment)
+}
+
+struct Partial {
+    first_received: Instant,
+    chunks: Vec<Option<String>>,
+    remaining: usize,
+}
+
+#[derive(Default)]
+struct Reassembler {
+    // {(src, fragment_of): partial message}.
+    partial: HashMap<(String, u64), Partial>,
+}
+
+impl Reassembler {
+    fn receive(&mut self, mut fragment: Map<String, Value>) -> Option<Map<String, Value>> {
+        self.expire();
+        metrics::incr("fragment.received_fragments");
+
+        let src: String = crate::rpc::take_field(&mut fragment, "src");
+        let dest: String = crate::rpc::take_field(&mut fragment, "dest");
+        let mut body: Map<String, Value> = crate::rpc::take_field(&mut fragment, "body");
+        let fragment_of: u64 = crate::rpc::take_field(&mut body, "fragment_of");
+        let index: usize = crate::rpc::take_field(&mut body, "index");
+        let count: usize = crate::rpc::take_field(&mut body, "count");
+        let data: String = crate::rpc::take_field(&mut body, "data");
+
+        let key = (src, fragment_of);
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            first_received: crate::clock::now(),
+            chunks: vec![None; count],
+            remaining: count,
+        });
+        if partial.chunks[index].is_none() {
+            partial.chunks[index] = Some(data);
+            partial.remaining -= 1;
+        }
+        if partial.remaining > 0 {
+            return None;
+        }
+
+        let partial = self.partial.remove(&key).unwrap();
+        let body: String = partial.chunks.into_iter().map(Option::unwrap).collect();
+        let Ok(body) = serde_json::from_str::<Value>(&body) else {
+            panic!("Failed to parse reassembled body: {body}");
+        };
+        metrics::incr("fragment.reassembled_messages");
+        let (src, _) = key;
+        let message = serde_json::json!({ "src": src, "dest": dest, "body": body });
+        let Value::Object(message) = message else {
+            panic!("Invalid message {:?}", message);
+        };
+        Some(message)
+    }
+
+    fn expire(&mut self) {
+        let timeout = *REASSEMBLY_TIMEOUT;
+        self.partial.retain(|(src, fragment_of), partial| {
+            let live = (crate::clock::now() - partial.first_received) < timeout;
+            if !live {
+                eprintln!("Dropping incomplete message {fragment_of} from {src}");
+                metrics::incr("fragment.expired_messages");
+            }
+            live
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A message to a peer large enough to go out as 3 fragments.
+    fn large_message() -> Map<String, Value> {
+        let payload = "x".repeat(*MAX_MESSAGE_BYTES * 5 / 2);
+        let message = serde_json::json!({
+            "src": "n0",
+            "dest": "n1",
+            "body": {"type": "replicate", "msg_id": 7, "payload": payload},
+        });
+        let Value::Object(message) = message else {
+            panic!("Invalid message {:?}", message);
+        };
+        message
+    }
+
+    fn fragments(message: &Map<String, Value>) -> Vec<Map<String, Value>> {
+        let serialized = serde_json::to_string(message).unwrap();
+        let fragments = fragment(message.clone(), &serialized);
+        assert_eq!(fragments.len(), 3);
+        fragments
+    }
+
+    #[test]
+    fn small_messages_and_client_replies_go_out_whole() {
+        let message = large_message();
+        let serialized = serde_json::to_string(&message).unwrap();
+        let mut reply = message.clone();
+        reply["dest"] = Value::from("c1");
+        assert_eq!(fragment(reply.clone(), &serialized), [reply]);
+        let mut small = message;
+        small["body"]["payload"] = Value::from("x");
+        assert_eq!(fragment(small.clone(), "{}"), [small]);
+    }
+
+    #[test]
+    fn out_of_order_fragments_reassemble() {
+        let message = large_message();
+        let mut reassembler = Reassembler::default();
+        let mut fragments = fragments(&message);
+        fragments.reverse();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert_eq!(reassembler.receive(fragment), None);
+        }
+        assert_eq!(reassembler.receive(last), Some(message));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let message = large_message();
+        let mut reassembler = Reassembler::default();
+        let fragments = fragments(&message);
+        for fragment in [&fragments[0], &fragments[0], &fragments[1], &fragments[1]] {
+            assert_eq!(reassembler.receive(fragment.clone()), None);
+        }
+        assert_eq!(reassembler.receive(fragments[2].clone()), Some(message));
+    }
+
+    #[test]
+    fn a_missing_fragment_holds_the_message_back() {
+        let message = large_message();
+        let mut reassembler = Reassembler::default();
+        let fragments = fragments(&message);
+        assert_eq!(reassembler.receive(fragments[0].clone()), None);
+        assert_eq!(reassembler.receive(fragments[1].clone()), None);
+        assert_eq!(reassembler.partial[&("n0".to_owned(), 7)].remaining, 1);
+        // Nothing is handed on until the final fragment arrives.
+        assert_eq!(reassembler.receive(fragments[2].clone()), Some(message));
+    }
+}
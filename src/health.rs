@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::metrics;
+use crate::runtime::env_or;
+
+// With `MAELSTROM_STALENESS_HINT`, replies served while the node can only reach a minority of the
+// cluster carry `stale_ms`, how long it's been cut off from the majority, so write-ups can tell
+// which reads during a partition came from the losing side.
+static STALENESS_HINT: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_STALENESS_HINT", false));
+
+struct PeerHealth {
+    // When we first sent the peer something which it hasn't answered (or otherwise talked to us)
+    // since.
+    waiting_since: Option<Instant>,
+    reachable: bool,
+}
+
+// Tracks which peers are reachable. A peer is considered unreachable once it's gone quiet for
+// `timeout` while we were waiting to hear from it, and reachable again as soon as we hear anything
+// from it. Idle peers we aren't waiting on stay reachable.
+//
+// Internally locked so it can be updated from handlers holding either side of the node's RwLock.
+pub struct Health {
+    timeout: Duration,
+    peers: Mutex<HashMap<String, PeerHealth>>,
+    // When we last found ourselves able to reach only a minority of the cluster, if we still are.
+    minority_since: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, peers: Mutex::new(HashMap::new()), minority_since: Mutex::new(None) }
+    }
+
+    // Record that we sent `peer` something we expect to hear back about.
+    pub fn sent_to(&self, peer: &str) {
+        let mut peers = self.peers.lock();
+        let health = peers
+            .entry(peer.to_owned())
+            .or_insert(PeerHealth { waiting_since: None, reachable: true });
+        health.waiting_since.get_or_insert_with(crate::clock::now);
+    }
+
+    // Record that we heard from `peer`. Returns true if the peer was unreachable, meaning the
+    // partition between us just healed and the caller should trigger its catch-up exchange.
+    pub fn heard_from(&self, peer: &str) -> bool {
+        let mut peers = self.peers.lock();
+        let health = peers
+            .entry(peer.to_owned())
+            .or_insert(PeerHealth { waiting_since: None, reachable: true });
+        health.waiting_since = None;
+        let healed = !health.reachable;
+        health.reachable = true;
+        if healed {
+            eprintln!("Peer {peer} is reachable again");
+        }
+        healed
+    }
+
+    // Mark peers we've been waiting on for longer than `timeout` as unreachable, and check whether
+    // we can still reach a majority of the `cluster_size` nodes, ourselves included. Peers we don't
+    // exchange with count as reachable. Returns the peers which just became unreachable.
+    pub fn check(&self, cluster_size: usize) -> Vec<String> {
+        let mut newly_unreachable = Vec::new();
+        let mut peers = self.peers.lock();
+        for (peer, health) in peers.iter_mut() {
+            let Some(waiting_since) = health.waiting_since else {
+                continue;
+            };
+            if health.reachable && (crate::clock::now() - waiting_since) > self.timeout {
+                eprintln!("Peer {peer} is unreachable");
+                health.reachable = false;
+                newly_unreachable.push(peer.clone());
+            }
+        }
+        let unreachable = peers.values().filter(|health| !health.reachable).count();
+        self.check_majority(cluster_size.saturating_sub(unreachable), cluster_size);
+        newly_unreachable
+    }
+
+    fn check_majority(&self, reachable: usize, cluster_size: usize) {
+        metrics::set("health.reachable_nodes", reachable as u64);
+        let in_minority = reachable <= cluster_size / 2;
+        let mut minority_since = self.minority_since.lock();
+        match (in_minority, *minority_since) {
+            (true, None) => {
+                eprintln!("Can only reach {reachable} of {cluster_size} nodes, a minority");
+                metrics::incr("health.minority_episodes");
+                *minority_since = Some(crate::clock::now());
+            }
+            (false, Some(since)) => {
+                let lasted = crate::clock::now() - since;
+                eprintln!("Can reach a majority again, after {lasted:?}");
+                metrics::max("health.max_minority_ms", lasted.as_millis() as u64);
+                *minority_since = None;
+            }
+            _ => {}
+        }
+        metrics::set("health.in_minority", in_minority as u64);
+    }
+
+    pub fn in_minority(&self) -> bool {
+        self.minority_since.lock().is_some()
+    }
+
+    // Add the staleness hint to `response` if enabled and we're in a minority, see
+    // `STALENESS_HINT`.
+    pub fn tag_stale(&self, response: &mut Map<String, Value>) {
+        let Some(since) = *self.minority_since.lock() else {
+            return;
+        };
+        if *STALENESS_HINT {
+            let stale = (crate::clock::now() - since).as_millis() as u64;
+            response["body"]["stale_ms"] = serde_json::json!(stale);
+        }
+    }
+
+    pub fn is_reachable(&self, peer: &str) -> bool {
+        self.peers.lock().get(peer).is_none_or(|health| health.reachable)
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use serde::{Serialize, Serializer};
+
+use crate::metrics;
+use crate::runtime::env_or;
+
+// How many of the most accessed keys `KeyStats` reports.
+static TOP_N: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_HOT_KEYS", 10));
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Accesses {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl Accesses {
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+// Per key access counts for a store, to find its hot keys: where txns and CAS retries pile up, and
+// what sharding would have to split. Recording takes `&self`, so read paths can count too. It
+// serializes as the top `MAELSTROM_HOT_KEYS` keys, which is what a `dump_state` snapshot shows.
+#[derive(Default)]
+pub struct KeyStats<K> {
+    counts: Mutex<HashMap<K, Accesses>>,
+}
+
+impl<K: Clone + Display + Eq + Hash> KeyStats<K> {
+    pub fn new() -> Self {
+        Self { counts: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn read(&self, key: &K) {
+        self.counts.lock().entry(key.clone()).or_default().reads += 1;
+    }
+
+    pub fn write(&self, key: &K) {
+        self.counts.lock().entry(key.clone()).or_default().writes += 1;
+    }
+
+    // The `n` most accessed keys, most accessed first.
+    pub fn hottest(&self, n: usize) -> Vec<(K, Accesses)> {
+        let mut counts: Vec<_> = self.counts.lock().iter().map(|(k, a)| (k.clone(), *a)).collect();
+        counts.sort_by_key(|(_, accesses)| std::cmp::Reverse(accesses.total()));
+        counts.truncate(n);
+        counts
+    }
+
+    // Set `{prefix}.hot_keys.{key}` to the access count of each of the top keys, e.g. before the
+    // metrics are dumped on shutdown.
+    pub fn publish(&self, prefix: &str) {
+        for (key, accesses) in self.hottest(*TOP_N) {
+            metrics::set(&format!("{prefix}.hot_keys.{key}"), accesses.total());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HotKey {
+    key: String,
+    #[serde(flatten)]
+    accesses: Accesses,
+}
+
+impl<K: Clone + Display + Eq + Hash> Serialize for KeyStats<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hottest = self.hottest(*TOP_N).into_iter();
+        let hottest = hottest.map(|(key, accesses)| HotKey { key: key.to_string(), accesses });
+        serializer.collect_seq(hottest)
+    }
+}
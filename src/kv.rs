@@ -0,0 +1,16 @@
+// Building blocks for key-value and txn workloads: storage, forwarding to a primary, and quorum
+// replication of registers.
+pub use crate::abd::Abd;
+pub use crate::key_stats::KeyStats;
+pub use crate::offset_log::OffsetLog;
+pub use crate::persistent_map::PersistentMap;
+pub use crate::proxy::Proxy;
+pub use crate::txn::TxnOp;
+
+pub mod merge {
+    pub use crate::merge::{merge, merge_with, Conflict, MergePolicy, Overwrite, Reject, Union};
+}
+
+pub mod quorum {
+    pub use crate::quorum::{majority, newest, Done, Quorum, Registers, Timestamp, Versioned};
+}
@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+
+// A single client operation as recorded in a history. `call` and `ret` are timestamps from one
+// shared clock (e.g. the simulator's tick counter). An op whose outcome is unknown, such as a
+// write which timed out, has `output: None` and `ret: u64::MAX`; it may or may not have taken
+// effect, so the checker is free to linearize it anywhere after its call, or never.
+#[derive(Clone, Debug)]
+pub struct Operation<I, O> {
+    pub call: u64,
+    pub ret: u64,
+    pub input: I,
+    pub output: Option<O>,
+}
+
+// A sequential specification of the object under test.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+    type Input;
+    type Output;
+
+    fn init(&self) -> Self::State;
+
+    // Apply `input` to `state`. Returns the resulting state if the op could have produced
+    // `output` (always true for an unknown output), otherwise None.
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State>;
+}
+
+// Wing & Gong's search with Lowe's memoization of (linearized ops, state) pairs. The search is
+// exponential in the worst case, so this is only meant for the small histories our tests produce.
+pub fn check<M: Model>(model: &M, history: &[Operation<M::Input, M::Output>]) -> bool {
+    let mut linearized = vec![false; history.len()];
+    let mut seen = HashSet::new();
+    search(model, history, &mut linearized, model.init(), &mut seen)
+}
+
+fn search<M: Model>(
+    model: &M,
+    history: &[Operation<M::Input, M::Output>],
+    linearized: &mut Vec<bool>,
+    state: M::State,
+    seen: &mut HashSet<(Vec<bool>, M::State)>,
+) -> bool {
+    // Done once every op which returned has a place in the order. Unknown ops can be dropped.
+    let pending = || history.iter().zip(linearized.iter()).filter(|(_, &done)| !done);
+    if pending().all(|(op, _)| op.output.is_none()) {
+        return true;
+    }
+    if !seen.insert((linearized.clone(), state.clone())) {
+        return false;
+    }
+
+    // An op can only come next if it was called before every remaining op returned.
+    let horizon = pending().map(|(op, _)| op.ret).min().unwrap();
+    for i in 0..history.len() {
+        let op = &history[i];
+        if linearized[i] || op.call > horizon {
+            continue;
+        }
+        let Some(next) = model.step(&state, &op.input, op.output.as_ref()) else {
+            continue;
+        };
+        linearized[i] = true;
+        if search(model, history, linearized, next, seen) {
+            return true;
+        }
+        linearized[i] = false;
+    }
+    false
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegisterInput {
+    Read,
+    Write(i64),
+    Cas(i64, i64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegisterOutput {
+    Ok,
+    Read(Option<i64>),
+    // The cas precondition didn't hold (Maelstrom error 22).
+    CasFailed,
+}
+
+// A read/write/cas register which starts out empty, as in Maelstrom's lin-kv workload.
+pub struct Register;
+
+impl Model for Register {
+    type State = Option<i64>;
+    type Input = RegisterInput;
+    type Output = RegisterOutput;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        match (input, output) {
+            (RegisterInput::Read, None) => Some(*state),
+            (RegisterInput::Read, Some(RegisterOutput::Read(v))) if v == state => Some(*state),
+            (RegisterInput::Write(v), None | Some(RegisterOutput::Ok)) => Some(Some(*v)),
+            (RegisterInput::Cas(from, to), None) => {
+                Some(if *state == Some(*from) { Some(*to) } else { *state })
+            }
+            (RegisterInput::Cas(from, to), Some(RegisterOutput::Ok)) if *state == Some(*from) => {
+                Some(Some(*to))
+            }
+            (RegisterInput::Cas(from, _), Some(RegisterOutput::CasFailed))
+                if *state != Some(*from) =>
+            {
+                Some(*state)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CounterInput {
+    Add(i64),
+    Read,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CounterOutput {
+    Ok,
+    Read(i64),
+}
+
+// A counter which starts at 0, as in the g-counter/pn-counter workloads.
+pub struct Counter;
+
+impl Model for Counter {
+    type State = i64;
+    type Input = CounterInput;
+    type Output = CounterOutput;
+
+    fn init(&self) -> Self::State {
+        0
+    }
+
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        match (input, output) {
+            // An add which would overflow can't have happened.
+            (CounterInput::Add(delta), None | Some(CounterOutput::Ok)) => state.checked_add(*delta),
+            (CounterInput::Read, None) => Some(*state),
+            (CounterInput::Read, Some(CounterOutput::Read(v))) if v == state => Some(*state),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op<I, O>(call: u64, ret: u64, input: I, output: O) -> Operation<I, O> {
+        Operation { call, ret, input, output: Some(output) }
+    }
+
+    // Timed out: called at `call`, never returned.
+    fn unknown<I, O>(call: u64, input: I) -> Operation<I, O> {
+        Operation { call, ret: u64::MAX, input, output: None }
+    }
+
+    #[test]
+    fn register_sequential() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [
+            op(0, 1, I::Read, O::Read(None)),
+            op(2, 3, I::Write(1), O::Ok),
+            op(4, 5, I::Read, O::Read(Some(1))),
+            op(6, 7, I::Cas(1, 2), O::Ok),
+            op(8, 9, I::Cas(1, 3), O::CasFailed),
+            op(10, 11, I::Read, O::Read(Some(2))),
+        ];
+        assert!(check(&Register, &history));
+    }
+
+    #[test]
+    fn register_concurrent_ops_reorder() {
+        use {RegisterInput as I, RegisterOutput as O};
+        // The read overlaps both writes, so it may see either.
+        let history = [
+            op(0, 10, I::Write(1), O::Ok),
+            op(1, 11, I::Write(2), O::Ok),
+            op(2, 12, I::Read, O::Read(Some(1))),
+        ];
+        assert!(check(&Register, &history));
+    }
+
+    #[test]
+    fn register_stale_read() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [
+            op(0, 1, I::Write(1), O::Ok),
+            op(2, 3, I::Write(2), O::Ok),
+            op(4, 5, I::Read, O::Read(Some(1))),
+        ];
+        assert!(!check(&Register, &history));
+    }
+
+    #[test]
+    fn register_read_before_write_was_called() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [op(0, 1, I::Read, O::Read(Some(1))), op(2, 3, I::Write(1), O::Ok)];
+        assert!(!check(&Register, &history));
+    }
+
+    #[test]
+    fn register_unknown_write_may_take_effect() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [
+            unknown(0, I::Write(1)),
+            op(5, 6, I::Read, O::Read(None)),
+            op(7, 8, I::Read, O::Read(Some(1))),
+        ];
+        assert!(check(&Register, &history));
+    }
+
+    #[test]
+    fn register_unknown_write_may_never_happen() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [unknown(0, I::Write(1)), op(5, 6, I::Read, O::Read(None))];
+        assert!(check(&Register, &history));
+    }
+
+    #[test]
+    fn register_unknown_write_cant_precede_its_call() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [op(0, 1, I::Read, O::Read(Some(1))), unknown(2, I::Write(1))];
+        assert!(!check(&Register, &history));
+    }
+
+    #[test]
+    fn register_failed_cas_needs_a_mismatch() {
+        use {RegisterInput as I, RegisterOutput as O};
+        let history = [op(0, 1, I::Write(1), O::Ok), op(2, 3, I::Cas(1, 2), O::CasFailed)];
+        assert!(!check(&Register, &history));
+    }
+
+    #[test]
+    fn counter_sums_adds() {
+        use {CounterInput as I, CounterOutput as O};
+        let history = [
+            op(0, 1, I::Add(2), O::Ok),
+            op(2, 3, I::Add(-5), O::Ok),
+            op(4, 5, I::Read, O::Read(-3)),
+        ];
+        assert!(check(&Counter, &history));
+    }
+
+    #[test]
+    fn counter_concurrent_read_sees_a_prefix() {
+        use {CounterInput as I, CounterOutput as O};
+        let history = [
+            op(0, 10, I::Add(1), O::Ok),
+            op(0, 10, I::Add(2), O::Ok),
+            op(1, 9, I::Read, O::Read(2)),
+        ];
+        assert!(check(&Counter, &history));
+    }
+
+    #[test]
+    fn counter_lost_add() {
+        use {CounterInput as I, CounterOutput as O};
+        let history =
+            [op(0, 1, I::Add(1), O::Ok), op(2, 3, I::Add(2), O::Ok), op(4, 5, I::Read, O::Read(2))];
+        assert!(!check(&Counter, &history));
+    }
+
+    #[test]
+    fn counter_unknown_adds_are_optional() {
+        use {CounterInput as I, CounterOutput as O};
+        let history = [
+            unknown(0, I::Add(1)),
+            unknown(0, I::Add(10)),
+            op(5, 6, I::Read, O::Read(10)),
+            op(7, 8, I::Read, O::Read(11)),
+        ];
+        assert!(check(&Counter, &history));
+        let history = [unknown(0, I::Add(1)), op(5, 6, I::Read, O::Read(2))];
+        assert!(!check(&Counter, &history));
+    }
+
+    #[test]
+    fn counter_overflow_is_a_violation() {
+        use {CounterInput as I, CounterOutput as O};
+        let history = [op(0, 1, I::Add(i64::MAX), O::Ok), op(2, 3, I::Add(1), O::Ok)];
+        assert!(!check(&Counter, &history));
+        // An unknown add which would overflow is simply never linearized.
+        let history = [
+            op(0, 1, I::Add(i64::MAX), O::Ok),
+            unknown(2, I::Add(1)),
+            op(3, 4, I::Read, O::Read(i64::MAX)),
+        ];
+        assert!(check(&Counter, &history));
+    }
+}
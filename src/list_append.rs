@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+// Checks list-append histories (the datomic txn workload) for a subset of the anomalies Elle
+// reports. Like Elle we rely on every appended value being unique per key, which lets us map each
+// element of a read back to the txn which wrote it, and on reads being prefixes of one another,
+// which lets us recover the version order of every key from its longest read.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MicroOp {
+    Append(i64, i64),
+    // A read of `key` with the list it returned, None if the key didn't exist.
+    Read(i64, Option<Vec<i64>>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Committed,
+    Aborted,
+    // The client timed out; the txn may or may not have committed.
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct Txn {
+    pub ops: Vec<MicroOp>,
+    pub outcome: Outcome,
+}
+
+// Txns are identified by their index in the history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    // Two reads of `key` which aren't prefixes of each other, so no version order exists.
+    IncompatibleOrder { key: i64, a: Vec<i64>, b: Vec<i64> },
+    // Dirty write: a cycle of txns each of which overwrote (appended after) the previous one.
+    G0 { cycle: Vec<usize> },
+    // Aborted read: `reader` committed after observing `value`, written by the aborted `writer`.
+    G1a { reader: usize, writer: usize, key: i64, value: i64 },
+    // Intermediate read: `reader` observed `value` as the last element of `key`, but `writer`
+    // went on to append more to `key` in the same txn.
+    G1b { reader: usize, writer: usize, key: i64, value: i64 },
+}
+
+pub fn check(history: &[Txn]) -> Vec<Anomaly> {
+    // {(key, value): (writer, is_final_append_to_key_by_writer)}.
+    let mut writers = HashMap::new();
+    for (i, txn) in history.iter().enumerate() {
+        let mut last_append = HashMap::new();
+        for op in txn.ops.iter() {
+            if let MicroOp::Append(key, value) = op {
+                writers.insert((*key, *value), (i, false));
+                last_append.insert(*key, *value);
+            }
+        }
+        for (key, value) in last_append {
+            writers.insert((key, value), (i, true));
+        }
+    }
+
+    let mut anomalies = Vec::new();
+    let mut longest_reads: HashMap<i64, &Vec<i64>> = HashMap::new();
+    for (i, txn) in history.iter().enumerate() {
+        if txn.outcome != Outcome::Committed {
+            continue;
+        }
+        for op in txn.ops.iter() {
+            let MicroOp::Read(key, Some(values)) = op else {
+                continue;
+            };
+
+            for value in values {
+                match writers.get(&(*key, *value)) {
+                    Some(&(writer, _)) if history[writer].outcome == Outcome::Aborted => {
+                        anomalies.push(Anomaly::G1a { reader: i, writer, key: *key, value: *value })
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(value) = values.last() {
+                match writers.get(&(*key, *value)) {
+                    Some(&(writer, false)) if writer != i => {
+                        anomalies.push(Anomaly::G1b { reader: i, writer, key: *key, value: *value })
+                    }
+                    _ => (),
+                }
+            }
+
+            let longest = longest_reads.entry(*key).or_insert(values);
+            let (short, long) =
+                if longest.len() < values.len() { (*longest, values) } else { (values, *longest) };
+            if !long.starts_with(short) {
+                anomalies.push(Anomaly::IncompatibleOrder {
+                    key: *key,
+                    a: short.clone(),
+                    b: long.clone(),
+                });
+            } else {
+                *longest = long;
+            }
+        }
+    }
+
+    // Each pair of adjacent elements in a key's version order is a write-write dependency from the
+    // txn which wrote the first to the txn which wrote the second. Past an element no txn wrote, the
+    // order is unknown, so nothing is inferred from the rest of the read.
+    let mut ww_edges: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for (key, values) in longest_reads {
+        let txns = values.iter().map_while(|value| writers.get(&(key, *value)));
+        for ((from, _), (to, _)) in txns.clone().zip(txns.skip(1)) {
+            if from != to {
+                ww_edges.entry(*from).or_default().insert(*to);
+            }
+        }
+    }
+    for cycle in find_cycles(history.len(), &ww_edges) {
+        anomalies.push(Anomaly::G0 { cycle });
+    }
+    anomalies
+}
+
+// Tarjan's strongly connected components; every component with more than one txn has a cycle.
+fn find_cycles(num_txns: usize, edges: &HashMap<usize, HashSet<usize>>) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        edges: &'a HashMap<usize, HashSet<usize>>,
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next_index: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next_index);
+            self.low_link[v] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+
+            for &w in self.edges.get(&v).into_iter().flatten() {
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.low_link[v] = self.low_link[v].min(self.low_link[w]);
+                    }
+                    Some(index) if self.on_stack[w] => {
+                        self.low_link[v] = self.low_link[v].min(index);
+                    }
+                    Some(_) => (),
+                }
+            }
+
+            if Some(self.low_link[v]) == self.index[v] {
+                let mut component = Vec::new();
+                loop {
+                    let w = self.stack.pop().unwrap();
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                if component.len() > 1 {
+                    component.sort();
+                    self.components.push(component);
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges,
+        index: vec![None; num_txns],
+        low_link: vec![0; num_txns],
+        on_stack: vec![false; num_txns],
+        stack: Vec::new(),
+        next_index: 0,
+        components: Vec::new(),
+    };
+    for v in 0..num_txns {
+        if tarjan.index[v].is_none() {
+            tarjan.visit(v);
+        }
+    }
+    tarjan.components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MicroOp::{Append, Read};
+
+    fn committed(ops: Vec<MicroOp>) -> Txn {
+        Txn { ops, outcome: Outcome::Committed }
+    }
+
+    #[test]
+    fn clean_history() {
+        let history = [
+            committed(vec![Append(1, 1), Read(2, None)]),
+            committed(vec![Read(1, Some(vec![1])), Append(1, 2), Append(2, 1)]),
+            Txn { ops: vec![Append(1, 3)], outcome: Outcome::Unknown },
+            committed(vec![Read(1, Some(vec![1, 2, 3])), Read(2, Some(vec![1]))]),
+        ];
+        assert_eq!(check(&history), []);
+    }
+
+    #[test]
+    fn g0() {
+        // Txn 1 appended to key 1 after txn 0, and txn 0 to key 2 after txn 1.
+        let history = [
+            committed(vec![Append(1, 1), Append(2, 2)]),
+            committed(vec![Append(1, 2), Append(2, 1)]),
+            committed(vec![Read(1, Some(vec![1, 2])), Read(2, Some(vec![1, 2]))]),
+        ];
+        assert_eq!(check(&history), [Anomaly::G0 { cycle: vec![0, 1] }]);
+    }
+
+    #[test]
+    fn g1a() {
+        let history = [
+            Txn { ops: vec![Append(1, 1)], outcome: Outcome::Aborted },
+            committed(vec![Read(1, Some(vec![1]))]),
+        ];
+        assert_eq!(check(&history), [Anomaly::G1a { reader: 1, writer: 0, key: 1, value: 1 }]);
+    }
+
+    #[test]
+    fn g1b() {
+        let history =
+            [committed(vec![Append(1, 1), Append(1, 2)]), committed(vec![Read(1, Some(vec![1]))])];
+        assert_eq!(check(&history), [Anomaly::G1b { reader: 1, writer: 0, key: 1, value: 1 }]);
+    }
+
+    #[test]
+    fn g1b_ignores_reads_of_own_appends() {
+        let history = [committed(vec![Append(1, 1), Read(1, Some(vec![1])), Append(1, 2)])];
+        assert_eq!(check(&history), []);
+    }
+
+    #[test]
+    fn incompatible_order() {
+        let history = [
+            committed(vec![Append(1, 1)]),
+            committed(vec![Append(1, 2)]),
+            committed(vec![Read(1, Some(vec![1]))]),
+            committed(vec![Read(1, Some(vec![2]))]),
+        ];
+        let anomaly = Anomaly::IncompatibleOrder { key: 1, a: vec![2], b: vec![1] };
+        assert_eq!(check(&history), [anomaly]);
+    }
+
+    #[test]
+    fn ww_edges_stop_at_unknown_writers() {
+        // Nothing wrote 99, so the order of 2 relative to 1 is unknown, and there's no cycle.
+        let history = [
+            committed(vec![Append(1, 1), Append(2, 4)]),
+            committed(vec![Append(1, 2), Append(2, 3)]),
+            committed(vec![Read(1, Some(vec![1, 99, 2])), Read(2, Some(vec![3, 4]))]),
+        ];
+        assert_eq!(check(&history), []);
+    }
+}
@@ -0,0 +1,76 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::runtime::env_or;
+use crate::{clock, metrics};
+
+// Holding a lock for longer than `MAELSTROM_LOCK_WARN_MS` is logged. 0 disables the warnings, but
+// not the metrics.
+static WARN_AFTER: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_LOCK_WARN_MS", 100)));
+
+// A parking_lot Mutex which records how it's used, under `lock.{name}.*` in the metrics: how
+// often it's taken and found already held, how long callers wait for it and how long it's held.
+// Everything a workload does goes through its one lock, so these show when that lock, rather than
+// the work itself, is what limits throughput.
+pub struct InstrumentedMutex<T> {
+    name: &'static str,
+    mutex: Mutex<T>,
+}
+
+impl<T> InstrumentedMutex<T> {
+    pub fn new(name: &'static str, value: T) -> Self {
+        Self { name, mutex: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> InstrumentedGuard<'_, T> {
+        metrics::incr(&format!("lock.{}.acquisitions", self.name));
+        let guard = match self.mutex.try_lock() {
+            Some(guard) => guard,
+            None => {
+                metrics::incr(&format!("lock.{}.contended", self.name));
+                let started = clock::now();
+                let guard = self.mutex.lock();
+                let waited = (clock::now() - started).as_micros() as u64;
+                metrics::add(&format!("lock.{}.wait_us", self.name), waited);
+                metrics::max(&format!("lock.{}.max_wait_us", self.name), waited);
+                guard
+            }
+        };
+        InstrumentedGuard { name: self.name, guard, acquired: clock::now() }
+    }
+}
+
+pub struct InstrumentedGuard<'a, T> {
+    name: &'static str,
+    guard: MutexGuard<'a, T>,
+    acquired: Instant,
+}
+
+impl<T> Deref for InstrumentedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for InstrumentedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for InstrumentedGuard<'_, T> {
+    fn drop(&mut self) {
+        let held = clock::now() - self.acquired;
+        metrics::max(&format!("lock.{}.max_hold_us", self.name), held.as_micros() as u64);
+        if !WARN_AFTER.is_zero() && held > *WARN_AFTER {
+            metrics::incr(&format!("lock.{}.long_holds", self.name));
+            eprintln!("Held the {} lock for {held:?}", self.name);
+        }
+    }
+}
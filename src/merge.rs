@@ -0,0 +1,135 @@
+use std::sync::LazyLock;
+
+use crate::runtime::env_or;
+
+// Reconciling the history of a list-append key held by this node with the one replicated to us. If
+// one list is a prefix of the other, one side is just behind and the longer list wins under every
+// policy. Otherwise both sides appended concurrently, e.g. a primary which restarted and lost what
+// its followers still hold, and the policy decides. Merges are directional, "remote" being the
+// history we're catching up with, so only the side receiving it has to run them.
+//
+// `MAELSTROM_MERGE_POLICY` picks the policy: overwrite, the default, union or reject. Per-element
+// last-writer-wins is left out, as appended values carry no timestamps.
+static POLICY: LazyLock<Box<dyn MergePolicy>> = LazyLock::new(|| {
+    let name: String = env_or("MAELSTROM_MERGE_POLICY", "overwrite".to_owned());
+    match name.as_str() {
+        "overwrite" => Box::new(Overwrite),
+        "union" => Box::new(Union),
+        "reject" => Box::new(Reject),
+        _ => panic!("Unknown MAELSTROM_MERGE_POLICY {name}"),
+    }
+});
+
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub key: i64,
+    pub local: Vec<i64>,
+    pub remote: Vec<i64>,
+}
+
+pub trait MergePolicy: Send + Sync {
+    // Merge two histories which have diverged after their common prefix of length `common`.
+    fn merge_divergent(
+        &self,
+        key: i64,
+        local: &[i64],
+        remote: &[i64],
+        common: usize,
+    ) -> Result<Vec<i64>, Conflict>;
+}
+
+// Merge `key`'s histories under the run's policy.
+pub fn merge(key: i64, local: &[i64], remote: &[i64]) -> Result<Vec<i64>, Conflict> {
+    merge_with(&**POLICY, key, local, remote)
+}
+
+pub fn merge_with(
+    policy: &dyn MergePolicy,
+    key: i64,
+    local: &[i64],
+    remote: &[i64],
+) -> Result<Vec<i64>, Conflict> {
+    let common = local.iter().zip(remote).take_while(|(a, b)| a == b).count();
+    if common == local.len() || common == remote.len() {
+        let longer = if local.len() < remote.len() { remote } else { local };
+        return Ok(longer.to_vec());
+    }
+    policy.merge_divergent(key, local, remote, common)
+}
+
+// Blind overwrite: the remote history replaces ours, and whatever we appended concurrently is lost.
+// This is what replicating the whole value does, and leaves a follower identical to its primary.
+pub struct Overwrite;
+
+impl MergePolicy for Overwrite {
+    fn merge_divergent(
+        &self,
+        _key: i64,
+        _local: &[i64],
+        remote: &[i64],
+        _common: usize,
+    ) -> Result<Vec<i64>, Conflict> {
+        Ok(remote.to_vec())
+    }
+}
+
+// Keep every append: the remote history, then whatever we appended after the common prefix which
+// isn't already in it. The result extends the remote history, so reads of it stay consistent with
+// reads of the remote side.
+pub struct Union;
+
+impl MergePolicy for Union {
+    fn merge_divergent(
+        &self,
+        _key: i64,
+        local: &[i64],
+        remote: &[i64],
+        common: usize,
+    ) -> Result<Vec<i64>, Conflict> {
+        let mut merged = remote.to_vec();
+        for value in &local[common..] {
+            if !merged[common..].contains(value) {
+                merged.push(*value);
+            }
+        }
+        Ok(merged)
+    }
+}
+
+// Refuse to merge, surfacing divergence as an error for the caller to report.
+pub struct Reject;
+
+impl MergePolicy for Reject {
+    fn merge_divergent(
+        &self,
+        key: i64,
+        local: &[i64],
+        remote: &[i64],
+        _common: usize,
+    ) -> Result<Vec<i64>, Conflict> {
+        Err(Conflict { key, local: local.to_vec(), remote: remote.to_vec() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_longer_history_wins_when_one_is_a_prefix() {
+        for policy in [&Overwrite as &dyn MergePolicy, &Union, &Reject] {
+            assert_eq!(merge_with(policy, 1, &[1, 2], &[1, 2, 3]), Ok(vec![1, 2, 3]));
+            assert_eq!(merge_with(policy, 1, &[1, 2, 3], &[1]), Ok(vec![1, 2, 3]));
+            assert_eq!(merge_with(policy, 1, &[], &[]), Ok(vec![]));
+        }
+    }
+
+    #[test]
+    fn policies_settle_divergent_histories() {
+        let (local, remote) = ([1, 2, 4, 5], [1, 2, 3, 5]);
+        assert_eq!(merge_with(&Overwrite, 7, &local, &remote), Ok(vec![1, 2, 3, 5]));
+        assert_eq!(merge_with(&Union, 7, &local, &remote), Ok(vec![1, 2, 3, 5, 4]));
+        let conflict = Conflict { key: 7, local: local.to_vec(), remote: remote.to_vec() };
+        assert_eq!(merge_with(&Reject, 7, &local, &remote), Err(conflict));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// Process wide counters, keyed by name. Cheap enough for per-message accounting at the rates
+// Maelstrom drives us at, and dumped to stderr as JSON so they end up next to the node's logs.
+static REGISTRY: LazyLock<Mutex<BTreeMap<String, u64>>> = LazyLock::new(Default::default);
+
+pub fn add(name: &str, n: u64) {
+    let mut registry = REGISTRY.lock();
+    match registry.get_mut(name) {
+        Some(count) => *count += n,
+        None => {
+            registry.insert(name.to_owned(), n);
+        }
+    }
+}
+
+pub fn incr(name: &str) {
+    add(name, 1);
+}
+
+// Record `value` if it is the largest seen so far for `name`.
+pub fn max(name: &str, value: u64) {
+    let mut registry = REGISTRY.lock();
+    let entry = registry.entry(name.to_owned()).or_default();
+    *entry = value.max(*entry);
+}
+
+pub fn set(name: &str, value: u64) {
+    REGISTRY.lock().insert(name.to_owned(), value);
+}
+
+pub fn get(name: &str) -> u64 {
+    REGISTRY.lock().get(name).copied().unwrap_or(0)
+}
+
+pub fn snapshot() -> BTreeMap<String, u64> {
+    REGISTRY.lock().clone()
+}
+
+// The rate of some event, smoothed over roughly the last 8 occurrences, for adapting to load.
+#[derive(Default)]
+pub struct Rate {
+    // Smoothed time between events.
+    gap: Option<Duration>,
+    last: Option<Instant>,
+}
+
+impl Rate {
+    pub fn mark(&mut self) {
+        let now = crate::clock::now();
+        if let Some(last) = self.last {
+            let sample = now - last;
+            self.gap = Some(match self.gap {
+                Some(gap) => (gap * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+        self.last = Some(now);
+    }
+
+    // Events per second. The time since the last event counts too, so the rate decays while idle
+    // rather than staying at whatever the last burst's was.
+    pub fn per_sec(&self) -> f64 {
+        let (Some(gap), Some(last)) = (self.gap, self.last) else {
+            return 0.0;
+        };
+        1.0 / gap.max(crate::clock::now() - last).as_secs_f64()
+    }
+}
+
+pub fn dump() {
+    eprintln!("Metrics {}", serde_json::to_string(&snapshot()).unwrap());
+}
@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::persist;
+use crate::reliable::Reliable;
+use crate::rpc::{self, ERROR_CRASH, ERROR_NOT_SUPPORTED};
+use crate::rtt::{self, Rtts};
+use crate::runtime::Outbox;
+
+// Hands out the msg_ids for every message a node sends. Shared between tasks without locking.
+//
+// Relaxed ordering is sufficient. All read-modify-write operations on a single atomic are totally
+// ordered (its modification order) whatever ordering they use, so every `next` sees a distinct
+// previous value and ids are unique and sequential. What Relaxed doesn't give us is ordering with
+// respect to other memory, which we don't need since a msg_id is never used to publish other data.
+//
+// With persistence (see `persist`), ids are reserved `MSG_ID_HEADROOM` at a time, and each
+// reservation is stored before any id in it is handed out, so a node which crashes and restarts
+// resumes beyond every id it may have used rather than from 0, which peers deduplicating by
+// (src, msg_id) would take for replays. Only the `next` which runs out of reserved ids waits on a
+// write.
+#[derive(Default)]
+pub struct MsgIdAllocator {
+    next: AtomicU64,
+    // Set with persistence: the node the reservation is stored under.
+    node_id: Option<String>,
+    // Every id below this is covered by the stored reservation.
+    reserved: AtomicU64,
+    // Held while storing a new reservation.
+    reserving: Mutex<()>,
+}
+
+const MSG_ID_HEADROOM: u64 = 1000;
+
+impl MsgIdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Resume handing out ids after a restart, from a value previously returned by `snapshot`.
+    pub fn resume_from(snapshot: u64) -> Self {
+        Self { next: AtomicU64::new(snapshot), ..Self::default() }
+    }
+
+    // `node_id`'s allocator, resuming from its stored reservation if persistence is enabled.
+    pub fn load(node_id: &str) -> Self {
+        if !persist::enabled() {
+            return Self::new();
+        }
+        let reserved = persist::load(node_id, "msg_id").unwrap_or(0);
+        Self { node_id: Some(node_id.to_owned()), ..Self::resume_from(reserved) }
+    }
+
+    pub fn next(&self) -> u64 {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        if let Some(node_id) = &self.node_id {
+            self.reserve(node_id, id);
+        }
+        id
+    }
+
+    // Make sure `id` is covered by the stored reservation before it's used.
+    fn reserve(&self, node_id: &str, id: u64) {
+        if id < self.reserved.load(Ordering::Acquire) {
+            return;
+        }
+        let _reserving = self.reserving.lock();
+        if id < self.reserved.load(Ordering::Acquire) {
+            return;
+        }
+        let reserved = self.snapshot() + MSG_ID_HEADROOM;
+        persist::store(node_id, "msg_id", &reserved);
+        self.reserved.store(reserved, Ordering::Release);
+    }
+
+    // Every id below the returned value may have been handed out.
+    pub fn snapshot(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+}
+
+// A node's identity and the means to send messages. Workloads wrap this with their own state.
+// Clones share the msg_id allocator and outbox, so a clone can send without the workload's lock.
+#[derive(Clone)]
+pub struct Node {
+    node_id: String,
+    // Unique list of all neighbors/nodes.
+    node_ids: Vec<String>,
+
+    msg_id: Arc<MsgIdAllocator>,
+
+    outbox: Outbox,
+
+    reliable: Arc<Mutex<Reliable>>,
+
+    rtts: Arc<Mutex<Rtts>>,
+}
+
+impl Node {
+    pub fn new(node_id: &Value, node_ids: &Value, outbox: Outbox) -> Node {
+        let node_id = match node_id {
+            Value::String(id) => id.clone(),
+            _ => panic!("Non-string node_id {}", node_id),
+        };
+        // Use a HashSet to guarantee each element is unique.
+        let node_ids: HashSet<_> = match &node_ids {
+            Value::Array(ids) => ids.iter().map(|x| x.as_str().unwrap().to_string()).collect(),
+            _ => panic!("Non-string node_id {:?}", node_ids),
+        };
+        Node {
+            msg_id: Arc::new(MsgIdAllocator::load(&node_id)),
+            reliable: Arc::new(Mutex::new(Reliable::load(&node_id))),
+            rtts: Arc::new(Mutex::new(Rtts::default())),
+            node_id,
+            node_ids: node_ids.into_iter().collect(),
+            outbox,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    // Add a node which joined after init, returning false if we already knew it. Clones made
+    // before don't see it, so add it to the workload's own Node.
+    pub fn add_node(&mut self, node_id: &str) -> bool {
+        if self.node_ids.iter().any(|n| n == node_id) {
+            return false;
+        }
+        self.node_ids.push(node_id.to_owned());
+        true
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    // Commit messages produced by a handler. See `Outbox`.
+    pub fn commit(&self, messages: Vec<Map<String, Value>>) {
+        self.outbox.commit(messages);
+    }
+
+    // Some other node, chosen at random favoring those with a lower round trip time, or None in a
+    // single node cluster. See `nearby_peers`.
+    pub fn nearby_peer(&self) -> Option<&str> {
+        self.nearby_peers(1).pop()
+    }
+
+    // Up to `n` distinct other nodes, chosen at random with each weighted by the inverse of its
+    // round trip time, so gossip and repair go mostly to nearby peers while far ones still get
+    // some. Peers we haven't measured yet are weighted as an average peer, see `Rtts::weights`.
+    pub fn nearby_peers(&self, n: usize) -> Vec<&str> {
+        let mut peers: Vec<&str> =
+            self.node_ids.iter().map(String::as_str).filter(|&p| p != self.node_id).collect();
+        let mut weights = self.rtts.lock().weights(&peers);
+        let mut picked = Vec::new();
+        while picked.len() < n && !peers.is_empty() {
+            // RandomState is seeded randomly per instance, which is plenty for spreading load.
+            let random = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+            let i = rtt::pick(&weights, (random >> 11) as f64 / (1u64 << 53) as f64);
+            weights.swap_remove(i);
+            picked.push(peers.swap_remove(i));
+        }
+        picked
+    }
+
+    // Note that `dest` was just sent something it acks with `token`, e.g. a delta's `through`, so
+    // `ack_received` can time the round trip for `nearby_peers`.
+    pub fn await_ack(&self, dest: &str, token: u64) {
+        self.rtts.lock().await_ack(dest, token);
+    }
+
+    pub fn ack_received(&self, src: &str, token: u64) {
+        self.rtts.lock().ack_received(src, token);
+    }
+
+    // Send `body`, which must include a `type`, to `dest` and keep resending it until acked. The
+    // message is numbered with a per-peer `seq`, to be acked with `ack` by the receiver and passed
+    // to `handle_ack` by us. Registering the message for retransmission and committing it happen
+    // together, so one is never done without the other. Returns the message's `seq`, which acks are
+    // in terms of.
+    pub fn send_expect_ok(&self, dest: &str, body: Map<String, Value>) -> u64 {
+        let Some(Value::String(msg_type)) = body.get("type") else {
+            panic!("Invalid message body {:?}", body);
+        };
+        let mut message = self.build_message(&self.node_id, dest, msg_type);
+        for (k, v) in body.into_iter().filter(|(k, _)| k != "type") {
+            message["body"][k] = v;
+        }
+        // Commit under the lock so an ack can't be processed before the message is registered.
+        let mut reliable = self.reliable.lock();
+        reliable.register(dest, &mut message);
+        let seq = message["body"]["seq"].as_u64().unwrap();
+        self.commit(vec![message]);
+        seq
+    }
+
+    // Record receipt of `request`, sent with `send_expect_ok`, and build the ack of type
+    // `msg_type`. The ack covers everything received from the sender so far, not just `request`,
+    // and asks for any gaps to be resent right away rather than waiting on retransmission.
+    pub fn ack(&self, request: &Map<String, Value>, msg_type: &str) -> Map<String, Value> {
+        let src = self.receive(request);
+        self.build_ack(&src, msg_type)
+    }
+
+    // The first half of `ack`, for callers which ack later, returning the sender.
+    pub fn receive(&self, request: &Map<String, Value>) -> String {
+        let Some(src) = request["src"].as_str() else {
+            panic!("Invalid request {:?}", request);
+        };
+        let Some(seq) = request["body"]["seq"].as_u64() else {
+            panic!("Missing seq {:?}", request);
+        };
+        self.reliable.lock().received(src, seq);
+        src.to_owned()
+    }
+
+    // The second half of `ack`: an ack of everything received from `src` so far.
+    pub fn build_ack(&self, src: &str, msg_type: &str) -> Map<String, Value> {
+        let (acked_through, missing) = self.reliable.lock().watermark(src);
+        let mut ack = self.build_message(&self.node_id, src, msg_type);
+        ack["body"]["acked_through"] = serde_json::json!(acked_through);
+        if !missing.is_empty() {
+            eprintln!("Missing {:?} from {src}", missing);
+            ack["body"]["missing"] = serde_json::json!(missing);
+        }
+        ack
+    }
+
+    // Handle an ack built by `ack`, resending anything it says is missing.
+    pub fn handle_ack(&self, ack: &Map<String, Value>) {
+        let Some(src) = ack["src"].as_str() else {
+            panic!("Invalid ack {:?}", ack);
+        };
+        let Some(acked_through) = ack["body"]["acked_through"].as_u64() else {
+            panic!("Missing acked_through {:?}", ack);
+        };
+        let missing: Vec<u64> = match ack["body"].get("missing") {
+            Some(missing) => serde_json::from_value(missing.clone()).unwrap(),
+            None => Vec::new(),
+        };
+        let mut reliable = self.reliable.lock();
+        let (resend, rtt) = reliable.acked(src, acked_through, &missing);
+        if let Some(rtt) = rtt {
+            self.rtts.lock().observe(src, rtt);
+        }
+        self.commit(resend);
+    }
+
+    // Resend everything sent with `send_expect_ok` which hasn't been acked.
+    pub fn retransmit_unacked(&self) {
+        self.retransmit_unacked_with(|_, _| {});
+    }
+
+    // As `retransmit_unacked`, but passing each message and its destination to `rewrite` first, e.g.
+    // to drop whatever the peer is known to have received some other way. The message still has to
+    // be resent, however little is left of it, as the peer acks by sequence number.
+    pub fn retransmit_unacked_with(&self, mut rewrite: impl FnMut(&str, &mut Map<String, Value>)) {
+        let mut reliable = self.reliable.lock();
+        let mut messages = reliable.unacked();
+        for message in &mut messages {
+            let Some(dest) = message["dest"].as_str().map(str::to_owned) else {
+                panic!("Invalid message {:?}", message);
+            };
+            rewrite(&dest, message);
+        }
+        self.commit(crate::reliable::coalesce(messages));
+    }
+
+    // {peer: seq}, where the peer has acked everything sent to it with `send_expect_ok` up to and
+    // including `seq`, which is the `send_expect_ok` return value to compare with.
+    pub fn acked_through(&self) -> BTreeMap<String, u64> {
+        self.reliable.lock().acked_through()
+    }
+
+    // Whether every peer has acked everything sent with `send_expect_ok`, i.e. has all our data.
+    pub fn converged(&self) -> bool {
+        self.reliable.lock().converged()
+    }
+
+    pub fn build_message(&self, src: &str, dest: &str, msg_type: &str) -> Map<String, Value> {
+        let msg_id = self.msg_id.next();
+        let msg = serde_json::json!({
+            "src": src,
+            "dest": dest,
+            "body": {
+                "msg_id": msg_id,
+                "type": msg_type,
+            }
+        });
+        match msg {
+            Value::Object(obj) => obj,
+            _ => panic!("Invalid message {:?}", msg),
+        }
+    }
+
+    pub fn build_response(
+        &self,
+        request: &Map<String, Value>,
+        msg_type: &str,
+    ) -> Map<String, Value> {
+        assert_ne!(&self.node_id, "", "Uninitialized node cannot send responses. {request:?}");
+
+        let mut response =
+            self.build_message(&self.node_id, request["src"].as_str().unwrap(), msg_type);
+        let Value::Object(response_body) = &mut response["body"] else {
+            panic!("Invalid response {:?}", response);
+        };
+        let Value::Object(request_body) = &request["body"] else {
+            panic!("Invalid request {:?}", request);
+        };
+        response_body.insert("in_reply_to".to_owned(), request_body["msg_id"].clone());
+        response
+    }
+
+    pub fn build_error(
+        &self,
+        request: &Map<String, Value>,
+        code: u64,
+        text: &str,
+    ) -> Map<String, Value> {
+        let mut response = self.build_response(request, "error");
+        response["body"]["code"] = serde_json::json!(code);
+        response["body"]["text"] = serde_json::json!(text);
+        response
+    }
+
+    // Reply to a request of a type this node doesn't handle, e.g. because the binary was pointed at
+    // the wrong workload, instead of taking the node down.
+    pub fn reply_not_supported(&self, request: &Map<String, Value>) {
+        eprintln!("Unknown msg type {}", serde_json::to_string(request).unwrap());
+        // A request's header carries a null msg_id if the request had none.
+        if !request["body"]["msg_id"].is_null() && !rpc::is_reply(request) {
+            let text = format!("Unsupported msg type {}", request["body"]["type"]);
+            self.commit(vec![self.build_error(request, ERROR_NOT_SUPPORTED, &text)]);
+        }
+    }
+
+    // Tell the sender of the request described by `header` that its handler crashed, so it isn't
+    // left waiting forever. See `catch_panic`.
+    pub fn reply_crash(&self, header: &Map<String, Value>, text: &str) {
+        eprintln!("Handler crashed on {}: {text}", serde_json::to_string(header).unwrap());
+        if !rpc::is_reply(header) {
+            self.commit(vec![self.build_error(header, ERROR_CRASH, text)]);
+        }
+    }
+}
+
+// Maelstrom names nodes n0, n1, ... as opposed to clients (c0, c1, ...) and services (lin-kv, ...).
+pub fn is_node(id: &str) -> bool {
+    id.starts_with('n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_from_a_snapshot() {
+        let ids = MsgIdAllocator::new();
+        assert_eq!((ids.next(), ids.next()), (0, 1));
+        let resumed = MsgIdAllocator::resume_from(ids.snapshot());
+        assert_eq!(resumed.next(), 2);
+    }
+
+    #[test]
+    fn restarted_node_never_reuses_ids() {
+        persist::enable_for_tests();
+        let ids = MsgIdAllocator::load("n-msg-ids");
+        let used: Vec<_> = (0..2500).map(|_| ids.next()).collect();
+        assert_eq!(used, (0..2500).collect::<Vec<_>>());
+        // A crash, with nothing stored since.
+        drop(ids);
+        let restarted = MsgIdAllocator::load("n-msg-ids");
+        let first = restarted.next();
+        assert!(first >= 2500, "Reused {first}");
+        assert!(first <= 2500 + MSG_ID_HEADROOM, "Skipped to {first}");
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+
+use serde::Serialize;
+
+// An append only log addressed by offset, as used for a Kafka style topic or a list-append key.
+// Offsets start at 0 and keep counting up across `truncate`, which only drops the front of the log.
+// A consumer's progress is tracked with `commit`, which never moves backwards.
+#[derive(Clone, Serialize)]
+pub struct OffsetLog<T> {
+    // Offset of `entries[0]`.
+    base: u64,
+    entries: VecDeque<T>,
+    // Every offset below `committed` has been committed.
+    committed: u64,
+}
+
+impl<T> Default for OffsetLog<T> {
+    fn default() -> Self {
+        Self { base: 0, entries: VecDeque::new(), committed: 0 }
+    }
+}
+
+impl<T> OffsetLog<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Append `value`, returning its offset.
+    pub fn append(&mut self, value: T) -> u64 {
+        self.entries.push_back(value);
+        self.end() - 1
+    }
+
+    // The offset the next append will get.
+    pub fn end(&self) -> u64 {
+        self.base + self.entries.len() as u64
+    }
+
+    // The first offset still held, having not been truncated.
+    pub fn start(&self) -> u64 {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, offset: u64) -> Option<&T> {
+        let index = offset.checked_sub(self.base)?;
+        self.entries.get(index as usize)
+    }
+
+    // The (offset, value) pairs held in `range`. Offsets which were truncated or not yet appended are
+    // skipped.
+    pub fn read(&self, range: impl RangeBounds<u64>) -> impl Iterator<Item = (u64, &T)> {
+        let from = match range.start_bound() {
+            Bound::Included(&from) => from,
+            Bound::Excluded(&from) => from.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let to = match range.end_bound() {
+            Bound::Included(&to) => to.saturating_add(1),
+            Bound::Excluded(&to) => to,
+            Bound::Unbounded => u64::MAX,
+        };
+        let from = from.max(self.base);
+        let to = to.min(self.end()).max(from);
+        let skip = (from - self.base) as usize;
+        (from..to).zip(self.entries.iter().skip(skip))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter()
+    }
+
+    // Mark everything below `offset` as committed. Older commits are ignored, so the watermark only
+    // moves forward.
+    pub fn commit(&mut self, offset: u64) {
+        self.committed = self.committed.max(offset.min(self.end()));
+    }
+
+    // Every offset below this has been committed.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    // Drop everything below `offset`.
+    pub fn truncate(&mut self, offset: u64) {
+        let drop = offset.saturating_sub(self.base).min(self.entries.len() as u64);
+        self.entries.drain(..drop as usize);
+        self.base += drop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_of(values: impl IntoIterator<Item = char>) -> OffsetLog<char> {
+        let mut log = OffsetLog::new();
+        for value in values {
+            log.append(value);
+        }
+        log
+    }
+
+    fn read(log: &OffsetLog<char>, range: impl RangeBounds<u64>) -> Vec<(u64, char)> {
+        log.read(range).map(|(offset, &value)| (offset, value)).collect()
+    }
+
+    #[test]
+    fn append_returns_offsets() {
+        let mut log = OffsetLog::new();
+        assert!(log.is_empty());
+        assert_eq!(log.append('a'), 0);
+        assert_eq!(log.append('b'), 1);
+        assert_eq!((log.start(), log.end(), log.len()), (0, 2, 2));
+        assert_eq!(log.get(1), Some(&'b'));
+        assert_eq!(log.get(2), None);
+    }
+
+    #[test]
+    fn offsets_continue_across_truncate() {
+        let mut log = log_of("abc".chars());
+        log.truncate(2);
+        assert_eq!((log.start(), log.end(), log.len()), (2, 3, 1));
+        assert_eq!(log.get(1), None);
+        assert_eq!(log.append('d'), 3);
+        assert_eq!(log.iter().collect::<String>(), "cd");
+        // Truncating past the end drops everything, but the next offset is kept.
+        log.truncate(10);
+        assert!(log.is_empty());
+        assert_eq!(log.append('e'), 4);
+    }
+
+    #[test]
+    fn read_ranges() {
+        let mut log = log_of("abcd".chars());
+        assert_eq!(read(&log, 1..3), [(1, 'b'), (2, 'c')]);
+        assert_eq!(read(&log, 1..=3), [(1, 'b'), (2, 'c'), (3, 'd')]);
+        assert_eq!(read(&log, ..), [(0, 'a'), (1, 'b'), (2, 'c'), (3, 'd')]);
+        log.truncate(2);
+        assert_eq!(read(&log, 0..3), [(2, 'c')]);
+    }
+
+    #[test]
+    fn read_past_the_end() {
+        let log = log_of("ab".chars());
+        assert_eq!(read(&log, 1..10), [(1, 'b')]);
+        assert_eq!(read(&log, 2..), []);
+        assert_eq!(read(&log, 5..10), []);
+        assert_eq!(read(&log, u64::MAX..), []);
+        assert_eq!(read(&log, ..=u64::MAX), [(0, 'a'), (1, 'b')]);
+    }
+
+    #[test]
+    fn commit_offsets() {
+        let mut log = log_of("abc".chars());
+        assert_eq!(log.committed(), 0);
+        log.commit(2);
+        assert_eq!(log.committed(), 2);
+        // Offsets not yet appended can't be committed.
+        log.commit(10);
+        assert_eq!(log.committed(), 3);
+    }
+
+    #[test]
+    fn commit_never_moves_backwards() {
+        let mut log = log_of("abc".chars());
+        log.commit(2);
+        log.commit(1);
+        assert_eq!(log.committed(), 2);
+        log.commit(0);
+        assert_eq!(log.committed(), 2);
+    }
+}
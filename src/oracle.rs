@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Checks of the gossip workloads' final states, for whatever has collected them from every node,
+// e.g. `dump_state` snapshots taken once a run has quiesced, together with which client requests
+// were acknowledged. Each returns every violation found, empty if the states are correct. Requests
+// which timed out may or may not have taken effect, so they're given separately and allowed either
+// way.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    // `node` is missing elements, which some other node, or an acknowledged add, has.
+    Missing { node: String, elements: Vec<u64> },
+    // `node` has elements which no client ever added.
+    Unexpected { node: String, elements: Vec<u64> },
+    // `node`'s counter is outside the range the acknowledged and indefinite adds allow.
+    Count { node: String, value: i64, min: i64, max: i64 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Missing { node, elements } => {
+                write!(f, "{node} is missing {} elements: {elements:?}", elements.len())
+            }
+            Violation::Unexpected { node, elements } => {
+                write!(f, "{node} has {} elements never added: {elements:?}", elements.len())
+            }
+            Violation::Count { node, value, min, max } if min == max => {
+                write!(f, "{node} counted {value}, expected {min} (off by {})", value - min)
+            }
+            Violation::Count { node, value, min, max } => {
+                write!(f, "{node} counted {value}, expected between {min} and {max}")
+            }
+        }
+    }
+}
+
+// Broadcast: every node has received every message any node has.
+pub fn converged(states: &BTreeMap<String, BTreeSet<u64>>) -> Vec<Violation> {
+    let union: BTreeSet<u64> = states.values().flatten().copied().collect();
+    missing(states, &union)
+}
+
+// gset and broadcast: every node has every acknowledged element, and nothing beyond what was
+// acknowledged or `indefinite`.
+pub fn union_of_adds(
+    states: &BTreeMap<String, BTreeSet<u64>>,
+    acked: &BTreeSet<u64>,
+    indefinite: &BTreeSet<u64>,
+) -> Vec<Violation> {
+    let mut violations = missing(states, acked);
+    for (node, elements) in states {
+        let unexpected: Vec<_> = elements
+            .iter()
+            .filter(|&e| !acked.contains(e) && !indefinite.contains(e))
+            .copied()
+            .collect();
+        if !unexpected.is_empty() {
+            violations.push(Violation::Unexpected { node: node.clone(), elements: unexpected });
+        }
+    }
+    violations
+}
+
+// gcounter: every node's count is the sum of the acknowledged deltas, plus any subset of the
+// `indefinite` ones.
+pub fn sum_of_deltas(
+    counts: &BTreeMap<String, i64>,
+    acked: &[i64],
+    indefinite: &[i64],
+) -> Vec<Violation> {
+    let sum: i64 = acked.iter().sum();
+    let min = sum + indefinite.iter().filter(|&&d| d < 0).sum::<i64>();
+    let max = sum + indefinite.iter().filter(|&&d| d > 0).sum::<i64>();
+    let wrong = counts.iter().filter(|(_, &value)| value < min || value > max);
+    wrong.map(|(node, &value)| Violation::Count { node: node.clone(), value, min, max }).collect()
+}
+
+fn missing(states: &BTreeMap<String, BTreeSet<u64>>, expected: &BTreeSet<u64>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for (node, elements) in states {
+        let missing: Vec<_> = expected.difference(elements).copied().collect();
+        if !missing.is_empty() {
+            violations.push(Violation::Missing { node: node.clone(), elements: missing });
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(nodes: &[(&str, &[u64])]) -> BTreeMap<String, BTreeSet<u64>> {
+        nodes
+            .iter()
+            .map(|(node, elements)| (node.to_string(), elements.iter().copied().collect()))
+            .collect()
+    }
+
+    fn counts(nodes: &[(&str, i64)]) -> BTreeMap<String, i64> {
+        nodes.iter().map(|&(node, count)| (node.to_string(), count)).collect()
+    }
+
+    #[test]
+    fn converged_nodes() {
+        assert_eq!(converged(&states(&[("n0", &[1, 2]), ("n1", &[1, 2])])), []);
+    }
+
+    #[test]
+    fn unconverged_node_is_missing_elements() {
+        let violations = converged(&states(&[("n0", &[1, 2, 3]), ("n1", &[2])]));
+        assert_eq!(violations, [Violation::Missing { node: "n1".into(), elements: vec![1, 3] }]);
+    }
+
+    #[test]
+    fn union_of_adds_allows_indefinite_elements() {
+        let states = states(&[("n0", &[1, 2]), ("n1", &[1])]);
+        let violations = union_of_adds(&states, &[1].into(), &[2].into());
+        assert_eq!(violations, []);
+    }
+
+    #[test]
+    fn union_of_adds_missing_acked() {
+        let states = states(&[("n0", &[1, 2]), ("n1", &[1])]);
+        let violations = union_of_adds(&states, &[1, 2].into(), &BTreeSet::new());
+        assert_eq!(violations, [Violation::Missing { node: "n1".into(), elements: vec![2] }]);
+    }
+
+    #[test]
+    fn union_of_adds_unexpected() {
+        let states = states(&[("n0", &[1, 5])]);
+        let violations = union_of_adds(&states, &[1].into(), &[2].into());
+        assert_eq!(violations, [Violation::Unexpected { node: "n0".into(), elements: vec![5] }]);
+    }
+
+    #[test]
+    fn sum_of_acked_deltas() {
+        assert_eq!(sum_of_deltas(&counts(&[("n0", 3), ("n1", 3)]), &[1, 2], &[]), []);
+        let violations = sum_of_deltas(&counts(&[("n0", 3), ("n1", 2)]), &[1, 2], &[]);
+        let count = Violation::Count { node: "n1".into(), value: 2, min: 3, max: 3 };
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0], count);
+        assert_eq!(count.to_string(), "n1 counted 2, expected 3 (off by -1)");
+    }
+
+    #[test]
+    fn indefinite_deltas_bound_any_subset() {
+        // With 10 acked, -4 and 3 and 5 indefinite, any count from 6 (only -4) to 18 (3 and 5).
+        let (acked, indefinite) = (&[10], &[-4, 3, 5]);
+        for value in [6, 9, 10, 14, 18] {
+            assert_eq!(sum_of_deltas(&counts(&[("n0", value)]), acked, indefinite), []);
+        }
+        for value in [5, 19] {
+            let violations = sum_of_deltas(&counts(&[("n0", value)]), acked, indefinite);
+            let count = Violation::Count { node: "n0".into(), value, min: 6, max: 18 };
+            assert_eq!(violations, [count]);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use serde::Serialize;
+
+// A node's overlay: the peers it sends to and in what role, e.g. a broadcast neighbor or a tree
+// parent. Logged on shutdown and returned for a `dump_overlay` message, as JSON and as a Graphviz
+// digraph, so the effective topology of a run can be pieced together from every node's and drawn.
+// With `MAELSTROM_OVERLAY_DIR`, both are also written to `{node_id}.overlay.{json,dot}` there.
+static OVERLAY_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| std::env::var("MAELSTROM_OVERLAY_DIR").ok().map(PathBuf::from));
+
+#[derive(Default, Serialize)]
+pub struct Overlay {
+    edges: Vec<Edge>,
+}
+
+#[derive(Serialize)]
+struct Edge {
+    peer: String,
+    role: &'static str,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add an edge to each of `peers`, labelled with `role`.
+    pub fn add<'a>(&mut self, role: &'static str, peers: impl IntoIterator<Item = &'a String>) {
+        self.edges.extend(peers.into_iter().map(|peer| Edge { peer: peer.clone(), role }));
+    }
+
+    pub fn to_dot(&self, node_id: &str) -> String {
+        let edges = self.edges.iter().map(|edge| {
+            format!("  \"{node_id}\" -> \"{}\" [label=\"{}\"];\n", edge.peer, edge.role)
+        });
+        format!("digraph \"{node_id}\" {{\n{}}}\n", edges.collect::<String>())
+    }
+
+    // Log the overlay, and write it to `OVERLAY_DIR` if set. A failed write is only logged, since
+    // this is a debugging aid.
+    pub fn export(&self, node_id: &str) {
+        let json = serde_json::to_string(self).unwrap();
+        eprintln!("Overlay {json}");
+        let Some(dir) = OVERLAY_DIR.as_ref() else {
+            return;
+        };
+        for (extension, contents) in [("json", json), ("dot", self.to_dot(node_id))] {
+            let path = dir.join(format!("{node_id}.overlay.{extension}"));
+            if let Err(e) = std::fs::write(&path, contents) {
+                eprintln!("Failed to write {path:?}: {e}");
+            }
+        }
+    }
+}
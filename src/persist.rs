@@ -0,0 +1,74 @@
+#[cfg(test)]
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// State which must survive a node restarting is written as JSON files under
+// `MAELSTROM_STATE_DIR`, one per node and name. Without it persistence is disabled, which is the
+// usual case under Maelstrom, and loads return None.
+static STATE_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| std::env::var("MAELSTROM_STATE_DIR").ok().map(PathBuf::from));
+
+// Set by `enable_for_tests` for the calling thread only, so tests building nodes concurrently with
+// it neither read the environment too early nor write state files of their own.
+#[cfg(test)]
+thread_local! {
+    static TEST_STATE_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+fn state_dir() -> Option<PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = TEST_STATE_DIR.with(|dir| dir.borrow().clone()) {
+        return Some(dir);
+    }
+    STATE_DIR.clone()
+}
+
+fn path(node_id: &str, name: &str) -> Option<PathBuf> {
+    state_dir().map(|dir| dir.join(format!("{node_id}.{name}.json")))
+}
+
+pub fn enabled() -> bool {
+    state_dir().is_some()
+}
+
+pub fn load<T: DeserializeOwned>(node_id: &str, name: &str) -> Option<T> {
+    let path = path(node_id, name)?;
+    let contents = std::fs::read_to_string(&path).ok()?;
+    let Ok(value) = serde_json::from_str(&contents) else {
+        panic!("Corrupt state file {path:?}: {contents}");
+    };
+    eprintln!("Loaded {name} from {path:?}");
+    Some(value)
+}
+
+// Durably replace the stored value. Written to a temporary file and renamed into place so a crash
+// mid-write leaves the previous value intact rather than a truncated file.
+pub fn store<T: Serialize>(node_id: &str, name: &str, value: &T) {
+    let Some(path) = path(node_id, name) else {
+        return;
+    };
+    let tmp = path.with_extension("json.tmp");
+    let serialized = serde_json::to_vec(value).unwrap();
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            std::io::Write::write_all(&mut file, &serialized)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, &path));
+    if let Err(e) = result {
+        panic!("Failed to store {path:?}: {e}");
+    }
+}
+
+// Turn persistence on for the calling test, under a directory of this process's own. Every test
+// calling this gets the same directory, so tests must use node ids of their own.
+#[cfg(test)]
+pub(crate) fn enable_for_tests() {
+    let dir = std::env::temp_dir().join(format!("maelstrom-tests-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    TEST_STATE_DIR.with(|state_dir| *state_dir.borrow_mut() = Some(dir));
+}
@@ -0,0 +1,414 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+// An ordered map whose clones share structure, so taking a snapshot of one is O(1) rather than a
+// deep copy. It's an AVL tree of reference counted nodes: an update copies only the nodes on the
+// path to the key, and only those still shared with another clone (see `Arc::make_mut`), so a map
+// which has never been cloned is updated in place like any other tree.
+pub struct PersistentMap<K, V> {
+    root: Tree<K, V>,
+    len: usize,
+}
+
+type Tree<K, V> = Option<Arc<Node<K, V>>>;
+
+#[derive(Clone)]
+struct Node<K, V> {
+    key: K,
+    value: V,
+    height: u8,
+    left: Tree<K, V>,
+    right: Tree<K, V>,
+}
+
+fn height<K, V>(tree: &Tree<K, V>) -> u8 {
+    tree.as_ref().map_or(0, |node| node.height)
+}
+
+impl<K, V> Node<K, V> {
+    fn update_height(&mut self) {
+        self.height = 1 + height(&self.left).max(height(&self.right));
+    }
+}
+
+impl<K, V> Clone for PersistentMap<K, V> {
+    fn clone(&self) -> Self {
+        Self { root: self.root.clone(), len: self.len }
+    }
+}
+
+impl<K, V> Default for PersistentMap<K, V> {
+    fn default() -> Self {
+        Self { root: None, len: 0 }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut tree = &self.root;
+        while let Some(node) = tree {
+            match key.cmp(&node.key) {
+                Ordering::Less => tree = &node.left,
+                Ordering::Greater => tree = &node.right,
+                Ordering::Equal => return Some(&node.value),
+            }
+        }
+        None
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Copies the nodes on the way to `key` which are shared with another clone, even if `key`
+    // turns out not to be there.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut tree = &mut self.root;
+        loop {
+            let node = Arc::make_mut(tree.as_mut()?);
+            match key.cmp(&node.key) {
+                Ordering::Less => tree = &mut node.left,
+                Ordering::Greater => tree = &mut node.right,
+                Ordering::Equal => return Some(&mut node.value),
+            }
+        }
+    }
+
+    // Returns the value `key` previously had, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = insert(&mut self.root, key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let old = remove(&mut self.root, key);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    // In key order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        iter.push_left(&self.root);
+        iter
+    }
+
+    // In key order, from the first key at or after `from`.
+    pub fn iter_from(&self, from: &K) -> Iter<'_, K, V> {
+        let mut iter = Iter { stack: Vec::new() };
+        let mut tree = &self.root;
+        while let Some(node) = tree {
+            if node.key < *from {
+                tree = &node.right;
+            } else {
+                iter.stack.push(node);
+                tree = &node.left;
+            }
+        }
+        iter
+    }
+}
+
+fn insert<K: Ord + Clone, V: Clone>(tree: &mut Tree<K, V>, key: K, value: V) -> Option<V> {
+    let Some(node) = tree else {
+        *tree = Some(Arc::new(Node { key, value, height: 1, left: None, right: None }));
+        return None;
+    };
+    let node = Arc::make_mut(node);
+    let old = match key.cmp(&node.key) {
+        Ordering::Less => insert(&mut node.left, key, value),
+        Ordering::Greater => insert(&mut node.right, key, value),
+        Ordering::Equal => return Some(std::mem::replace(&mut node.value, value)),
+    };
+    rebalance(tree);
+    old
+}
+
+fn remove<K: Ord + Clone, V: Clone>(tree: &mut Tree<K, V>, key: &K) -> Option<V> {
+    let node = Arc::make_mut(tree.as_mut()?);
+    let old = match key.cmp(&node.key) {
+        Ordering::Less => remove(&mut node.left, key),
+        Ordering::Greater => remove(&mut node.right, key),
+        Ordering::Equal if node.right.is_some() => {
+            // Replace the node's entry with the next one in order.
+            let (key, value) = remove_min(&mut node.right);
+            node.key = key;
+            Some(std::mem::replace(&mut node.value, value))
+        }
+        Ordering::Equal => {
+            let left = node.left.take();
+            let (_, value) = into_entry(std::mem::replace(tree, left));
+            return Some(value);
+        }
+    };
+    rebalance(tree);
+    old
+}
+
+// Remove the smallest entry of `tree`, which must not be empty.
+fn remove_min<K: Clone, V: Clone>(tree: &mut Tree<K, V>) -> (K, V) {
+    let Some(node) = tree else {
+        panic!("No entries to remove");
+    };
+    let node = Arc::make_mut(node);
+    if node.left.is_some() {
+        let min = remove_min(&mut node.left);
+        rebalance(tree);
+        return min;
+    }
+    let right = node.right.take();
+    into_entry(std::mem::replace(tree, right))
+}
+
+// The entry of a node which `Arc::make_mut` has made unique.
+fn into_entry<K: Clone, V: Clone>(tree: Tree<K, V>) -> (K, V) {
+    let Some(node) = tree else {
+        panic!("No node");
+    };
+    let node = Arc::unwrap_or_clone(node);
+    (node.key, node.value)
+}
+
+// Restore the AVL invariant, that the heights of a node's subtrees differ by at most one, at the
+// root of `tree` after one of its subtrees grew or shrank by one.
+fn rebalance<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    let Some(node) = tree else {
+        return;
+    };
+    let node = Arc::make_mut(node);
+    node.update_height();
+    let balance = height(&node.left) as i32 - height(&node.right) as i32;
+    if balance > 1 {
+        if let Some(left) = &node.left {
+            if height(&left.left) < height(&left.right) {
+                rotate_left(&mut node.left);
+            }
+        }
+        rotate_right(tree);
+    } else if balance < -1 {
+        if let Some(right) = &node.right {
+            if height(&right.right) < height(&right.left) {
+                rotate_right(&mut node.right);
+            }
+        }
+        rotate_left(tree);
+    }
+}
+
+fn rotate_right<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    let Some(mut root) = tree.take() else {
+        panic!("Rotating an empty tree");
+    };
+    let node = Arc::make_mut(&mut root);
+    let Some(mut left) = node.left.take() else {
+        panic!("Rotating right without a left subtree");
+    };
+    let pivot = Arc::make_mut(&mut left);
+    node.left = pivot.right.take();
+    node.update_height();
+    pivot.right = Some(root);
+    pivot.update_height();
+    *tree = Some(left);
+}
+
+fn rotate_left<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    let Some(mut root) = tree.take() else {
+        panic!("Rotating an empty tree");
+    };
+    let node = Arc::make_mut(&mut root);
+    let Some(mut right) = node.right.take() else {
+        panic!("Rotating left without a right subtree");
+    };
+    let pivot = Arc::make_mut(&mut right);
+    node.right = pivot.left.take();
+    node.update_height();
+    pivot.left = Some(root);
+    pivot.update_height();
+    *tree = Some(right);
+}
+
+pub struct Iter<'a, K, V> {
+    // The nodes whose entry and right subtree are still to be visited, the next one last.
+    stack: Vec<&'a Node<K, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn push_left(&mut self, mut tree: &'a Tree<K, V>) {
+        while let Some(node) = tree {
+            self.stack.push(node);
+            tree = &node.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some((&node.key, &node.value))
+    }
+}
+
+impl<K: Ord + Clone + Serialize, V: Clone + Serialize> Serialize for PersistentMap<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len))?;
+        for (key, value) in self.iter() {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::testing::xorshift;
+
+    // Checks that keys are ordered, heights are right and the AVL invariant holds, returning the
+    // tree's height.
+    fn check_invariants<K: Ord, V>(tree: &Tree<K, V>, lo: Option<&K>, hi: Option<&K>) -> u8 {
+        let Some(node) = tree else {
+            return 0;
+        };
+        assert!(lo.is_none_or(|lo| *lo < node.key) && hi.is_none_or(|hi| node.key < *hi));
+        let left = check_invariants(&node.left, lo, Some(&node.key));
+        let right = check_invariants(&node.right, Some(&node.key), hi);
+        assert!(left.abs_diff(right) <= 1, "Unbalanced, {left} and {right}");
+        assert_eq!(node.height, 1 + left.max(right));
+        node.height
+    }
+
+    fn entries<K: Ord + Clone, V: Clone>(map: &PersistentMap<K, V>) -> Vec<(K, V)> {
+        map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    #[test]
+    fn insert_overwrite_remove() {
+        let mut map = PersistentMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.insert(2, 'b'), None);
+        assert_eq!(map.insert(1, 'a'), None);
+        assert_eq!(map.insert(2, 'c'), Some('b'));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&2), Some(&'c'));
+        *map.get_mut(&1).unwrap() = 'z';
+        assert_eq!(map.get(&1), Some(&'z'));
+        assert_eq!(map.get_mut(&3), None);
+        assert_eq!(map.remove(&2), Some('c'));
+        assert_eq!(map.remove(&2), None);
+        assert!(!map.contains_key(&2));
+        assert_eq!(entries(&map), [(1, 'z')]);
+    }
+
+    #[test]
+    fn stays_balanced() {
+        let mut map = PersistentMap::new();
+        // Ascending inserts are the worst case for an unbalanced tree.
+        for i in 0..1000 {
+            map.insert(i, ());
+        }
+        let height = check_invariants(&map.root, None, None);
+        // An AVL tree of n nodes is at most ~1.44 log2(n) high.
+        assert!(height <= 14, "Height {height}");
+        for i in (0..1000).step_by(3) {
+            map.remove(&i);
+            check_invariants(&map.root, None, None);
+        }
+    }
+
+    #[test]
+    fn iter_from_is_ordered() {
+        let mut map = PersistentMap::new();
+        for i in [50, 10, 40, 20, 30] {
+            map.insert(i, ());
+        }
+        let keys = |from| map.iter_from(&from).map(|(&k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(0), [10, 20, 30, 40, 50]);
+        assert_eq!(keys(20), [20, 30, 40, 50]);
+        assert_eq!(keys(25), [30, 40, 50]);
+        assert!(keys(51).is_empty());
+    }
+
+    #[test]
+    fn old_versions_are_unchanged() {
+        let mut map = PersistentMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        let snapshot = map.clone();
+        map.insert(1000, 0);
+        map.remove(&50);
+        *map.get_mut(&7).unwrap() = -7;
+        assert_eq!(entries(&snapshot), (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!((map.len(), map.get(&7), map.get(&50)), (100, Some(&-7), None));
+    }
+
+    #[test]
+    fn updates_copy_only_their_path() {
+        let mut map = PersistentMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        let snapshot = map.clone();
+        *map.get_mut(&0).unwrap() = -1;
+        let (old, new) = (snapshot.root.as_ref().unwrap(), map.root.as_ref().unwrap());
+        assert!(!Arc::ptr_eq(old, new));
+        assert!(Arc::ptr_eq(old.right.as_ref().unwrap(), new.right.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn matches_btree_map() {
+        for seed in 1..=200u64 {
+            let mut next = xorshift(seed);
+            let mut map = PersistentMap::new();
+            let mut expected = BTreeMap::new();
+            let mut snapshots = Vec::new();
+            for _ in 0..200 {
+                let key = next() % 64;
+                match next() % 4 {
+                    0 => assert_eq!(map.remove(&key), expected.remove(&key), "Seed {seed}"),
+                    1 => snapshots.push((map.clone(), expected.clone())),
+                    _ => assert_eq!(map.insert(key, seed), expected.insert(key, seed)),
+                }
+                assert_eq!(map.len(), expected.len(), "Seed {seed}");
+            }
+            check_invariants(&map.root, None, None);
+            snapshots.push((map, expected));
+            for (map, expected) in snapshots {
+                let expected: Vec<_> = expected.into_iter().collect();
+                assert_eq!(entries(&map), expected, "Seed {seed}");
+                let from = next() % 64;
+                let tail: Vec<_> = map.iter_from(&from).map(|(&k, &v)| (k, v)).collect();
+                assert_eq!(
+                    tail,
+                    expected.into_iter().filter(|&(k, _)| k >= from).collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+}
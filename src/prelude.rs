@@ -0,0 +1,10 @@
+// What a workload binary needs to talk to Maelstrom, for `use maelstrom_gossip_glommers::prelude::*`.
+// The building blocks of each kind of workload are in `kv`, `crdt` and `testing`.
+pub use crate::node::{is_node, MsgIdAllocator, Node};
+pub use crate::rpc::{
+    request_header, take_field, ERROR_CRASH, ERROR_KEY_DOES_NOT_EXIST, ERROR_MALFORMED_REQUEST,
+    ERROR_NOT_SUPPORTED, ERROR_PRECONDITION_FAILED, ERROR_TEMPORARILY_UNAVAILABLE, ERROR_TIMEOUT,
+    ERROR_TXN_CONFLICT,
+};
+pub use crate::runtime::{await_request, catch_panic, create_node, env_or, Outbox};
+pub use crate::workload::Workload;
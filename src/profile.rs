@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use serde_json::Value;
+
+// Named bundles of settings for the challenge targets, selected with `MAELSTROM_PROFILE`, so that
+// re-running a target doesn't mean remembering each of its knobs. A profile only supplies defaults
+// for `env_or`: a variable set in the environment still wins.
+const PROFILES: &[(&str, &[(&str, &str)])] = &[
+    // Multi-node broadcast: deliver everything, at whatever cost in messages.
+    ("3b-multi", &[("MAELSTROM_RETRY_INTERVAL_MS", "100")]),
+    // Efficient broadcast: retransmit less eagerly, and trust what neighbors are believed to have
+    // for longer, to get msgs-per-op down.
+    (
+        "3d-efficient",
+        &[
+            ("MAELSTROM_RETRY_INTERVAL_MS", "500"),
+            ("MAELSTROM_BELIEF_RESYNC_MS", "30000"),
+            ("MAELSTROM_BATCH_WINDOW_MS", "200"),
+            ("MAELSTROM_TIMER_JITTER", "0.2"),
+        ],
+    ),
+    // Broadcast with tight latency bounds: retransmit quickly and keep ticks close to schedule.
+    (
+        "3e-low-latency",
+        &[("MAELSTROM_RETRY_INTERVAL_MS", "50"), ("MAELSTROM_TIMER_JITTER", "0.05")],
+    ),
+    // Grow-only counter: converge on reads rather than waiting on replication.
+    ("4-gcounter", &[("MAELSTROM_READ_REPAIR", "true")]),
+    // Grow-only counter with small replication messages, at the cost of partition tolerance.
+    ("4-gcounter-tree", &[("MAELSTROM_GCOUNTER_TREE", "true")]),
+];
+
+static PROFILE: LazyLock<Option<&'static [(&'static str, &'static str)]>> = LazyLock::new(|| {
+    let name = std::env::var("MAELSTROM_PROFILE").ok()?;
+    let Some((_, settings)) = PROFILES.iter().find(|(profile, _)| *profile == name) else {
+        let names: Vec<_> = PROFILES.iter().map(|(profile, _)| profile).collect();
+        panic!("Unknown MAELSTROM_PROFILE={name}, expected one of {names:?}");
+    };
+    eprintln!("Using profile {name}: {settings:?}");
+    Some(settings)
+});
+
+// What this run is configured with: the crate version, the profile, and every `MAELSTROM_*`
+// setting, from the profile or the environment, which is in effect. Secrets are left out. Sent in
+// init_ok, so logs record the parameters of a run unambiguously.
+pub(crate) fn describe() -> Value {
+    let mut settings: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in PROFILE.iter().flat_map(|settings| settings.iter()) {
+        settings.insert(name.to_string(), value.to_string());
+    }
+    settings.extend(std::env::vars().filter(|(name, _)| name.starts_with("MAELSTROM_")));
+    settings.retain(|name, _| name != "MAELSTROM_PROFILE" && !name.contains("SECRET"));
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "profile": std::env::var("MAELSTROM_PROFILE").ok(),
+        "settings": settings,
+    })
+}
+
+// The selected profile's value for the variable `name`, if any.
+pub(crate) fn setting(name: &str) -> Option<&'static str> {
+    let (_, value) = PROFILE.as_ref()?.iter().find(|(setting, _)| *setting == name)?;
+    Some(value)
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+use crate::node::Node;
+use crate::rpc::{request_header, ERROR_TIMEOUT};
+use crate::{clock, deadline};
+
+// Handles requests by delegating them to another node. The request is re-sent as an internal RPC
+// with our own msg_id, and once the delegate replies we turn its reply into a reply to the
+// original client, with `in_reply_to` pointing at the client's msg_id. A request the delegate
+// doesn't answer in time, e.g. because the reply was lost, is answered with a timeout by `expire`.
+pub struct Proxy {
+    timeout: Duration,
+    // {internal msg_id: (the client's request header, when it times out)}.
+    pending: HashMap<u64, (Map<String, Value>, Instant)>,
+}
+
+impl Proxy {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, pending: HashMap::new() }
+    }
+
+    // Build the internal message forwarding `request` to `dest`. The body is copied as is, except
+    // for `msg_id` and `type`, and given a deadline, see `deadline`. The caller is responsible for
+    // sending it.
+    pub fn forward(
+        &mut self,
+        node: &Node,
+        request: &Map<String, Value>,
+        dest: &str,
+        msg_type: &str,
+    ) -> Map<String, Value> {
+        let Value::Object(request_body) = &request["body"] else {
+            panic!("Invalid request {:?}", request);
+        };
+        let mut message = node.build_message(node.node_id(), dest, msg_type);
+        let Value::Object(body) = &mut message["body"] else {
+            panic!("Invalid message {:?}", message);
+        };
+        for (k, v) in request_body.iter().filter(|(k, _)| *k != "msg_id" && *k != "type") {
+            body.insert(k.clone(), v.clone());
+        }
+
+        let internal_msg_id = body["msg_id"].as_u64().unwrap();
+        deadline::propagate(request, &mut message);
+        let expires = clock::now() + self.timeout;
+        self.pending.insert(internal_msg_id, (request_header(request), expires));
+        message
+    }
+
+    // Turn the delegate's `reply` into the reply for the client whose request it answers. The type
+    // and all other body fields (including error codes) are passed through. Returns None if the
+    // reply doesn't belong to a request we forwarded, e.g. a duplicate.
+    pub fn translate(
+        &mut self,
+        node: &Node,
+        mut reply: Map<String, Value>,
+    ) -> Option<Map<String, Value>> {
+        let mut reply_body: Map<String, Value> = crate::rpc::take_field(&mut reply, "body");
+        let in_reply_to = reply_body.remove("in_reply_to")?.as_u64()?;
+        let (header, _) = self.pending.remove(&in_reply_to)?;
+
+        let msg_type = reply_body.remove("type").unwrap();
+        let mut response = node.build_response(&header, msg_type.as_str().unwrap());
+        let Value::Object(body) = &mut response["body"] else {
+            panic!("Invalid response {:?}", response);
+        };
+        reply_body.remove("msg_id");
+        body.extend(reply_body);
+        Some(response)
+    }
+
+    // Give up on forwarded requests the delegate hasn't answered in time, returning timeout errors
+    // for their clients. Whether such a request took effect is indefinite, and a late reply to it
+    // is dropped by `translate`.
+    pub fn expire(&mut self, node: &Node) -> Vec<Map<String, Value>> {
+        let now = clock::now();
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(&msg_id, _)| msg_id)
+            .collect();
+        let text = "The request was forwarded and no reply came in time";
+        expired
+            .into_iter()
+            .filter_map(|msg_id| self.pending.remove(&msg_id))
+            .map(|(header, _)| node.build_error(&header, ERROR_TIMEOUT, text))
+            .collect()
+    }
+
+    // Number of forwarded requests still waiting on the delegate.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::runtime::Outbox;
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    fn node() -> Node {
+        Node::new(&json!("n1"), &json!(["n0", "n1"]), Outbox::spawn_writer())
+    }
+
+    fn request() -> Map<String, Value> {
+        let body = json!({"type": "txn", "msg_id": 7, "txn": [["append", 1, 2]]});
+        message(json!({"src": "c3", "dest": "n1", "body": body}))
+    }
+
+    fn reply_to(forwarded: &Map<String, Value>, body: Value) -> Map<String, Value> {
+        let mut reply = message(json!({"src": forwarded["dest"], "dest": "n1", "body": body}));
+        reply["body"]["in_reply_to"] = forwarded["body"]["msg_id"].clone();
+        reply
+    }
+
+    #[tokio::test]
+    async fn forwards_the_body_under_our_own_msg_id() {
+        let node = node();
+        let mut proxy = Proxy::new(Duration::from_secs(60));
+        let forwarded = proxy.forward(&node, &request(), "n0", "forwarded_txn");
+        assert_eq!((&forwarded["src"], &forwarded["dest"]), (&json!("n1"), &json!("n0")));
+        assert_eq!(forwarded["body"]["type"], "forwarded_txn");
+        assert_eq!(forwarded["body"]["txn"], json!([["append", 1, 2]]));
+        assert_ne!(forwarded["body"]["msg_id"], json!(null));
+        assert_eq!(proxy.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn translates_replies_for_the_client() {
+        let node = node();
+        let mut proxy = Proxy::new(Duration::from_secs(60));
+        let forwarded = proxy.forward(&node, &request(), "n0", "txn");
+        let reply = reply_to(&forwarded, json!({"type": "txn_ok", "msg_id": 40, "txn": [1]}));
+        let Some(response) = proxy.translate(&node, reply.clone()) else {
+            panic!("Reply to a forwarded request wasn't translated");
+        };
+        assert_eq!((&response["src"], &response["dest"]), (&json!("n1"), &json!("c3")));
+        assert_eq!(response["body"]["type"], "txn_ok");
+        assert_eq!(response["body"]["in_reply_to"], 7);
+        assert_eq!(response["body"]["txn"], json!([1]));
+        assert_ne!(response["body"]["msg_id"], 40);
+        assert!(proxy.is_empty());
+        // A duplicate of the reply has nothing left to answer.
+        assert!(proxy.translate(&node, reply).is_none());
+
+        let forwarded = proxy.forward(&node, &request(), "n0", "txn");
+        let error = reply_to(&forwarded, json!({"type": "error", "code": 30, "text": "Aborted"}));
+        let response = proxy.translate(&node, error).unwrap();
+        assert_eq!(
+            (&response["body"]["code"], &response["body"]["in_reply_to"]),
+            (&json!(30), &json!(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let node = node();
+        let mut waiting = Proxy::new(Duration::from_secs(60));
+        waiting.forward(&node, &request(), "n0", "txn");
+        assert!(waiting.expire(&node).is_empty());
+        assert_eq!(waiting.len(), 1);
+
+        let mut proxy = Proxy::new(Duration::ZERO);
+        let forwarded = proxy.forward(&node, &request(), "n0", "txn");
+        let errors = proxy.expire(&node);
+        let [error] = &errors[..] else {
+            panic!("Expected one timeout, got {errors:?}");
+        };
+        assert_eq!(error["dest"], "c3");
+        assert_eq!(error["body"]["type"], "error");
+        assert_eq!(error["body"]["code"], ERROR_TIMEOUT);
+        assert_eq!(error["body"]["in_reply_to"], 7);
+        assert!(proxy.is_empty());
+        // The client has had its answer, so a late reply is dropped.
+        let late = reply_to(&forwarded, json!({"type": "txn_ok", "msg_id": 41}));
+        assert!(proxy.translate(&node, late).is_none());
+    }
+}
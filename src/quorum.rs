@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::node::Node;
+use crate::{clock, metrics};
+
+// Orders a register's writes: by counter, then by the writing node, so concurrent writes with the
+// same counter are still ordered the same way everywhere.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Timestamp {
+    pub counter: u64,
+    pub writer: String,
+}
+
+// A register's value as of a write. A register which was never written is null at the zero
+// timestamp.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Versioned {
+    pub ts: Timestamp,
+    pub value: Value,
+}
+
+// The registers this node holds a replica of, which quorum rounds read and write. Keys are the
+// JSON text of the client's key, so any key Maelstrom sends can be used.
+#[derive(Default, Serialize)]
+pub struct Registers {
+    registers: HashMap<String, Versioned>,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Versioned {
+        self.registers.get(key).cloned().unwrap_or_default()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Versioned)> {
+        self.registers.iter()
+    }
+
+    // Store `versioned` if it's newer than what we hold. Returns whether it was.
+    pub fn put(&mut self, key: &str, versioned: Versioned) -> bool {
+        match self.registers.get(key) {
+            Some(current) if current.ts >= versioned.ts => false,
+            _ => {
+                self.registers.insert(key.to_owned(), versioned);
+                true
+            }
+        }
+    }
+}
+
+// The smallest number of nodes which is more than half of `n`.
+pub fn majority(n: usize) -> usize {
+    n / 2 + 1
+}
+
+// The newest of `replies`' `versioned` fields, e.g. of a round of register reads.
+pub fn newest(replies: &[Map<String, Value>]) -> Versioned {
+    let versions = replies.iter().map(|reply| {
+        let Ok(versioned) = serde_json::from_value::<Versioned>(reply["versioned"].clone()) else {
+            panic!("Invalid register reply {:?}", reply);
+        };
+        versioned
+    });
+    versions.max_by(|a, b| a.ts.cmp(&b.ts)).unwrap_or_default()
+}
+
+struct Round<S> {
+    state: S,
+    needed: usize,
+    // Bodies of the replies so far, ours included.
+    replies: Vec<Map<String, Value>>,
+    deadline: Instant,
+    msg_ids: Vec<u64>,
+}
+
+// A round that heard from enough nodes.
+pub struct Done<S> {
+    pub state: S,
+    pub replies: Vec<Map<String, Value>>,
+}
+
+// Fans a request out to every node and collects replies until enough have answered, e.g. a read
+// quorum R and a write quorum W with R + W > N, so every read round overlaps every write round and
+// sees the latest completed write. Rounds are driven by messages like the rest of the node: `start`
+// returns the requests to send and `reply` is fed every reply, returning each round once it
+// completes. `S` is whatever the caller needs to pick up where it left off, e.g. the client request
+// to answer.
+pub struct Quorum<S> {
+    timeout: Duration,
+    next_round: u64,
+    rounds: HashMap<u64, Round<S>>,
+    // {msg_id of a request we fanned out: its round}.
+    requests: HashMap<u64, u64>,
+}
+
+impl<S> Quorum<S> {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, next_round: 0, rounds: HashMap::new(), requests: HashMap::new() }
+    }
+
+    // Start a round which completes once `needed` nodes, us included, have answered a `msg_type`
+    // request with `body`. Returns the round and the requests for every peer, which the caller
+    // sends. The caller then gives our own answer with `reply_local`, which may complete the round.
+    pub fn start(
+        &mut self,
+        node: &Node,
+        msg_type: &str,
+        body: &Map<String, Value>,
+        needed: usize,
+        state: S,
+    ) -> (u64, Vec<Map<String, Value>>) {
+        let round = self.next_round;
+        self.next_round += 1;
+        let mut messages = Vec::new();
+        let mut msg_ids = Vec::new();
+        for peer in node.node_ids().iter().filter(|&n| n != node.node_id()) {
+            let mut message = node.build_message(node.node_id(), peer, msg_type);
+            for (k, v) in body {
+                message["body"][k] = v.clone();
+            }
+            let msg_id = message["body"]["msg_id"].as_u64().unwrap();
+            self.requests.insert(msg_id, round);
+            msg_ids.push(msg_id);
+            messages.push(message);
+        }
+        let deadline = clock::now() + self.timeout;
+        self.rounds.insert(round, Round { state, needed, replies: Vec::new(), deadline, msg_ids });
+        (round, messages)
+    }
+
+    // Our own answer to `round`'s request.
+    pub fn reply_local(&mut self, round: u64, body: Map<String, Value>) -> Option<Done<S>> {
+        self.add(round, body)
+    }
+
+    // A peer's answer to one of our requests. Answers to rounds which already completed or timed
+    // out are dropped. So are errors, e.g. from a peer whose handler crashed, which aren't votes:
+    // the round waits on the other peers, or times out.
+    pub fn reply(&mut self, mut reply: Map<String, Value>) -> Option<Done<S>> {
+        let body: Map<String, Value> = crate::rpc::take_field(&mut reply, "body");
+        let in_reply_to = body.get("in_reply_to")?.as_u64()?;
+        let round = self.requests.remove(&in_reply_to)?;
+        if body["type"] == "error" {
+            eprintln!("Not counting error reply in round {round}: {body:?}");
+            metrics::incr("quorum.error_replies");
+            return None;
+        }
+        self.add(round, body)
+    }
+
+    fn add(&mut self, round: u64, body: Map<String, Value>) -> Option<Done<S>> {
+        let pending = self.rounds.get_mut(&round)?;
+        pending.replies.push(body);
+        if pending.replies.len() < pending.needed {
+            return None;
+        }
+        let pending = self.rounds.remove(&round).unwrap();
+        for msg_id in &pending.msg_ids {
+            self.requests.remove(msg_id);
+        }
+        Some(Done { state: pending.state, replies: pending.replies })
+    }
+
+    // Rounds which didn't hear from enough nodes in time, e.g. because of a partition.
+    pub fn expired(&mut self) -> Vec<S> {
+        let now = clock::now();
+        let expired: Vec<_> = self
+            .rounds
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(&round, _)| round)
+            .collect();
+        let mut states = Vec::new();
+        for round in expired {
+            let pending = self.rounds.remove(&round).unwrap();
+            for msg_id in &pending.msg_ids {
+                self.requests.remove(msg_id);
+            }
+            states.push(pending.state);
+        }
+        states
+    }
+
+    // Rounds still waiting on replies.
+    pub fn len(&self) -> usize {
+        self.rounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rounds.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::runtime::Outbox;
+
+    fn node() -> Node {
+        Node::new(&json!("n0"), &json!(["n0", "n1", "n2", "n3", "n4"]), Outbox::spawn_writer())
+    }
+
+    fn body(value: Value) -> Map<String, Value> {
+        let Value::Object(body) = value else {
+            panic!("Invalid body {:?}", value);
+        };
+        body
+    }
+
+    // `request`'s recipient answering it with `body`.
+    fn answer(request: &Map<String, Value>, mut body: Map<String, Value>) -> Map<String, Value> {
+        body.insert("in_reply_to".to_owned(), request["body"]["msg_id"].clone());
+        let reply = json!({"src": request["dest"], "dest": "n0", "body": body});
+        let Value::Object(reply) = reply else {
+            panic!("Invalid reply {:?}", reply);
+        };
+        reply
+    }
+
+    fn versioned(counter: u64) -> Map<String, Value> {
+        let versioned = json!({"ts": {"counter": counter, "writer": "n1"}, "value": counter * 10});
+        body(json!({"type": "register_get_ok", "versioned": versioned}))
+    }
+
+    #[tokio::test]
+    async fn completes_once_a_majority_answers() {
+        let node = node();
+        let mut quorum = Quorum::new(Duration::from_secs(60));
+        let needed = majority(node.node_ids().len());
+        assert_eq!(needed, 3);
+        let (round, requests) = quorum.start(&node, "register_get", &Map::new(), needed, "read");
+        assert_eq!(requests.len(), 4);
+        assert!(quorum.reply_local(round, versioned(1)).is_none());
+        assert!(quorum.reply(answer(&requests[0], versioned(3))).is_none());
+        let Some(done) = quorum.reply(answer(&requests[1], versioned(2))) else {
+            panic!("A majority answered");
+        };
+        assert_eq!(done.state, "read");
+        assert_eq!(done.replies.len(), 3);
+        assert_eq!(newest(&done.replies).ts.counter, 3);
+        assert!(quorum.is_empty());
+        // Late answers to a completed round are dropped.
+        assert!(quorum.reply(answer(&requests[2], versioned(4))).is_none());
+        assert!(quorum.reply(answer(&requests[1], versioned(2))).is_none());
+    }
+
+    #[tokio::test]
+    async fn errors_are_not_votes() {
+        let node = node();
+        let mut quorum = Quorum::new(Duration::from_secs(60));
+        let (round, requests) = quorum.start(&node, "register_get", &Map::new(), 3, ());
+        assert!(quorum.reply_local(round, versioned(1)).is_none());
+        let error = body(json!({"type": "error", "code": 13, "text": "Handler crashed"}));
+        assert!(quorum.reply(answer(&requests[0], error.clone())).is_none());
+        assert!(quorum.reply(answer(&requests[1], error)).is_none());
+        assert!(quorum.reply(answer(&requests[2], versioned(2))).is_none());
+        let Some(done) = quorum.reply(answer(&requests[3], versioned(3))) else {
+            panic!("A majority answered");
+        };
+        assert!(done.replies.iter().all(|reply| reply["type"] != "error"));
+        assert_eq!(newest(&done.replies).ts.counter, 3);
+    }
+
+    #[tokio::test]
+    async fn rounds_without_a_majority_expire() {
+        let node = node();
+        let mut waiting = Quorum::new(Duration::from_secs(60));
+        waiting.start(&node, "register_get", &Map::new(), 3, "waiting");
+        assert!(waiting.expired().is_empty());
+        assert_eq!(waiting.len(), 1);
+
+        let mut quorum = Quorum::new(Duration::ZERO);
+        let (round, requests) = quorum.start(&node, "register_get", &Map::new(), 3, "read");
+        assert!(quorum.reply_local(round, versioned(1)).is_none());
+        assert_eq!(quorum.expired(), ["read"]);
+        assert!(quorum.is_empty());
+        // Answers after the round timed out are dropped.
+        assert!(quorum.reply(answer(&requests[0], versioned(2))).is_none());
+        assert!(quorum.reply(answer(&requests[1], versioned(2))).is_none());
+    }
+}
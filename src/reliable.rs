@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::runtime::env_or;
+use crate::{metrics, persist};
+
+// With `MAELSTROM_COALESCE_RETRIES`, the default, a retransmission of several messages to one peer
+// goes out as a single `retry_batch` carrying them all, which the peer's runtime unpacks into the
+// individual messages before anything else sees them. After a partition heals, that's a message per
+// peer per retry rather than one for every message held back.
+static COALESCE_RETRIES: LazyLock<bool> =
+    LazyLock::new(|| env_or("MAELSTROM_COALESCE_RETRIES", true));
+
+// Delivery of messages which must reach a peer, see `Node::send_expect_ok`. Each message to a peer
+// is numbered with a per-peer sequence number and the peer acknowledges all messages up to some
+// sequence number at once, so a lost ack is covered by the next one and retry state is just the
+// messages above the peer's watermark.
+//
+// With persistence enabled (see `persist`) both directions' state is journaled as it changes, so a
+// node which crashes and restarts resumes retrying exactly the messages that were in flight, and
+// carries on numbering and acking where it left off rather than from 0, which peers would take for
+// duplicates. That's a durable write per message sent, ack and receipt, so only with persistence.
+pub(crate) struct Reliable {
+    // Our node id, which the journal is stored under.
+    node_id: String,
+    // {peer: state}.
+    outgoing: HashMap<String, Outgoing>,
+    incoming: HashMap<String, Incoming>,
+    // When the oldest unacked message was sent, if there is one. How long it takes to get back
+    // to everything being acked is how long peers take to converge on what we've sent them.
+    unconverged_since: Option<Instant>,
+}
+
+// Messages we've sent a peer. Timings aren't journaled, as they mean nothing after a restart.
+#[derive(Default, Serialize, Deserialize)]
+struct Outgoing {
+    last_seq: u64,
+    // The peer has acked every message up to and including this one.
+    acked_through: u64,
+    // {seq: message}.
+    unacked: BTreeMap<u64, Map<String, Value>>,
+    // {seq: when first sent}, for messages which haven't been retransmitted. An ack for a
+    // retransmitted message is ambiguous about which send it answers, so it isn't an RTT sample.
+    #[serde(skip)]
+    sent_at: BTreeMap<u64, Instant>,
+}
+
+impl Outgoing {
+    // Returns the round trip time from the acked message, if it's a sample, see `sent_at`.
+    fn ack(&mut self, acked_through: u64) -> Option<Duration> {
+        let rtt = self.sent_at.get(&acked_through).map(|sent_at| crate::clock::now() - *sent_at);
+        // Acks can be reordered, so only ever move the watermark forward.
+        self.acked_through = self.acked_through.max(acked_through);
+        // Keep only the messages above the watermark, of which there are none at the very top.
+        match self.acked_through.checked_add(1) {
+            Some(above) => {
+                self.unacked = self.unacked.split_off(&above);
+                self.sent_at = self.sent_at.split_off(&above);
+            }
+            None => {
+                self.unacked.clear();
+                self.sent_at.clear();
+            }
+        }
+        rtt
+    }
+}
+
+// Messages we've received from a peer.
+#[derive(Default, Serialize, Deserialize)]
+struct Incoming {
+    // Every message up to and including `acked_through` has been received.
+    acked_through: u64,
+    // Received messages above `acked_through`.
+    out_of_order: BTreeSet<u64>,
+}
+
+impl Incoming {
+    fn receive(&mut self, seq: u64) {
+        if seq > self.acked_through {
+            self.out_of_order.insert(seq);
+        }
+        while self.out_of_order.remove(&(self.acked_through + 1)) {
+            self.acked_through += 1;
+        }
+    }
+
+    // Messages we know were sent, because a later one arrived, but haven't received.
+    fn missing(&self) -> Vec<u64> {
+        let Some(&highest) = self.out_of_order.last() else {
+            return Vec::new();
+        };
+        (self.acked_through + 1..highest).filter(|seq| !self.out_of_order.contains(seq)).collect()
+    }
+}
+
+impl Reliable {
+    // Our state from before a restart, if it was journaled, or else none. Any messages still
+    // unacked are resent on the next retransmission.
+    pub(crate) fn load(node_id: &str) -> Self {
+        let (outgoing, incoming): (HashMap<String, Outgoing>, _) =
+            persist::load(node_id, "reliable").unwrap_or_default();
+        let unacked = outgoing.values().any(|outgoing| !outgoing.unacked.is_empty());
+        Self {
+            node_id: node_id.to_owned(),
+            outgoing,
+            incoming,
+            unconverged_since: unacked.then(crate::clock::now),
+        }
+    }
+
+    fn journal(&self) {
+        if persist::enabled() {
+            persist::store(&self.node_id, "reliable", &(&self.outgoing, &self.incoming));
+        }
+    }
+
+    // Number `message` and hold on to it until it's acked.
+    pub(crate) fn register(&mut self, dest: &str, message: &mut Map<String, Value>) {
+        let outgoing = self.outgoing.entry(dest.to_owned()).or_default();
+        outgoing.last_seq += 1;
+        message["body"]["seq"] = serde_json::json!(outgoing.last_seq);
+        outgoing.unacked.insert(outgoing.last_seq, message.clone());
+        outgoing.sent_at.insert(outgoing.last_seq, crate::clock::now());
+        self.unconverged_since.get_or_insert_with(crate::clock::now);
+        let unacked = self.outgoing.values().map(|outgoing| outgoing.unacked.len()).sum::<usize>();
+        metrics::max("reliable.max_unacked", unacked as u64);
+        self.journal();
+    }
+
+    // Record an ack from `src`, returning the messages it asked to have resent, and the round trip
+    // time it measured, if any.
+    pub(crate) fn acked(
+        &mut self,
+        src: &str,
+        acked_through: u64,
+        missing: &[u64],
+    ) -> (Vec<Map<String, Value>>, Option<Duration>) {
+        let outgoing = self.outgoing.entry(src.to_owned()).or_default();
+        let before = outgoing.acked_through;
+        let rtt = outgoing.ack(acked_through);
+        let advanced = outgoing.acked_through > before;
+        eprintln!("{src} acked through {acked_through}, {} unacked", outgoing.unacked.len());
+        metrics::set(&format!("reliable.acked_through.{src}"), outgoing.acked_through);
+        let mut resend = Vec::new();
+        for seq in missing {
+            if let Some(message) = outgoing.unacked.get(seq) {
+                outgoing.sent_at.remove(seq);
+                resend.push(message.clone());
+            }
+        }
+        metrics::add("reliable.retransmits", resend.len() as u64);
+        if self.converged() {
+            if let Some(since) = self.unconverged_since.take() {
+                let took = (crate::clock::now() - since).as_micros() as u64;
+                metrics::max("reliable.max_convergence_us", took);
+            }
+        }
+        if advanced {
+            self.journal();
+        }
+        (resend, rtt)
+    }
+
+    // {peer: the seq it has acked every message through}, for every peer we've sent to.
+    pub(crate) fn acked_through(&self) -> BTreeMap<String, u64> {
+        self.outgoing
+            .iter()
+            .map(|(peer, outgoing)| (peer.clone(), outgoing.acked_through))
+            .collect()
+    }
+
+    // Whether every message sent has been acked.
+    pub(crate) fn converged(&self) -> bool {
+        self.outgoing.values().all(|outgoing| outgoing.unacked.is_empty())
+    }
+
+    // Every message which hasn't been acked, to be resent.
+    pub(crate) fn unacked(&mut self) -> Vec<Map<String, Value>> {
+        let mut resend = Vec::new();
+        for outgoing in self.outgoing.values_mut() {
+            outgoing.sent_at.clear();
+            resend.extend(outgoing.unacked.values().cloned());
+        }
+        metrics::add("reliable.retransmits", resend.len() as u64);
+        resend
+    }
+
+    // Record receipt of message `seq` from `src`.
+    pub(crate) fn received(&mut self, src: &str, seq: u64) {
+        self.incoming.entry(src.to_owned()).or_default().receive(seq);
+        self.journal();
+    }
+
+    // The watermark of what we've received from `src`, and any gaps below the highest message
+    // received.
+    pub(crate) fn watermark(&self, src: &str) -> (u64, Vec<u64>) {
+        match self.incoming.get(src) {
+            Some(incoming) => (incoming.acked_through, incoming.missing()),
+            None => (0, Vec::new()),
+        }
+    }
+}
+
+// `messages`, with those to each peer combined into a `retry_batch` if there's more than one. See
+// `COALESCE_RETRIES`.
+pub(crate) fn coalesce(messages: Vec<Map<String, Value>>) -> Vec<Map<String, Value>> {
+    if !*COALESCE_RETRIES {
+        return messages;
+    }
+    // {dest: (src, bodies)}.
+    let mut by_dest: BTreeMap<String, (Value, Vec<Value>)> = BTreeMap::new();
+    for mut message in messages {
+        let Some(dest) = message["dest"].as_str().map(str::to_owned) else {
+            panic!("Invalid message {:?}", message);
+        };
+        let (_, bodies) =
+            by_dest.entry(dest).or_insert_with(|| (message["src"].take(), Vec::new()));
+        bodies.push(message["body"].take());
+    }
+    let mut coalesced = Vec::new();
+    for (dest, (src, mut bodies)) in by_dest {
+        let body = match bodies.len() {
+            1 => bodies.pop().unwrap(),
+            len => {
+                metrics::add("reliable.coalesced", len as u64);
+                serde_json::json!({ "type": "retry_batch", "messages": bodies })
+            }
+        };
+        let message = serde_json::json!({ "src": src, "dest": dest, "body": body });
+        let Value::Object(message) = message else {
+            panic!("Invalid message {:?}", message);
+        };
+        coalesced.push(message);
+    }
+    coalesced
+}
+
+// The messages carried by `batch`, a `retry_batch`, each addressed as the batch was.
+pub(crate) fn unpack(mut batch: Map<String, Value>) -> Vec<Map<String, Value>> {
+    let mut body: Map<String, Value> = crate::rpc::take_field(&mut batch, "body");
+    let bodies: Vec<Value> = crate::rpc::take_field(&mut body, "messages");
+    let unpacked = bodies.into_iter().map(|body| {
+        let mut message = batch.clone();
+        message.insert("body".to_owned(), body);
+        message
+    });
+    unpacked.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    fn send(reliable: &mut Reliable, dest: &str, n: u64) {
+        let mut message = message(json!({"src": "n0", "dest": dest, "body": {"n": n}}));
+        reliable.register(dest, &mut message);
+    }
+
+    fn seqs(messages: &[Map<String, Value>]) -> Vec<u64> {
+        messages.iter().map(|message| message["body"]["seq"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn acks_are_cumulative() {
+        let mut reliable = Reliable::load("n0");
+        for n in 1..=5 {
+            send(&mut reliable, "n1", n);
+        }
+        let (resend, _) = reliable.acked("n1", 3, &[]);
+        assert!(resend.is_empty());
+        assert_eq!(seqs(&reliable.unacked()), [4, 5]);
+        // A reordered, older ack doesn't bring anything back.
+        reliable.acked("n1", 1, &[]);
+        assert_eq!(reliable.acked_through()["n1"], 3);
+        assert_eq!(seqs(&reliable.unacked()), [4, 5]);
+        reliable.acked("n1", 5, &[]);
+        assert!(reliable.converged());
+    }
+
+    #[test]
+    fn acking_the_highest_seq_clears_everything() {
+        let mut outgoing = Outgoing::default();
+        outgoing.unacked.insert(u64::MAX, Map::new());
+        outgoing.sent_at.insert(u64::MAX, crate::clock::now());
+        outgoing.ack(u64::MAX);
+        assert!(outgoing.unacked.is_empty());
+        assert!(outgoing.sent_at.is_empty());
+    }
+
+    #[test]
+    fn gaps_are_reported_and_resent() {
+        let mut reliable = Reliable::load("n0");
+        for seq in [1, 2, 4, 6] {
+            reliable.received("n1", seq);
+        }
+        assert_eq!(reliable.watermark("n1"), (2, vec![3, 5]));
+        reliable.received("n1", 3);
+        assert_eq!(reliable.watermark("n1"), (4, vec![5]));
+        assert_eq!(reliable.watermark("n2"), (0, vec![]));
+
+        let mut sender = Reliable::load("n1");
+        for n in 1..=6 {
+            send(&mut sender, "n0", n);
+        }
+        let (resend, _) = sender.acked("n0", 4, &[5]);
+        assert_eq!(seqs(&resend), [5]);
+        // Already acked, so there's nothing to resend.
+        let (resend, _) = sender.acked("n0", 4, &[2]);
+        assert!(resend.is_empty());
+    }
+
+    #[test]
+    fn retry_batches_unpack_into_the_messages_coalesced() {
+        let messages: Vec<_> = [("n1", 1), ("n2", 2), ("n1", 3)]
+            .into_iter()
+            .map(|(dest, n)| message(json!({"src": "n0", "dest": dest, "body": {"n": n}})))
+            .collect();
+        let coalesced = coalesce(messages.clone());
+        assert_eq!(coalesced.len(), 2);
+        assert_eq!(coalesced[0]["body"]["type"], "retry_batch");
+        // A lone message to a peer goes out as it is.
+        assert_eq!(coalesced[1], messages[1]);
+
+        let unpacked = unpack(coalesced[0].clone());
+        assert_eq!(unpacked, [messages[0].clone(), messages[2].clone()]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::runtime::env_or;
+use crate::{clock, metrics};
+
+// With `MAELSTROM_REPLAY_WINDOW_MS`, a request whose (src, msg_id) was already received within
+// about that long is dropped before it reaches a handler, e.g. one a network replayed. Seen ids are
+// kept in two generations, the current one and the one before, and rotated once the current one is
+// a window old, so memory is bounded by two windows' worth of traffic however long the run. An id
+// is remembered for between one and two windows. 0, the default, disables it, as a node which
+// restarts without persistence numbers its messages from scratch and would be dropped.
+static WINDOW: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_REPLAY_WINDOW_MS", 0)));
+
+struct Generations {
+    current: HashSet<(String, u64)>,
+    previous: HashSet<(String, u64)>,
+    rotated_at: Instant,
+}
+
+static SEEN: LazyLock<Mutex<Generations>> = LazyLock::new(|| {
+    Mutex::new(Generations {
+        current: HashSet::new(),
+        previous: HashSet::new(),
+        rotated_at: clock::now(),
+    })
+});
+
+// Whether `request` is a replay to drop, remembering it otherwise.
+pub fn is_replay(request: &Map<String, Value>) -> bool {
+    if WINDOW.is_zero() {
+        return false;
+    }
+    // `Reliable` numbers its messages and deduplicates them itself, and retransmits them, including
+    // in a `retry_batch`, with their original msg_id. Dropping a retransmission here would leave it
+    // unacked, so the sender would keep retrying until the window lapsed.
+    if request["body"].get("seq").is_some() {
+        return false;
+    }
+    let (Some(src), Some(msg_id)) = (request["src"].as_str(), request["body"]["msg_id"].as_u64())
+    else {
+        return false;
+    };
+    let mut seen = SEEN.lock();
+    let now = clock::now();
+    if now - seen.rotated_at >= *WINDOW {
+        seen.previous = std::mem::take(&mut seen.current);
+        seen.rotated_at = now;
+        metrics::incr("replay.rotations");
+    }
+    let id = (src.to_owned(), msg_id);
+    if seen.previous.contains(&id) || !seen.current.insert(id) {
+        metrics::incr("replay.suppressed");
+        metrics::incr("duplicates_suppressed");
+        return true;
+    }
+    metrics::max("replay.max_remembered", (seen.current.len() + seen.previous.len()) as u64);
+    false
+}
+
+// Turn the window on for this test process. Every test calling this gets the same window, as it's
+// only read once.
+#[cfg(test)]
+pub(crate) fn enable_for_tests() {
+    std::env::set_var("MAELSTROM_REPLAY_WINDOW_MS", "60000");
+    assert_eq!(*WINDOW, Duration::from_secs(60));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(value: Value) -> Map<String, Value> {
+        let Value::Object(message) = value else {
+            panic!("Invalid message {:?}", value);
+        };
+        message
+    }
+
+    #[test]
+    fn repeated_msg_id_is_a_replay() {
+        enable_for_tests();
+        let request = message(json!({"src": "c-replay", "body": {"type": "add", "msg_id": 1}}));
+        assert!(!is_replay(&request));
+        assert!(is_replay(&request));
+        let other = message(json!({"src": "c-replay", "body": {"type": "add", "msg_id": 2}}));
+        assert!(!is_replay(&other));
+        // The same msg_id from another src is a different request.
+        let elsewhere = message(json!({"src": "c-other", "body": {"type": "add", "msg_id": 1}}));
+        assert!(!is_replay(&elsewhere));
+    }
+
+    #[test]
+    fn reliable_retransmissions_are_not_replays() {
+        enable_for_tests();
+        let gossip =
+            message(json!({"src": "n-replay", "body": {"type": "gossip", "msg_id": 7, "seq": 3}}));
+        assert!(!is_replay(&gossip));
+        assert!(!is_replay(&gossip));
+    }
+
+    #[test]
+    fn messages_without_msg_id_are_not_replays() {
+        enable_for_tests();
+        let request = message(json!({"src": "c-no-id", "body": {"type": "add"}}));
+        assert!(!is_replay(&request));
+        assert!(!is_replay(&request));
+    }
+}
@@ -0,0 +1,46 @@
+use serde_json::{Map, Value};
+
+// Maelstrom error codes. https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+pub const ERROR_TIMEOUT: u64 = 0;
+pub const ERROR_NOT_SUPPORTED: u64 = 10;
+pub const ERROR_TEMPORARILY_UNAVAILABLE: u64 = 11;
+pub const ERROR_MALFORMED_REQUEST: u64 = 12;
+pub const ERROR_CRASH: u64 = 13;
+pub const ERROR_KEY_DOES_NOT_EXIST: u64 = 20;
+pub const ERROR_PRECONDITION_FAILED: u64 = 22;
+pub const ERROR_TXN_CONFLICT: u64 = 30;
+
+// Enough of `request` to reply to it (src, dest, msg_id and type), and cheap to hold on to while the
+// request itself is moved into a handler.
+pub fn request_header(request: &Map<String, Value>) -> Map<String, Value> {
+    let header = serde_json::json!({
+        "src": request["src"],
+        "dest": request["dest"],
+        "body": { "msg_id": request["body"]["msg_id"], "type": request["body"]["type"] }
+    });
+    let Value::Object(mut header) = header else {
+        panic!("Invalid header {:?}", header);
+    };
+    // Kept so that a reply is never answered, see `is_reply`.
+    if let Some(in_reply_to) = request["body"].get("in_reply_to") {
+        header["body"]["in_reply_to"] = in_reply_to.clone();
+    }
+    header
+}
+
+// Whether `message` answers one of ours. Replies, errors included, must never be answered, or two
+// nodes which don't understand each other's replies would send errors back and forth forever.
+pub fn is_reply(message: &Map<String, Value>) -> bool {
+    message["body"].get("in_reply_to").is_some()
+}
+
+// Useful for moving fields instead of copying them.
+pub fn take_field<T>(input: &mut Map<String, Value>, name: &str) -> T
+where
+    T: serde::de::DeserializeOwned,
+{
+    let serde_json::map::Entry::Occupied(entry) = input.entry(name) else {
+        panic!("Invalid field removal {:?}", input)
+    };
+    serde_json::from_value(entry.remove()).unwrap()
+}
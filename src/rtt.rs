@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+// Smoothed round trip times to peers, for preferring nearby peers, see `Node::nearby_peers`.
+// Samples come from acks: `Reliable`'s, and those of exchanges a workload times with
+// `Node::await_ack` and `Node::ack_received`, such as deltas and their `delta_ok`.
+#[derive(Default)]
+pub(crate) struct Rtts {
+    // {peer: smoothed RTT}.
+    rtts: HashMap<String, Duration>,
+    // {peer: (the ack token we're waiting for, when it was first sent)}. The time is None once
+    // it's been sent again, as an ack to a resend is ambiguous about which send it answers.
+    awaiting: HashMap<String, (u64, Option<Instant>)>,
+}
+
+impl Rtts {
+    pub(crate) fn observe(&mut self, peer: &str, sample: Duration) {
+        // Exponentially weighted, as TCP does, so one slow ack doesn't swing the estimate.
+        let rtt = self
+            .rtts
+            .entry(peer.to_owned())
+            .and_modify(|rtt| *rtt = (*rtt * 7 + sample) / 8)
+            .or_insert(sample);
+        metrics::set(&format!("rtt_us.{peer}"), rtt.as_micros() as u64);
+    }
+
+    pub(crate) fn await_ack(&mut self, peer: &str, token: u64) {
+        match self.awaiting.get_mut(peer) {
+            Some((awaiting, sent_at)) if *awaiting == token => *sent_at = None,
+            _ => {
+                self.awaiting.insert(peer.to_owned(), (token, Some(crate::clock::now())));
+            }
+        }
+    }
+
+    pub(crate) fn ack_received(&mut self, peer: &str, token: u64) {
+        let Some(&(awaiting, sent_at)) = self.awaiting.get(peer) else {
+            return;
+        };
+        if awaiting != token {
+            return;
+        }
+        self.awaiting.remove(peer);
+        if let Some(sent_at) = sent_at {
+            self.observe(peer, crate::clock::now() - sent_at);
+        }
+    }
+
+    pub(crate) fn get(&self, peer: &str) -> Option<Duration> {
+        self.rtts.get(peer).copied()
+    }
+
+    // How strongly to prefer each of `peers`: the inverse of its RTT, so a peer twice as far is
+    // picked half as often. A peer we have no sample for gets the average weight of those we do,
+    // so it's still tried and gets measured.
+    pub(crate) fn weights(&self, peers: &[&str]) -> Vec<f64> {
+        let weight = |rtt: Duration| 1.0 / rtt.as_secs_f64().max(1e-6);
+        let sampled: Vec<f64> = peers.iter().filter_map(|&p| self.get(p)).map(weight).collect();
+        let unsampled = match sampled.len() {
+            0 => 1.0,
+            n => sampled.iter().sum::<f64>() / n as f64,
+        };
+        peers.iter().map(|&p| self.get(p).map_or(unsampled, weight)).collect()
+    }
+}
+
+// The index into `weights` that `random`, uniform in [0, 1), lands on when each index gets a share
+// of the range proportional to its weight.
+pub(crate) fn pick(weights: &[f64], random: f64) -> usize {
+    let mut target = random * weights.iter().sum::<f64>();
+    for (i, &weight) in weights.iter().enumerate() {
+        if target < weight {
+            return i;
+        }
+        target -= weight;
+    }
+    // Only reachable through rounding.
+    weights.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearer_peers_are_picked_more_often() {
+        let mut rtts = Rtts::default();
+        rtts.observe("n1", Duration::from_millis(1));
+        rtts.observe("n2", Duration::from_millis(4));
+        let weights = rtts.weights(&["n1", "n2", "n3"]);
+        // n3 is unsampled, so gets the average of 1000 and 250.
+        assert_eq!(weights.iter().map(|w| w.round() as u64).collect::<Vec<_>>(), [1000, 250, 625]);
+
+        let mut picked = [0; 3];
+        for i in 0..1875 {
+            picked[pick(&weights, (i as f64 + 0.5) / 1875.0)] += 1;
+        }
+        assert_eq!(picked, [1000, 250, 625]);
+    }
+
+    #[test]
+    fn unsampled_peers_are_picked_uniformly() {
+        let weights = Rtts::default().weights(&["n1", "n2"]);
+        assert_eq!(weights, [1.0, 1.0]);
+        assert_eq!(pick(&weights, 0.25), 0);
+        assert_eq!(pick(&weights, 0.75), 1);
+    }
+
+    #[test]
+    fn acks_to_resends_are_not_samples() {
+        let mut rtts = Rtts::default();
+        rtts.await_ack("n1", 5);
+        rtts.ack_received("n1", 4);
+        assert_eq!(rtts.get("n1"), None);
+        rtts.ack_received("n1", 5);
+        assert!(rtts.get("n1").is_some());
+
+        rtts.await_ack("n2", 5);
+        rtts.await_ack("n2", 5);
+        rtts.ack_received("n2", 5);
+        assert_eq!(rtts.get("n2"), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::panic;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::node::Node;
+use crate::{
+    audit, auth, chaos, clock, events, flow, fragment, metrics, profile, source, trace, watchdog,
+};
+
+// Whether a message is logged in full, for workloads logging details of it alongside, see `trace`.
+pub use crate::trace::sampled as traced;
+
+// Messages a handler wants sent are committed to the outbox as a single batch, which a writer task
+// drains to stdout. Handlers produce their state change and the messages describing it together,
+// and the batch is committed while the node's lock is still held, so a client is never acked
+// ahead of the state change it acknowledges (or the reverse), and batches from concurrent
+// handlers reach stdout in the same order as the state changes which produced them.
+enum Batch {
+    Messages(Vec<Map<String, Value>>),
+    // Signalled once every batch committed before it has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Clone)]
+pub struct Outbox {
+    sender: mpsc::UnboundedSender<Batch>,
+}
+
+impl Outbox {
+    // Spawn the writer task. Must be called from within the tokio runtime.
+    pub fn spawn_writer() -> Outbox {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Batch>();
+        tokio::spawn(async move {
+            loop {
+                // Messages held back by flow control are written once there's credit for them, and
+                // those delayed by `chaos` once they're due.
+                let wait =
+                    [flow::until_release(), chaos::until_release()].into_iter().flatten().min();
+                let batch = match wait {
+                    Some(wait) => tokio::select! {
+                        batch = receiver.recv() => batch,
+                        _ = clock::sleep(wait) => Some(Batch::Messages(Vec::new())),
+                    },
+                    None => receiver.recv().await,
+                };
+                let Some(batch) = batch else {
+                    break;
+                };
+                let mut messages = flow::release();
+                match batch {
+                    Batch::Messages(batch) => {
+                        messages.extend(batch.into_iter().filter_map(flow::admit))
+                    }
+                    Batch::Flush(done) => {
+                        messages.extend(flow::drain());
+                        let mut messages = chaos::inject(messages);
+                        messages.extend(chaos::drain());
+                        Self::write(messages);
+                        let _ = done.send(());
+                        continue;
+                    }
+                }
+                Self::write(chaos::inject(messages));
+            }
+        });
+        Outbox { sender }
+    }
+
+    fn write(messages: Vec<Map<String, Value>>) {
+        let mut stdout = std::io::stdout().lock();
+        for mut message in messages {
+            if !watchdog::replied(&message) {
+                continue;
+            }
+            audit::check(&message);
+            auth::stamp(&mut message);
+            let serialized = serde_json::to_string(&message).unwrap();
+            events::record(events::Direction::Send, &message, &serialized);
+            metrics::incr("outbox.messages");
+            if let Some(msg_type) = message["body"]["type"].as_str() {
+                metrics::incr(&format!("sent.{msg_type}"));
+            }
+            metrics::add("outbox.bytes", serialized.len() as u64);
+            metrics::max("outbox.max_message_bytes", serialized.len() as u64);
+            for mut message in fragment::fragment(message, &serialized) {
+                auth::stamp(&mut message);
+                let serialized = serde_json::to_string(&message).unwrap();
+                writeln!(stdout, "{}", serialized).unwrap();
+            }
+        }
+    }
+
+    pub fn commit(&self, messages: Vec<Map<String, Value>>) {
+        if messages.is_empty() {
+            return;
+        }
+        let Ok(_) = self.sender.send(Batch::Messages(messages)) else {
+            panic!("Writer task is gone");
+        };
+    }
+
+    // Wait until everything committed so far has been written, e.g. before exiting.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        let Ok(_) = self.sender.send(Batch::Flush(done)) else {
+            panic!("Writer task is gone");
+        };
+        let _ = flushed.await;
+    }
+}
+
+// Read a config value from the environment, falling back to the `MAELSTROM_PROFILE`'s value and
+// then `default` if unset. See `profile`.
+pub fn env_or<T>(name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Debug,
+{
+    let value = match std::env::var(name) {
+        Ok(value) => value,
+        Err(_) => match profile::setting(name) {
+            Some(value) => value.to_owned(),
+            None => return default,
+        },
+    };
+    value.parse().unwrap_or_else(|e| panic!("Invalid {name}={value}: {e:?}"))
+}
+
+// Run `handler`, returning the panic message if it panics. A panic inside a spawned task is
+// otherwise swallowed by tokio, leaving the client to time out without us ever replying. Locks
+// held by the handler are parking_lot locks, which are released on unwind without poisoning, so the
+// node keeps serving. Its state may be left half updated, which is what the crash code tells the
+// client.
+pub fn catch_panic(handler: impl FnOnce()) -> Result<(), String> {
+    panic::catch_unwind(panic::AssertUnwindSafe(handler)).map_err(|payload| {
+        match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+            (Some(text), _) => text.to_string(),
+            (_, Some(text)) => text.clone(),
+            _ => "Unknown panic".to_owned(),
+        }
+    })
+}
+
+// By default an unparseable line is fatal. When replaying hand-edited transcripts or piping in test
+// data it is more useful to log and skip bad lines, up to a limit after which we give up.
+static SKIP_BAD_LINES: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_SKIP_BAD_LINES", false));
+static MAX_BAD_LINES: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_MAX_BAD_LINES", 100));
+
+// Messages unpacked from a `retry_batch`, which are returned before reading any more input.
+pub(crate) static UNPACKED: LazyLock<Mutex<VecDeque<Map<String, Value>>>> =
+    LazyLock::new(Default::default);
+
+// Wait to receive a JSON message and return the parsed version, or None once stdin is closed.
+// Fragments are reassembled and retry batches unpacked here, so callers only ever see whole,
+// individual messages.
+pub async fn await_request(stdin: &async_std::io::Stdin) -> Option<Map<String, Value>> {
+    loop {
+        if let Some(request) = UNPACKED.lock().pop_front() {
+            return Some(request);
+        }
+        let mut input = String::new();
+        let Ok(num_bytes) = stdin.read_line(&mut input).await else {
+            panic!("Failed to read from stdin");
+        };
+        if num_bytes == 0 {
+            eprintln!("Stdin closed");
+            return None;
+        }
+        let Ok(mut request) = serde_json::from_str::<Map<String, Value>>(&input) else {
+            if !*SKIP_BAD_LINES {
+                panic!("Failed to parse input: {input}");
+            }
+            metrics::incr("input.bad_lines");
+            let bad_lines = metrics::get("input.bad_lines");
+            eprintln!("Skipping unparseable input ({bad_lines} so far): {input}");
+            if bad_lines > *MAX_BAD_LINES {
+                eprintln!("Exceeded MAELSTROM_MAX_BAD_LINES={}, exiting", *MAX_BAD_LINES);
+                std::process::exit(1);
+            }
+            continue;
+        };
+        if trace::sampled(&request) {
+            eprintln!("Received {}", input);
+        }
+        if !auth::verify(&mut request) {
+            continue;
+        }
+        let request = if request["body"]["type"] == "fragment" {
+            let Some(mut request) = fragment::reassemble(request) else {
+                continue;
+            };
+            // The whole message was stamped as well as each fragment.
+            if !auth::verify(&mut request) {
+                continue;
+            }
+            request
+        } else {
+            request
+        };
+        events::record_message(events::Direction::Recv, &request);
+        if request["body"]["type"] != "retry_batch" {
+            return Some(request);
+        }
+        // Only the batch carries the sender's credit.
+        let mut batch = request;
+        flow::received(&mut batch);
+        UNPACKED.lock().extend(crate::reliable::unpack(batch));
+    }
+}
+
+// Awaits an init message, builds a node based on this, responds with init_ok, and returns the node.
+// Spawns the node's writer task, so must be called from within the tokio runtime.
+pub async fn create_node(source: &mut source::Source) -> Node {
+    let Some(request) = source.recv().await else {
+        panic!("Stdin closed before init");
+    };
+    assert_eq!(request["body"]["type"], "init", "{request:?}");
+    eprintln!("Initialized node {}", request["body"]["node_id"]);
+
+    let node = Node::new(
+        &request["body"]["node_id"],
+        &request["body"]["node_ids"],
+        Outbox::spawn_writer(),
+    );
+
+    let mut response = node.build_response(&request, "init_ok");
+    response["body"]["config"] = profile::describe();
+    eprintln!("Config {}", response["body"]["config"]);
+    node.commit(vec![response]);
+    watchdog::spawn(node.clone());
+
+    node
+}
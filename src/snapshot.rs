@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::tasks::TaskRegistry;
+
+// Debug snapshots of a workload's state, so a failed Maelstrom run leaves something to inspect.
+// Written on a `dump_state` message or SIGUSR1 as JSON files under `MAELSTROM_SNAPSHOT_DIR`,
+// defaulting to the system temp dir. Unlike `persist` these are never read back.
+static SNAPSHOT_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    std::env::var("MAELSTROM_SNAPSHOT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir())
+});
+
+// Write `state` to a new timestamped file and return its path.
+pub fn dump<T: Serialize>(node_id: &str, workload: &str, state: &T) -> std::io::Result<PathBuf> {
+    let millis = crate::clock::system_time().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let path = SNAPSHOT_DIR.join(format!("{node_id}.{workload}.{millis}.json"));
+    let snapshot = serde_json::json!({
+        "node_id": node_id,
+        "workload": workload,
+        "unix_millis": millis as u64,
+        "metrics": crate::metrics::snapshot(),
+        "state": state,
+    });
+    std::fs::write(&path, serde_json::to_vec_pretty(&snapshot).unwrap())?;
+    eprintln!("Dumped state to {path:?}");
+    Ok(path)
+}
+
+// Call `dump` each time the process receives SIGUSR1. A failed dump is logged rather than taking
+// down the node, since this is only a debugging aid.
+pub fn dump_on_signal<F>(tasks: &TaskRegistry, dump: F)
+where
+    F: Fn() -> std::io::Result<PathBuf> + Send + 'static,
+{
+    tasks.spawn_background(async move {
+        let Ok(mut signals) = signal(SignalKind::user_defined1()) else {
+            panic!("Failed to install SIGUSR1 handler");
+        };
+        while signals.recv().await.is_some() {
+            if let Err(e) = dump() {
+                eprintln!("Failed to dump state: {e}");
+            }
+        }
+    });
+}
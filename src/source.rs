@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+// Where requests come from. Normally that's Maelstrom over stdin, but with `--selfdrive` a binary
+// generates its own client traffic so it can be profiled (perf, flamegraphs) without Maelstrom.
+pub enum Source {
+    Stdin(async_std::io::Stdin),
+    SelfDrive(SelfDrive),
+}
+
+impl Source {
+    // Stdin, unless the binary was run with `--selfdrive`, in which case `generate` is called
+    // with an increasing counter to produce the body of each synthetic request.
+    pub fn from_args<F>(generate: F) -> Source
+    where
+        F: FnMut(u64) -> Map<String, Value> + Send + 'static,
+    {
+        if std::env::args().any(|arg| arg == "--selfdrive") {
+            Source::SelfDrive(SelfDrive::new(Box::new(generate)))
+        } else {
+            Source::Stdin(async_std::io::stdin())
+        }
+    }
+
+    // The next request, or None once there are no more. Replays are skipped, see `replay`.
+    pub async fn recv(&mut self) -> Option<Map<String, Value>> {
+        let mut request = loop {
+            let request = match self {
+                Source::Stdin(stdin) => crate::runtime::await_request(stdin).await,
+                Source::SelfDrive(self_drive) => Some(self_drive.recv().await),
+            }?;
+            if !crate::replay::is_replay(&request) {
+                break request;
+            }
+        };
+        crate::flow::received(&mut request);
+        crate::audit::received(&request);
+        if let Some(msg_type) = request["body"]["type"].as_str() {
+            crate::metrics::incr(&format!("received.{msg_type}"));
+        }
+        crate::watchdog::expect_reply(&request);
+        Some(request)
+    }
+}
+
+// A single node cluster ("n0") with a single client ("c0") sending requests at
+// `MAELSTROM_SELFDRIVE_RATE` requests per second, or as fast as they can be handled if 0.
+pub struct SelfDrive {
+    generate: Box<dyn FnMut(u64) -> Map<String, Value> + Send>,
+    interval: Option<crate::clock::Interval>,
+    msg_id: u64,
+}
+
+impl SelfDrive {
+    fn new(generate: Box<dyn FnMut(u64) -> Map<String, Value> + Send>) -> Self {
+        let rate: u64 = crate::runtime::env_or("MAELSTROM_SELFDRIVE_RATE", 1000);
+        let interval =
+            (rate > 0).then(|| crate::clock::interval(Duration::from_secs(1) / rate as u32));
+        Self { generate, interval, msg_id: 0 }
+    }
+
+    async fn recv(&mut self) -> Map<String, Value> {
+        let msg_id = self.msg_id;
+        self.msg_id += 1;
+        let body = if msg_id == 0 {
+            serde_json::json!({ "type": "init", "node_id": "n0", "node_ids": ["n0"] })
+        } else {
+            if let Some(interval) = &mut self.interval {
+                interval.tick().await;
+            }
+            // Let handler tasks run even when unthrottled.
+            tokio::task::yield_now().await;
+            Value::Object((self.generate)(msg_id))
+        };
+
+        let mut request = serde_json::json!({ "src": "c0", "dest": "n0", "body": body });
+        request["body"]["msg_id"] = serde_json::json!(msg_id);
+        let Value::Object(request) = request else {
+            panic!("Invalid request {:?}", request);
+        };
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reliable_retransmissions_are_delivered_with_the_replay_window_on() {
+        crate::replay::enable_for_tests();
+        // As unpacked from two `retry_batch`es, after the ack of the first was lost.
+        let retransmitted = json!({
+            "src": "n-source", "dest": "n0", "body": {"type": "gossip", "msg_id": 4, "seq": 1}
+        });
+        let replayed =
+            json!({"src": "c-source", "dest": "n0", "body": {"type": "add", "msg_id": 9}});
+        let queued = [&retransmitted, &retransmitted, &replayed, &replayed];
+        let mut queued: Vec<_> =
+            queued.into_iter().map(|m| serde_json::from_value(m.clone()).unwrap()).collect();
+        // Ends the test's input, as nothing else comes through the queue.
+        let last = json!({"src": "c-source", "dest": "n0", "body": {"type": "add", "msg_id": 10}});
+        queued.push(serde_json::from_value(last.clone()).unwrap());
+        crate::runtime::UNPACKED.lock().extend(queued);
+
+        let mut source = Source::Stdin(async_std::io::stdin());
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(Value::Object(source.recv().await.unwrap()));
+        }
+        assert_eq!(received, [retransmitted.clone(), retransmitted, replayed, last]);
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::metrics;
+
+// A human readable account of the run, printed to stderr on shutdown, for when iterating on a
+// challenge's numbers. Built from the metrics, plus handler latencies which are kept here as
+// metrics only hold counters.
+
+// Enough to get the percentiles right without growing forever.
+const MAX_SAMPLES: usize = 100_000;
+
+// {msg_type: handler latencies in micros}.
+static LATENCIES: LazyLock<Mutex<BTreeMap<String, Vec<u64>>>> = LazyLock::new(Default::default);
+static STARTED: LazyLock<Instant> = LazyLock::new(crate::clock::now);
+
+// Mark the start of the run, which uptime is measured from.
+pub fn start() {
+    LazyLock::force(&STARTED);
+}
+
+// Record that handling a `msg_type` request took `latency`.
+pub fn handled(msg_type: &str, latency: Duration) {
+    let mut latencies = LATENCIES.lock();
+    let samples = latencies.entry(msg_type.to_owned()).or_default();
+    if samples.len() < MAX_SAMPLES {
+        samples.push(latency.as_micros() as u64);
+    }
+}
+
+// "type count, ..." for every metric named `{prefix}.{type}`.
+fn by_type(metrics: &BTreeMap<String, u64>, prefix: &str) -> String {
+    let counts: Vec<_> = metrics
+        .iter()
+        .filter_map(|(name, count)| Some(format!("{} {count}", name.strip_prefix(prefix)?)))
+        .collect();
+    match counts.is_empty() {
+        true => "none".to_owned(),
+        false => counts.join(", "),
+    }
+}
+
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+pub fn print() {
+    let metrics = metrics::snapshot();
+    let get = |name: &str| metrics.get(name).copied().unwrap_or(0);
+    let mut lines = vec![
+        format!("uptime: {:.1}s", (crate::clock::now() - *STARTED).as_secs_f64()),
+        format!("received: {}", by_type(&metrics, "received.")),
+        format!("sent: {}", by_type(&metrics, "sent.")),
+        format!("retransmits: {}", get("reliable.retransmits")),
+        format!("duplicates suppressed: {}", get("duplicates_suppressed")),
+        format!("max pending acks: {}", get("reliable.max_unacked")),
+        format!("max convergence time: {}ms", get("reliable.max_convergence_us") / 1000),
+    ];
+    for (msg_type, latencies) in LATENCIES.lock().iter_mut() {
+        latencies.sort_unstable();
+        lines.push(format!(
+            "{msg_type} latency: p50 {}us, p90 {}us, p99 {}us, max {}us over {} requests",
+            percentile(latencies, 50),
+            percentile(latencies, 90),
+            percentile(latencies, 99),
+            latencies.last().unwrap(),
+            latencies.len(),
+        ));
+    }
+    eprintln!("Summary\n  {}", lines.join("\n  "));
+}
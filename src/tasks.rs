@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::metrics;
+
+// Owns every task a binary spawns, instead of dropping their handles on the floor, so that a
+// panicking task is noticed by the main loop and shutdown can wait for in flight work.
+pub struct TaskRegistry {
+    // Request handlers, which are run to completion at shutdown.
+    handlers: Mutex<JoinSet<()>>,
+    // Loops which run for the life of the node (replication, retries, ...), aborted at shutdown.
+    background: Mutex<JoinSet<()>>,
+    // Bounds the number of live handlers, `MAELSTROM_MAX_CONCURRENT_HANDLERS`. Handlers all contend
+    // on the node's lock, so beyond a handful more of them only costs memory during bursts.
+    handler_permits: Arc<Semaphore>,
+}
+
+impl Default for TaskRegistry {
+    fn default() -> Self {
+        let max_handlers = crate::runtime::env_or("MAELSTROM_MAX_CONCURRENT_HANDLERS", 64);
+        Self {
+            handlers: Default::default(),
+            background: Default::default(),
+            handler_permits: Arc::new(Semaphore::new(max_handlers)),
+        }
+    }
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Waits for a permit before spawning, so that when handlers can't keep up the main loop stops
+    // reading requests rather than spawning an unbounded number of tasks.
+    pub async fn spawn_handler<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let permit = match Arc::clone(&self.handler_permits).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                metrics::incr("tasks.handler_limit_waits");
+                Arc::clone(&self.handler_permits).acquire_owned().await.unwrap()
+            }
+        };
+        self.handlers.lock().spawn(async move {
+            task.await;
+            drop(permit);
+        });
+    }
+
+    pub fn spawn_background<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.background.lock().spawn(task);
+    }
+
+    // Reap finished tasks, re-raising the panic of any task which panicked. Called from the main
+    // loop so that a dead background loop takes the node down loudly rather than it limping on
+    // without, say, replication.
+    pub fn reap(&self) {
+        for tasks in [&self.handlers, &self.background] {
+            while let Some(result) = tasks.lock().try_join_next() {
+                match result {
+                    Ok(()) => (),
+                    Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                    Err(e) => eprintln!("Task failed: {e}"),
+                }
+            }
+        }
+    }
+
+    pub fn num_handlers(&self) -> usize {
+        self.handlers.lock().len()
+    }
+
+    // Wait for all handlers to finish and stop the background loops.
+    pub async fn shutdown(&self) {
+        eprintln!("Waiting on {} handlers", self.num_handlers());
+        let mut handlers = std::mem::take(&mut *self.handlers.lock());
+        while let Some(result) = handlers.join_next().await {
+            if let Err(e) = result {
+                eprintln!("Handler failed: {e}");
+            }
+        }
+        self.background.lock().abort_all();
+    }
+}
@@ -0,0 +1,26 @@
+// Checkers for the histories and final states of a run, and synthetic client traffic for
+// `--selfdrive`.
+pub use crate::workloadgen::Generator;
+
+// A xorshift generator, for randomized tests whose failures reproduce from `seed`, which mustn't
+// be 0.
+pub fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
+
+pub mod linearizability {
+    pub use crate::linearizability::*;
+}
+
+pub mod list_append {
+    pub use crate::list_append::*;
+}
+
+pub mod oracle {
+    pub use crate::oracle::*;
+}
@@ -0,0 +1,26 @@
+use std::sync::LazyLock;
+
+use serde_json::{Map, Value};
+
+use crate::node::is_node;
+use crate::runtime::env_or;
+
+// Verbose per-message logs are written for every message by default, which at high rates
+// multiplies I/O and skews latency measurements. With `MAELSTROM_TRACE_SAMPLE=N`, only about 1 in N
+// messages between nodes are logged, picked at random. Client and service traffic, and errors, are
+// always logged.
+static SAMPLE: LazyLock<u64> = LazyLock::new(|| env_or("MAELSTROM_TRACE_SAMPLE", 1));
+
+// Whether to log `message` in full.
+pub fn sampled(message: &Map<String, Value>) -> bool {
+    if *SAMPLE <= 1 {
+        return true;
+    }
+    let between_nodes = ["src", "dest"].iter().all(|k| message[*k].as_str().is_some_and(is_node));
+    if !between_nodes || message["body"]["type"] == "error" {
+        return true;
+    }
+    // RandomState is seeded randomly per instance, which is plenty for sampling.
+    let random = std::hash::BuildHasher::hash_one(&std::hash::RandomState::new(), ());
+    random.is_multiple_of(*SAMPLE)
+}
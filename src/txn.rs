@@ -0,0 +1,48 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+// A micro-op of a txn request, which Maelstrom encodes as a `[func, key, value]` triple: `["r", k,
+// null]`, `["append", k, v]` or `["w", k, v]`. A read's value is only filled in in the reply, so
+// it's ignored when parsing and serialized as null. `["rr", lo, hi]`, a read of every key in
+// `lo..=hi`, is an extension Maelstrom doesn't send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxnOp {
+    R(i64),
+    Append(i64, i64),
+    W(i64, i64),
+    RangeRead(i64, i64),
+}
+
+impl TxnOp {
+    pub fn key(&self) -> i64 {
+        match *self {
+            TxnOp::R(key) | TxnOp::Append(key, _) | TxnOp::W(key, _) => key,
+            TxnOp::RangeRead(lo, _) => lo,
+        }
+    }
+}
+
+impl Serialize for TxnOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            TxnOp::R(key) => ("r", key, None::<i64>).serialize(serializer),
+            TxnOp::Append(key, value) => ("append", key, Some(value)).serialize(serializer),
+            TxnOp::W(key, value) => ("w", key, Some(value)).serialize(serializer),
+            TxnOp::RangeRead(lo, hi) => ("rr", lo, Some(hi)).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TxnOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (func, key, value): (String, i64, Value) = Deserialize::deserialize(deserializer)?;
+        match (func.as_str(), value.as_i64()) {
+            ("r", _) => Ok(TxnOp::R(key)),
+            ("append", Some(value)) => Ok(TxnOp::Append(key, value)),
+            ("w", Some(value)) => Ok(TxnOp::W(key, value)),
+            ("rr", Some(hi)) => Ok(TxnOp::RangeRead(key, hi)),
+            _ => Err(D::Error::custom(format!("Invalid txn op [{func:?}, {key}, {value}]"))),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+// Internal payloads such as replicates carry a `version` so their format can evolve while binaries
+// built before a change still interoperate, e.g. during mixed-version experiments. Messages without
+// one predate versioning and are version 0. Each peer is sent the newest version both sides
+// understand, which is the older of ours and the last one it sent us, and a received message is
+// decoded according to its version by matching on what `received` returns.
+pub struct Versions {
+    current: u64,
+    oldest_supported: u64,
+    // {peer: version of the last message it sent us}.
+    peers: HashMap<String, u64>,
+}
+
+impl Versions {
+    pub fn new(current: u64, oldest_supported: u64) -> Self {
+        assert!(oldest_supported <= current, "Supporting {oldest_supported} to {current}");
+        Self { current, oldest_supported, peers: HashMap::new() }
+    }
+
+    // The version to decode `body`, received from `src`, as, and which `src` is then sent no newer
+    // a version than. A version newer than ours is decoded as ours, as new versions only add fields.
+    // None if it's older than we can decode, or invalid, in which case the caller drops it.
+    pub fn received(&mut self, src: &str, body: &Map<String, Value>) -> Option<u64> {
+        let version = match body.get("version") {
+            Some(version) => version.as_u64(),
+            None => Some(0),
+        };
+        let Some(version) = version.filter(|&version| version >= self.oldest_supported) else {
+            eprintln!(
+                "Dropping message at unsupported version {:?} from {src}, expected {} or newer",
+                body.get("version"),
+                self.oldest_supported
+            );
+            return None;
+        };
+        let version = version.min(self.current);
+        self.peers.insert(src.to_owned(), version);
+        Some(version)
+    }
+
+    // The version to send `peer`. Peers we haven't heard from yet get ours, which an older peer
+    // can still read so long as new versions only add fields.
+    pub fn for_peer(&self, peer: &str) -> u64 {
+        self.peers.get(peer).map_or(self.current, |&version| version.min(self.current))
+    }
+
+    // Mark `message` as being `for_peer` its dest, which is the version to encode it with.
+    pub fn stamp(&self, message: &mut Map<String, Value>) {
+        let Some(dest) = message["dest"].as_str() else {
+            panic!("Invalid message {:?}", message);
+        };
+        message["body"]["version"] = serde_json::json!(self.for_peer(dest));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(version: Option<u64>) -> Map<String, Value> {
+        let mut body = Map::new();
+        if let Some(version) = version {
+            body.insert("version".to_owned(), serde_json::json!(version));
+        }
+        body
+    }
+
+    #[test]
+    fn unversioned_is_zero() {
+        let mut versions = Versions::new(3, 0);
+        assert_eq!(versions.received("n1", &body(None)), Some(0));
+        assert_eq!(versions.for_peer("n1"), 0);
+    }
+
+    #[test]
+    fn newer_is_decoded_as_current() {
+        let mut versions = Versions::new(1, 0);
+        assert_eq!(versions.received("n1", &body(Some(3))), Some(1));
+        assert_eq!(versions.for_peer("n1"), 1);
+    }
+
+    #[test]
+    fn older_than_supported_is_dropped() {
+        let mut versions = Versions::new(3, 2);
+        assert_eq!(versions.received("n1", &body(Some(1))), None);
+        // Unheard from, as far as sending goes.
+        assert_eq!(versions.for_peer("n1"), 3);
+    }
+
+    #[test]
+    fn peers_are_sent_the_older_version() {
+        let mut versions = Versions::new(3, 0);
+        versions.received("n1", &body(Some(2)));
+        assert_eq!(versions.for_peer("n1"), 2);
+        assert_eq!(versions.for_peer("n2"), 3);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+
+use crate::metrics;
+use crate::node::Node;
+use crate::quorum;
+use crate::rpc::ERROR_TEMPORARILY_UNAVAILABLE;
+use crate::runtime::env_or;
+
+// A node which starts, or restarts, in the middle of a run knows nothing of what its peers hold, so
+// its reads would be wildly stale until replication caught it up. With `MAELSTROM_WARMUP_MS`, it
+// asks every peer for its state with a `state_request` right away, which peers answer with their
+// usual replication message, and holds off reads until every peer has answered or that long has
+// passed. 0, the default, disables it.
+static TIMEOUT: LazyLock<Duration> =
+    LazyLock::new(|| Duration::from_millis(env_or("MAELSTROM_WARMUP_MS", 0)));
+
+// With `MAELSTROM_WARMUP_QUORUM`, reads are served once a majority of the cluster, us included, has
+// answered rather than every peer, so a single unreachable peer doesn't hold them off until the
+// timeout.
+static QUORUM: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_WARMUP_QUORUM", false));
+
+// With `MAELSTROM_WARMUP_HOLD_READS`, reads during warm-up are held and answered once it's done,
+// rather than refused as temporarily unavailable. Clients see slow reads instead of errors, but it
+// can't be used with `MAELSTROM_AVAILABILITY=total`, which requires answering right away.
+static HOLD_READS: LazyLock<bool> = LazyLock::new(|| env_or("MAELSTROM_WARMUP_HOLD_READS", false));
+
+pub struct Warmup {
+    deadline: Instant,
+    // Peers we're still waiting on.
+    awaiting: HashSet<String>,
+    // How many of them may still be waiting once we're warm.
+    spare: usize,
+    // Reads waiting for warm-up, see `HOLD_READS`.
+    held: Vec<Map<String, Value>>,
+}
+
+impl Warmup {
+    // Send every peer a `state_request`, or None if warm-up is disabled.
+    pub fn start(node: &Node) -> Option<Self> {
+        if TIMEOUT.is_zero() {
+            return None;
+        }
+        let peers = node.node_ids().iter().filter(|&n| n != node.node_id());
+        let awaiting: HashSet<_> = peers.cloned().collect();
+        // The majority includes us.
+        let n = node.node_ids().len();
+        let spare = if *QUORUM { n - quorum::majority(n) } else { 0 };
+        let requests = awaiting
+            .iter()
+            .map(|peer| node.build_message(node.node_id(), peer, "state_request"))
+            .collect();
+        node.commit(requests);
+        Some(Self { deadline: crate::clock::now() + *TIMEOUT, awaiting, spare, held: Vec::new() })
+    }
+
+    // Record that `peer`'s state has been merged.
+    pub fn heard_from(&mut self, peer: &str) {
+        if self.awaiting.remove(peer) && self.awaiting.len() == self.spare {
+            eprintln!("Warmed up from enough peers");
+        }
+    }
+
+    // Whether reads can be served.
+    pub fn done(&self) -> bool {
+        self.awaiting.len() <= self.spare || crate::clock::now() >= self.deadline
+    }
+
+    // A read which arrived before we're done: held, or refused, per `HOLD_READS`. Returns the
+    // messages to send.
+    pub fn defer(&mut self, node: &Node, request: Map<String, Value>) -> Vec<Map<String, Value>> {
+        if !*HOLD_READS {
+            let text = "Still learning peers' state";
+            return vec![node.build_error(&request, ERROR_TEMPORARILY_UNAVAILABLE, text)];
+        }
+        metrics::incr("warmup.held_reads");
+        self.held.push(request);
+        Vec::new()
+    }
+
+    // The reads held until now, once we're done, for the caller to serve.
+    pub fn take_held(&mut self) -> Vec<Map<String, Value>> {
+        if !self.done() {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.held)
+    }
+}
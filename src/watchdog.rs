@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+
+use crate::node::{is_node, Node};
+use crate::{events, metrics, rpc};
+
+// Every client request gets a reply within `MAELSTROM_REPLY_DEADLINE_MS`, even if its handler hangs
+// or the reply is lost inside the node: once the deadline passes we answer with a timeout error
+// (code 0, meaning the request may or may not have taken effect) and drop the real reply if it
+// turns up later, so the client still sees exactly one. 0 disables the watchdog.
+static DEADLINE: LazyLock<Duration> = LazyLock::new(|| {
+    Duration::from_millis(crate::runtime::env_or("MAELSTROM_REPLY_DEADLINE_MS", 4000))
+});
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Requests are identified by (client, client msg_id).
+type RequestId = (String, u64);
+
+#[derive(Default)]
+struct Pending {
+    // {request: (deadline, header)}.
+    awaiting: HashMap<RequestId, (Instant, Map<String, Value>)>,
+    // Requests we sent a timeout for, whose real reply must be dropped.
+    timed_out: HashSet<RequestId>,
+}
+
+static PENDING: LazyLock<Mutex<Pending>> = LazyLock::new(|| Mutex::new(Pending::default()));
+
+fn client_request_id(client: &Value, msg_id: &Value) -> Option<RequestId> {
+    let client = client.as_str().filter(|&c| !is_node(c))?;
+    Some((client.to_owned(), msg_id.as_u64()?))
+}
+
+// Start the clock on a request as it's received. Only requests from clients are tracked, since
+// other nodes have their own retries. Replies, e.g. from a Maelstrom service, aren't requests.
+pub fn expect_reply(request: &Map<String, Value>) {
+    if DEADLINE.is_zero() || request["body"].get("in_reply_to").is_some() {
+        return;
+    }
+    let Some(id) = client_request_id(&request["src"], &request["body"]["msg_id"]) else {
+        return;
+    };
+    let header = rpc::request_header(request);
+    PENDING.lock().awaiting.insert(id, (crate::clock::now() + *DEADLINE, header));
+}
+
+// Called on every message as it's written. Returns false if `message` is the reply to a request we
+// already timed out, and must be dropped.
+pub fn replied(message: &Map<String, Value>) -> bool {
+    if DEADLINE.is_zero() {
+        return true;
+    }
+    let Some(id) = client_request_id(&message["dest"], &message["body"]["in_reply_to"]) else {
+        return true;
+    };
+    let mut pending = PENDING.lock();
+    if pending.awaiting.remove(&id).is_some() {
+        return true;
+    }
+    if pending.timed_out.remove(&id) {
+        metrics::incr("watchdog.late_replies");
+        eprintln!("Dropping late reply {}", serde_json::to_string(message).unwrap());
+        return false;
+    }
+    true
+}
+
+fn expired() -> Vec<Map<String, Value>> {
+    let now = crate::clock::now();
+    let mut pending = PENDING.lock();
+    let expired: Vec<_> = pending
+        .awaiting
+        .iter()
+        .filter(|(_, (deadline, _))| *deadline <= now)
+        .map(|(id, _)| id.clone())
+        .collect();
+    let mut headers = Vec::new();
+    for id in expired {
+        let (_, header) = pending.awaiting.remove(&id).unwrap();
+        pending.timed_out.insert(id);
+        headers.push(header);
+    }
+    headers
+}
+
+// Reply with a timeout to requests past their deadline. Runs on its own thread and writes straight
+// to stdout rather than through the outbox, because a handler which hangs is usually blocking a
+// tokio worker, and with few cores that can be the one the outbox's writer needs too. `node` is a
+// clone of the workload's node, so the watchdog never waits on a lock a hung handler may hold. It
+// waits on the installed `Clock` like everything else, driven by a runtime of its own for the same
+// reason.
+pub fn spawn(node: Node) {
+    if DEADLINE.is_zero() {
+        return;
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    std::thread::spawn(move || loop {
+        // Inside the runtime, which `SystemClock` needs to create its sleep.
+        runtime.block_on(async { crate::clock::sleep(CHECK_INTERVAL).await });
+        for header in expired() {
+            eprintln!(
+                "No reply within {:?} to {}",
+                *DEADLINE,
+                serde_json::to_string(&header).unwrap()
+            );
+            metrics::incr("watchdog.timeouts");
+            let error = node.build_error(&header, rpc::ERROR_TIMEOUT, "No reply within deadline");
+            let serialized = serde_json::to_string(&error).unwrap();
+            events::record(events::Direction::Send, &error, &serialized);
+            writeln!(std::io::stdout().lock(), "{}", serialized).unwrap();
+        }
+    });
+}
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::sync::{mpsc, Notify};
+
+use crate::lock::InstrumentedMutex;
+use crate::node::{is_node, Node};
+use crate::overlay::Overlay;
+use crate::runtime::{catch_panic, create_node, env_or};
+use crate::source::Source;
+use crate::tasks::TaskRegistry;
+use crate::{audit, clock, deadline, events, metrics, rpc, snapshot, summary};
+
+// With `MAELSTROM_WORKERS` > 0, a concurrent workload's requests are handed to that many long lived
+// worker tasks, each with a queue of `MAELSTROM_WORKER_QUEUE` requests, rather than each spawning a
+// task. Saves the per request spawn where handlers are CPU heavy enough to spread over cores.
+// Requests are sharded over the workers by src, which keeps each src's requests in order, see
+// `Lanes`.
+static WORKERS: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_WORKERS", 0));
+static WORKER_QUEUE: LazyLock<usize> = LazyLock::new(|| env_or("MAELSTROM_WORKER_QUEUE", 1024));
+
+// With `MAELSTROM_AVAILABILITY=total`, a client request must be answered by the handler it's passed
+// to, from local state however stale, rather than later once another node or service replies. That
+// keeps every workload serving clients through a partition, and the runtime asserts it of every
+// handler, replying with a crash error where it doesn't hold, if `audit::STRICT_CHECKS`. The
+// default, `strict`, lets handlers wait on remote replies, e.g. to forward requests to a Maelstrom
+// kv service.
+static TOTAL_AVAILABILITY: LazyLock<bool> =
+    LazyLock::new(|| match env_or("MAELSTROM_AVAILABILITY", "strict".to_owned()).as_str() {
+        "total" => true,
+        "strict" => false,
+        other => panic!("Invalid MAELSTROM_AVAILABILITY={other}, expected total or strict"),
+    });
+
+// See `TOTAL_AVAILABILITY`. Replies from services and messages from other nodes aren't client
+// requests.
+fn assert_answered(request: &Map<String, Value>, messages: &[Map<String, Value>]) {
+    let client = request["src"].as_str().unwrap_or_default();
+    if is_node(client) || request["body"].get("in_reply_to").is_some() {
+        return;
+    }
+    let msg_id = &request["body"]["msg_id"];
+    let answered = messages
+        .iter()
+        .any(|m| m["dest"] == client && m["body"].get("in_reply_to") == Some(msg_id));
+    assert!(
+        answered,
+        "Client request left unanswered under total availability: {}",
+        serde_json::to_string(request).unwrap()
+    );
+}
+
+// A workload's state and handlers, driven by `run`. Handlers are plain methods returning the
+// messages to send, so a workload can be exercised as a struct without a runtime. The runtime takes
+// care of everything around them: init, spawning, crash and not-supported replies, periodic ticks,
+// `flush`, `dump_state`/SIGUSR1 snapshots (hence `Serialize`), `dump_overlay` and shutdown, with a
+// `summary`.
+pub trait Workload: Serialize + Send + 'static {
+    // Used to name snapshots.
+    const NAME: &'static str;
+    // Whether requests may be handled concurrently, each in its own task. Otherwise they are handled
+    // one at a time in the order received.
+    const CONCURRENT: bool = true;
+
+    // Build the workload once init has been handled.
+    fn on_init(node: Node) -> Self;
+
+    // Handle a request of type `msg_type`, returning the messages to send, which are committed while
+    // the workload is still locked (see `Outbox`). None if `msg_type` isn't handled, in which case
+    // the runtime replies with error 10.
+    fn on_message(
+        &mut self,
+        msg_type: &str,
+        request: Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>>;
+
+    // Handle a request without locking the workload, for hot paths whose state lives outside it.
+    // None falls through to `on_message`, as does everything by default. Runs in the request's
+    // lane, so still in order with the rest of its src's requests.
+    fn on_message_unlocked(
+        _node: &Node,
+        _msg_type: &str,
+        _request: &Map<String, Value>,
+    ) -> Option<Vec<Map<String, Value>>> {
+        None
+    }
+
+    // How often to call `on_tick`, if at all.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    // Periodic work, such as replication or retries. Returns the messages to send.
+    fn on_tick(&mut self) -> Vec<Map<String, Value>> {
+        Vec::new()
+    }
+
+    // Called once stdin is closed and every handler has finished.
+    fn on_shutdown(&mut self) {}
+
+    // The peers the workload sends to, for `dump_overlay` and shutdown. None by default.
+    fn overlay(&self) -> Overlay {
+        Overlay::new()
+    }
+}
+
+// What a handler needs. The node is a clone of the workload's, sharing its msg_ids and outbox, so
+// the runtime can reply without going through the workload.
+struct Runtime<W> {
+    workload: InstrumentedMutex<W>,
+    node: Node,
+    // Notified when a tick was run outside the schedule, to restart the wait for the next one.
+    tick_reset: Notify,
+}
+
+impl<W: Workload> Runtime<W> {
+    fn tick(&self) {
+        let mut workload = self.workload.lock();
+        self.node.commit(workload.on_tick());
+    }
+
+    fn handle(&self, request: Map<String, Value>) {
+        let Value::String(msg_type) = request["body"]["type"].clone() else {
+            panic!("Invalid msg type encoding");
+        };
+        let header = rpc::request_header(&request);
+        let started = clock::now();
+        let result = catch_panic(|| match msg_type.as_str() {
+            "init" => panic!("Already initialized node: {:?}", request),
+            // Run a tick, e.g. a replication round and retry sweep, right away. Lets tests and
+            // controlled shutdowns propagate final state without waiting on the schedule, which
+            // restarts from here.
+            "flush" => {
+                self.tick();
+                self.tick_reset.notify_one();
+                self.node.commit(vec![self.node.build_response(&request, "flush_ok")]);
+            }
+            "dump_state" => {
+                let workload = self.workload.lock();
+                let path = snapshot::dump(self.node.node_id(), W::NAME, &*workload).unwrap();
+                let mut response = self.node.build_response(&request, "dump_state_ok");
+                response["body"]["path"] = serde_json::json!(path);
+                self.node.commit(vec![response]);
+            }
+            "dump_overlay" => {
+                let overlay = self.workload.lock().overlay();
+                overlay.export(self.node.node_id());
+                let mut response = self.node.build_response(&request, "dump_overlay_ok");
+                response["body"]["overlay"] = serde_json::json!(overlay);
+                response["body"]["dot"] = serde_json::json!(overlay.to_dot(self.node.node_id()));
+                self.node.commit(vec![response]);
+            }
+            // Nobody is waiting on the reply any more, see `deadline`.
+            _ if deadline::expired(&request) => {
+                let text = "Deadline passed";
+                let error =
+                    self.node.build_error(&request, rpc::ERROR_TEMPORARILY_UNAVAILABLE, text);
+                self.node.commit(vec![error]);
+            }
+            _ => {
+                if let Some(messages) = W::on_message_unlocked(&self.node, &msg_type, &request) {
+                    self.commit_answer(&request, messages);
+                    return;
+                }
+                // The request is moved into the handler, so answers are checked against `header`.
+                let mut workload = self.workload.lock();
+                match workload.on_message(&msg_type, request) {
+                    Some(messages) => self.commit_answer(&header, messages),
+                    None => self.node.reply_not_supported(&header),
+                }
+            }
+        });
+        if let Err(text) = result {
+            self.node.reply_crash(&header, &text);
+        }
+        summary::handled(&msg_type, clock::now() - started);
+    }
+
+    fn commit_answer(&self, request: &Map<String, Value>, messages: Vec<Map<String, Value>>) {
+        if audit::STRICT_CHECKS && *TOTAL_AVAILABILITY {
+            assert_answered(request, &messages);
+        }
+        self.node.commit(messages)
+    }
+}
+
+// Requests from one src are handled one at a time, in the order received, while those from
+// different srcs are handled concurrently. A task per request would let, e.g., a client's read
+// overtake the broadcast it sent just before. Each src with requests outstanding has a lane: a
+// queue drained by a single handler task, which exits once the queue is empty.
+struct Lanes<W> {
+    runtime: Arc<Runtime<W>>,
+    // {src: requests waiting behind the one its lane is handling}.
+    queues: Mutex<HashMap<String, VecDeque<Map<String, Value>>>>,
+}
+
+impl<W: Workload> Lanes<W> {
+    fn new(runtime: &Arc<Runtime<W>>) -> Arc<Self> {
+        Arc::new(Self { runtime: Arc::clone(runtime), queues: Mutex::new(HashMap::new()) })
+    }
+
+    async fn dispatch(self: &Arc<Self>, tasks: &TaskRegistry, request: Map<String, Value>) {
+        let src = request["src"].as_str().unwrap_or_default().to_owned();
+        {
+            let mut queues = self.queues.lock();
+            if let Some(queue) = queues.get_mut(&src) {
+                queue.push_back(request);
+                metrics::max("lanes.max_queued", queue.len() as u64);
+                return;
+            }
+            queues.insert(src.clone(), VecDeque::new());
+        }
+        let lanes = Arc::clone(self);
+        tasks
+            .spawn_handler(async move {
+                let mut request = request;
+                loop {
+                    lanes.runtime.handle(request);
+                    let mut queues = lanes.queues.lock();
+                    let Some(next) = queues.get_mut(&src).and_then(VecDeque::pop_front) else {
+                        queues.remove(&src);
+                        break;
+                    };
+                    request = next;
+                }
+            })
+            .await;
+    }
+}
+
+struct Queued {
+    request: Map<String, Value>,
+    queued_at: Instant,
+}
+
+// See `WORKERS`. Dropping the pool closes the queues, and the workers exit once they're drained.
+struct WorkerPool {
+    senders: Vec<mpsc::Sender<Queued>>,
+}
+
+impl WorkerPool {
+    // Workers are handlers as far as `tasks` is concerned, so shutdown waits for them.
+    async fn spawn<W: Workload>(tasks: &TaskRegistry, runtime: &Arc<Runtime<W>>) -> Self {
+        let mut senders = Vec::new();
+        for _ in 0..*WORKERS {
+            let (sender, mut receiver) = mpsc::channel::<Queued>(*WORKER_QUEUE);
+            senders.push(sender);
+            let runtime = Arc::clone(runtime);
+            tasks
+                .spawn_handler(async move {
+                    while let Some(queued) = receiver.recv().await {
+                        let waited = (clock::now() - queued.queued_at).as_micros() as u64;
+                        metrics::add("workers.wait_us", waited);
+                        metrics::max("workers.max_wait_us", waited);
+                        runtime.handle(queued.request);
+                    }
+                })
+                .await;
+        }
+        Self { senders }
+    }
+
+    // Waits while the src's worker's queue is full, so the main loop stops reading requests.
+    async fn send(&self, request: Map<String, Value>) {
+        // DefaultHasher::new is unseeded, so a src always maps to the same worker.
+        let mut hasher = std::hash::DefaultHasher::new();
+        request["src"].as_str().unwrap_or_default().hash(&mut hasher);
+        let sender = &self.senders[hasher.finish() as usize % self.senders.len()];
+        let depth = (*WORKER_QUEUE - sender.capacity()) as u64;
+        metrics::set("workers.queue_depth", depth);
+        metrics::max("workers.max_queue_depth", depth);
+        let Ok(()) = sender.send(Queued { request, queued_at: clock::now() }).await else {
+            panic!("Workers are gone");
+        };
+    }
+}
+
+// Run `W` as a node, reading requests from stdin or, with `--selfdrive`, from `generate`. Returns
+// once stdin is closed and everything has been written.
+pub async fn run<W: Workload>(generate: impl FnMut(u64) -> Map<String, Value> + Send + 'static) {
+    summary::start();
+    let mut source = Source::from_args(generate);
+    let node = create_node(&mut source).await;
+    let runtime = Arc::new(Runtime {
+        workload: InstrumentedMutex::new("workload", W::on_init(node.clone())),
+        node,
+        tick_reset: Notify::new(),
+    });
+
+    let tasks = TaskRegistry::new();
+    if let Some(period) = runtime.workload.lock().tick_interval() {
+        let runtime = Arc::clone(&runtime);
+        tasks.spawn_background(async move {
+            loop {
+                runtime.tick();
+                // Wait out `period`, give or take jitter, since the last tick, scheduled or flushed.
+                loop {
+                    tokio::select! {
+                        _ = clock::sleep(clock::jittered(period)) => break,
+                        _ = runtime.tick_reset.notified() => {}
+                    }
+                }
+            }
+        });
+    }
+    let dump_runtime = Arc::clone(&runtime);
+    snapshot::dump_on_signal(&tasks, move || {
+        snapshot::dump(dump_runtime.node.node_id(), W::NAME, &*dump_runtime.workload.lock())
+    });
+
+    let workers = match W::CONCURRENT && *WORKERS > 0 {
+        true => Some(WorkerPool::spawn(&tasks, &runtime).await),
+        false => None,
+    };
+    let lanes = Lanes::new(&runtime);
+
+    // Main loop.
+    while let Some(request) = source.recv().await {
+        tasks.reap();
+        if !W::CONCURRENT {
+            runtime.handle(request);
+            continue;
+        }
+        if let Some(workers) = &workers {
+            workers.send(request).await;
+            continue;
+        }
+        lanes.dispatch(&tasks, request).await;
+    }
+
+    drop(workers);
+    tasks.shutdown().await;
+    runtime.workload.lock().on_shutdown();
+    runtime.workload.lock().overlay().export(runtime.node.node_id());
+    runtime.node.outbox().flush().await;
+    metrics::dump();
+    summary::print();
+    events::dump();
+}
@@ -0,0 +1,170 @@
+use std::sync::LazyLock;
+
+use serde_json::{json, Map, Value};
+
+use crate::runtime::env_or;
+use crate::txn::TxnOp;
+
+// Synthetic client traffic, so that `--selfdrive` (see `Source`), simulations and benchmarks all put
+// the same, tunable shape of load on a node. The rate is up to the caller, e.g.
+// `MAELSTROM_SELFDRIVE_RATE`; the shape is configured with:
+// - `MAELSTROM_WORKLOAD_SEED`: generators with the same seed produce the same requests.
+// - `MAELSTROM_WORKLOAD_READ_RATIO`: the fraction of requests, or txn micro-ops, which are reads.
+// - `MAELSTROM_WORKLOAD_KEYS`: the number of kv or txn keys.
+// - `MAELSTROM_WORKLOAD_KEY_SKEW`: the Zipf exponent keys are picked with, see `Keys`.
+// - `MAELSTROM_WORKLOAD_TXN_OPS`: the most micro-ops in a txn.
+// - `MAELSTROM_WORKLOAD_MAX_DELTA`: the largest counter add.
+struct Config {
+    seed: u64,
+    read_ratio: f64,
+    keys: u64,
+    key_skew: f64,
+    txn_ops: u64,
+    max_delta: u64,
+}
+
+static CONFIG: LazyLock<Config> = LazyLock::new(|| Config {
+    seed: env_or("MAELSTROM_WORKLOAD_SEED", 0),
+    read_ratio: env_or("MAELSTROM_WORKLOAD_READ_RATIO", 0.1),
+    keys: env_or("MAELSTROM_WORKLOAD_KEYS", 5),
+    key_skew: env_or("MAELSTROM_WORKLOAD_KEY_SKEW", 0.0),
+    txn_ops: env_or("MAELSTROM_WORKLOAD_TXN_OPS", 3),
+    max_delta: env_or("MAELSTROM_WORKLOAD_MAX_DELTA", 1),
+});
+
+// SplitMix64. Not much of a generator, but deterministic, which matters more here.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in [low, high].
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+}
+
+// Picks keys 0..n with the probability of k proportional to 1 / (k + 1)^skew. That's uniform for
+// skew 0, and the higher the skew the more load lands on the first few keys, as with hot keys in
+// real traffic.
+struct Keys {
+    // cdf[k] is the probability of picking a key <= k.
+    cdf: Vec<f64>,
+}
+
+impl Keys {
+    fn new(n: u64, skew: f64) -> Self {
+        assert!(n > 0, "No keys to pick from");
+        let weights: Vec<f64> = (1..=n).map(|k| 1.0 / (k as f64).powf(skew)).collect();
+        let total: f64 = weights.iter().sum();
+        let mut cumulative = 0.0;
+        let cdf = weights
+            .into_iter()
+            .map(|weight| {
+                cumulative += weight / total;
+                cumulative
+            })
+            .collect();
+        Self { cdf }
+    }
+
+    fn pick(&self, rng: &mut Rng) -> i64 {
+        let p = rng.next_f64();
+        // Rounding can leave the last entry just under 1.
+        self.cdf.partition_point(|&c| c <= p).min(self.cdf.len() - 1) as i64
+    }
+}
+
+// Generates request bodies for each workload. `i` is the request counter passed by `Source`, which
+// is also used to keep broadcast messages and gset elements unique.
+pub struct Generator {
+    rng: Rng,
+    keys: Keys,
+    // Appended values must be unique per key, so the checker can tell which txn wrote them.
+    next_value: i64,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self { rng: Rng(CONFIG.seed), keys: Keys::new(CONFIG.keys, CONFIG.key_skew), next_value: 0 }
+    }
+}
+
+impl Generator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_read(&mut self) -> bool {
+        self.rng.next_f64() < CONFIG.read_ratio
+    }
+
+    pub fn broadcast(&mut self, i: u64) -> Map<String, Value> {
+        if self.is_read() {
+            return body(json!({ "type": "read" }));
+        }
+        body(json!({ "type": "broadcast", "message": i }))
+    }
+
+    pub fn gset(&mut self, i: u64) -> Map<String, Value> {
+        if self.is_read() {
+            return body(json!({ "type": "read" }));
+        }
+        body(json!({ "type": "add", "element": i }))
+    }
+
+    pub fn gcounter(&mut self, _i: u64) -> Map<String, Value> {
+        if self.is_read() {
+            return body(json!({ "type": "read" }));
+        }
+        let delta = self.rng.between(1, CONFIG.max_delta.max(1));
+        body(json!({ "type": "add", "delta": delta }))
+    }
+
+    // A read, write or cas of a key, with writes and cas split evenly.
+    pub fn kv(&mut self, i: u64) -> Map<String, Value> {
+        let key = self.keys.pick(&mut self.rng);
+        if self.is_read() {
+            return body(json!({ "type": "read", "key": key }));
+        }
+        if self.rng.next_u64().is_multiple_of(2) {
+            return body(json!({ "type": "write", "key": key, "value": i }));
+        }
+        let from = self.rng.between(0, i);
+        body(json!({ "type": "cas", "key": key, "from": from, "to": i }))
+    }
+
+    // A list-append txn of 1 to `MAELSTROM_WORKLOAD_TXN_OPS` micro-ops.
+    pub fn txn(&mut self, _i: u64) -> Map<String, Value> {
+        let len = self.rng.between(1, CONFIG.txn_ops.max(1));
+        let txn: Vec<_> = (0..len)
+            .map(|_| {
+                let key = self.keys.pick(&mut self.rng);
+                if self.is_read() {
+                    return TxnOp::R(key);
+                }
+                self.next_value += 1;
+                TxnOp::Append(key, self.next_value)
+            })
+            .collect();
+        body(json!({ "type": "txn", "txn": txn }))
+    }
+}
+
+fn body(body: Value) -> Map<String, Value> {
+    let Value::Object(body) = body else {
+        panic!("Invalid body {:?}", body);
+    };
+    body
+}